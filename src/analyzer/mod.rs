@@ -110,9 +110,14 @@ fn calculate_sizes(program: &AnchorProgram) -> Vec<AccountSize> {
         let mut fields = Vec::new();
 
         for field in &state.fields {
-            let field_size = estimate_field_size(&field.ty);
+            let max_lens: Vec<usize> =
+                field.max_len.into_iter().chain(field.inner_max_lens.iter().copied()).collect();
+            let field_size = bounded_field_size(&field.ty, &max_lens);
             fields.push((field.name.clone(), field_size));
-            total_size += field_size;
+            // #[borsh(skip)] fields are never written to the account
+            if !field.borsh_skip {
+                total_size += field_size;
+            }
         }
 
         sizes.push(AccountSize {
@@ -125,7 +130,20 @@ fn calculate_sizes(program: &AnchorProgram) -> Vec<AccountSize> {
     sizes
 }
 
-fn estimate_field_size(ty: &str) -> usize {
+/// Borsh size of a String / Vec field at its #[max_len] bounds, outermost first:
+/// `Vec<String>` with `#[max_len(4, 32)]` holds 4 strings of up to 32 bytes
+fn bounded_field_size(ty: &str, max_lens: &[usize]) -> usize {
+    let ty = ty.replace(' ', "");
+    match max_lens.split_first() {
+        Some((&len, inner)) if ty.starts_with("Vec<") && ty.ends_with('>') => {
+            4 + len * bounded_field_size(&ty[4..ty.len() - 1], inner)
+        }
+        Some((&len, _)) if ty == "String" => 4 + len,
+        _ => estimate_field_size(&ty),
+    }
+}
+
+pub fn estimate_field_size(ty: &str) -> usize {
     let ty = ty.replace(" ", "").to_lowercase();
 
     // Handle Option<T>
@@ -144,6 +162,13 @@ fn estimate_field_size(ty: &str) -> usize {
         return 4; // Length prefix (content is variable)
     }
 
+    // Handle [T; N]
+    if let Some((elem, len)) = ty.strip_prefix('[').and_then(|t| t.strip_suffix(']')).and_then(|t| t.rsplit_once(';')) {
+        if let Ok(len) = len.parse::<usize>() {
+            return len * estimate_field_size(elem);
+        }
+    }
+
     match ty.as_str() {
         // Primitive types
         "bool" => 1,
@@ -9,11 +9,11 @@ use regex::Regex;
 /// Default maximum sizes for Vec<T> when no #[max_len] is specified
 pub const DEFAULT_VEC_SIZES: &[(&str, usize)] = &[
     ("Pubkey", 32),      // Max signers in multisig
-    ("u64", 100),        // Max amounts/counters
-    ("u32", 100),        // Max counts
+    ("u64", 32),         // Max amounts/counters
+    ("u32", 32),         // Max counts
     ("u16", 256),        // Max small counts
     ("u8", 256),         // Max bytes
-    ("i64", 100),        // Max signed amounts
+    ("i64", 32),         // Max signed amounts
     ("String", 10),      // Max string items
     ("AccountInfo", 16), // Max remaining accounts
 ];
@@ -110,29 +110,25 @@ pub fn transform_vec_operations(body: &str, vec_fields: &[VecField]) -> String {
                 (full_match, prefix.to_string(), value, has_question)
             }).collect();
 
+            // Pubkey arguments arrive as `&[u8; 32]`, other elements by value
+            let deref = if vec_field.element_type == "Pubkey" { "*" } else { "" };
             for (full_match, prefix, value, has_question) in matches {
                 let replacement = if has_question {
                     // With error handling: vec.push(value)?
                     format!(
                         "{{ if {}{} as usize >= {} {{ return Err(ProgramError::Custom(0)); }} \
-                        {}{}[{}{} as usize] = {}; {}{} += 1; Ok::<(), ProgramError>(()) }}?",
+                        {}{}[{}{} as usize] = {}{}; {}{} += 1; Ok::<(), ProgramError>(()) }}?",
                         prefix, len_name, max_len,
-                        prefix, vec_name, prefix, len_name, value,
+                        prefix, vec_name, prefix, len_name, deref, value,
                         prefix, len_name
                     )
                 } else {
                     // Without error handling: vec.push(value)
-                    // Dereference the value if it's a reference
-                    let deref_value = if value.starts_with('&') || value.contains(" & ") {
-                        value.clone()
-                    } else {
-                        value.clone()
-                    };
                     format!(
                         "{{ if ({}{} as usize) >= {} {{ return Err(ProgramError::Custom(0)); }} \
-                        {}{}[{}{} as usize] = *{}; {}{} += 1; }}",
+                        {}{}[{}{} as usize] = {}{}; {}{} += 1; }}",
                         prefix, len_name, max_len,
-                        prefix, vec_name, prefix, len_name, deref_value,
+                        prefix, vec_name, prefix, len_name, deref, value,
                         prefix, len_name
                     )
                 };
@@ -141,33 +137,32 @@ pub fn transform_vec_operations(body: &str, vec_fields: &[VecField]) -> String {
         }
 
         // Transform vec.len() - handle both direct and state-prefixed patterns
-        // Pattern 1: signers.len() → signers_len as usize
+        // Parenthesized so comparisons like `(x as usize) < 10` don't parse as generics
+        // Pattern 2 first: state.signers.len() → (state.signers_len as usize)
+        // We need to preserve any prefix like "multisig_state."
+        let prefixed_len_re = Regex::new(&format!(
+            r"(\w+(?:\.\w+)*)\.{}\.len\s*\(\)",
+            regex::escape(vec_name)
+        ))
+        .unwrap();
+        result = prefixed_len_re
+            .replace_all(&result, format!("(${{1}}.{} as usize)", len_name).as_str())
+            .to_string();
+        // Pattern 1: signers.len() → (signers_len as usize)
         result = result.replace(
             &format!("{}.len()", vec_name),
-            &format!("{} as usize", len_name)
+            &format!("({} as usize)", len_name)
         );
-        // Pattern 2: state.signers.len() → state.signers_len as usize
-        // We need to preserve any prefix like "multisig_state."
-        let len_pattern = format!(".{}.len ()", vec_name);
-        if result.contains(&len_pattern) {
-            result = result.replace(
-                &len_pattern,
-                &format!(".{} as usize", len_name)
-            );
-        }
-        let len_pattern_compact = format!(".{}.len()", vec_name);
-        if result.contains(&len_pattern_compact) {
-            result = result.replace(
-                &len_pattern_compact,
-                &format!(".{} as usize", len_name)
-            );
-        }
 
         // Transform vec.is_empty()
-        result = result.replace(
-            &format!("{}.is_empty()", vec_name),
-            &format!("({} == 0)", len_name)
-        );
+        let is_empty_re = Regex::new(&format!(
+            r"\b{}\s*\.\s*is_empty\s*\(\s*\)",
+            regex::escape(vec_name)
+        ))
+        .unwrap();
+        result = is_empty_re
+            .replace_all(&result, format!("({} == 0)", len_name).as_str())
+            .to_string();
 
         // Transform vec.iter() - use regex to capture prefix
         let iter_pattern_str = format!(
@@ -191,10 +186,14 @@ pub fn transform_vec_operations(body: &str, vec_fields: &[VecField]) -> String {
         }
 
         // Transform vec.clear()
-        result = result.replace(
-            &format!("{}.clear()", vec_name),
-            &format!("{} = 0", len_name)
-        );
+        let clear_re = Regex::new(&format!(
+            r"\b{}\s*\.\s*clear\s*\(\s*\)",
+            regex::escape(vec_name)
+        ))
+        .unwrap();
+        result = clear_re
+            .replace_all(&result, format!("{} = 0", len_name).as_str())
+            .to_string();
 
         // Transform vec.remove(index)
         // Pattern: (prefix.)?vec.remove(index)
@@ -251,7 +250,8 @@ pub fn generate_vec_helpers(state_name: &str, vec_fields: &[VecField]) -> String
     for vec_field in vec_fields {
         let vec_name = &vec_field.name;
         let len_name = vec_field.length_field_name();
-        let element_type = &vec_field.element_type;
+        // Match the array element type emitted in state.rs
+        let element_type = &vec_field.element_type.replace("Pubkey", "[u8; 32]");
         let max_len = vec_field.get_max_len();
 
         content.push_str(&format!("
@@ -329,6 +329,25 @@ mod tests {
         assert_eq!(vec_field.get_max_len(), 32); // Default for Pubkey
     }
 
+    #[test]
+    fn test_transform_vec_push_derefs_pubkeys() {
+        let vec_field = VecField {
+            name: "signers".to_string(),
+            element_type: "Pubkey".to_string(),
+            max_len: None,
+            resolved_max_len: 0,
+            is_mutable: true,
+        };
+
+        // Both forms store the pointed-to key, bounded by the default length
+        let body = "state.signers.push(who)?; state.signers.push(other);";
+        let transformed = transform_vec_operations(body, &[vec_field]);
+
+        assert!(transformed.contains("if state.signers_len as usize >= 32 {"));
+        assert!(transformed.contains("state.signers[state.signers_len as usize] = *who;"));
+        assert!(transformed.contains("state.signers[state.signers_len as usize] = *other;"));
+    }
+
     #[test]
     fn test_length_field_name() {
         let vec_field = VecField {
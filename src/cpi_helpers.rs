@@ -9,42 +9,23 @@ pub fn token_transfer_cpi(
     authority: &str,
     amount: &str,
     with_signer: bool,
-    signer_seeds: Option<&[&str]>,
+    signers: Option<&[Vec<String>]>,
 ) -> String {
-    if let (true, Some(seeds)) = (with_signer, signer_seeds) {
-        let seeds_code: Vec<String> = seeds.iter().map(|s| format!("        {},", s)).collect();
-        format!(
-            r#"// Token transfer with PDA signer
-    Transfer {{
-        from: {},
-        to: {},
-        authority: {},
-        amount: {},
-    }}.invoke_signed(
-        &[&[
-{}
-        ]],
-    )?;
-"#,
-            from_account,
-            to_account,
-            authority,
-            amount,
-            seeds_code.join("\n")
-        )
-    } else {
-        format!(
-            r#"// Token transfer
+    format!(
+        r#"// Token transfer{}
     Transfer {{
         from: {},
         to: {},
         authority: {},
         amount: {},
-    }}.invoke()?;
-"#,
-            from_account, to_account, authority, amount
-        )
-    }
+    }}{}"#,
+        signer_note(with_signer, signers),
+        from_account,
+        to_account,
+        authority,
+        amount,
+        invoke_call(with_signer, signers)
+    )
 }
 
 /// Generate a Pinocchio token mint CPI call
@@ -54,44 +35,269 @@ pub fn token_mint_to_cpi(
     authority: &str,
     amount: &str,
     with_signer: bool,
-    signer_seeds: Option<&[&str]>,
+    signers: Option<&[Vec<String>]>,
 ) -> String {
-    if let (true, Some(seeds)) = (with_signer, signer_seeds) {
-        let seeds_code: Vec<String> = seeds.iter().map(|s| format!("        {},", s)).collect();
-        format!(
-            r#"// Mint tokens with PDA signer
+    format!(
+        r#"// Mint tokens{}
     MintTo {{
         mint: {},
         account: {},
         mint_authority: {},
         amount: {},
-    }}.invoke_signed(
-        &[&[
-{}
-        ]],
-    )?;
-"#,
-            mint_account,
-            to_account,
-            authority,
-            amount,
-            seeds_code.join("\n")
-        )
-    } else {
-        format!(
-            r#"// Mint tokens
-    MintTo {{
+    }}{}"#,
+        signer_note(with_signer, signers),
+        mint_account,
+        to_account,
+        authority,
+        amount,
+        invoke_call(with_signer, signers)
+    )
+}
+
+/// Generate a Pinocchio checked token transfer CPI call (mint and decimals verified)
+#[allow(clippy::too_many_arguments)]
+pub fn token_transfer_checked_cpi(
+    from_account: &str,
+    mint_account: &str,
+    to_account: &str,
+    authority: &str,
+    amount: &str,
+    decimals: &str,
+    with_signer: bool,
+    signers: Option<&[Vec<String>]>,
+) -> String {
+    format!(
+        r#"// Checked token transfer{}
+    TransferChecked {{
+        from: {},
+        mint: {},
+        to: {},
+        authority: {},
+        amount: {},
+        decimals: {},
+    }}{}"#,
+        signer_note(with_signer, signers),
+        from_account,
+        mint_account,
+        to_account,
+        authority,
+        amount,
+        decimals,
+        invoke_call(with_signer, signers)
+    )
+}
+
+/// Generate a Pinocchio checked token mint CPI call (decimals verified)
+pub fn token_mint_to_checked_cpi(
+    mint_account: &str,
+    to_account: &str,
+    authority: &str,
+    amount: &str,
+    decimals: &str,
+    with_signer: bool,
+    signers: Option<&[Vec<String>]>,
+) -> String {
+    format!(
+        r#"// Mint tokens (checked){}
+    MintToChecked {{
         mint: {},
         account: {},
         mint_authority: {},
         amount: {},
-    }}.invoke()?;
-"#,
-            mint_account, to_account, authority, amount
+        decimals: {},
+    }}{}"#,
+        signer_note(with_signer, signers),
+        mint_account,
+        to_account,
+        authority,
+        amount,
+        decimals,
+        invoke_call(with_signer, signers)
+    )
+}
+
+/// Generate a Pinocchio token approve CPI call (delegate may spend up to `amount`)
+pub fn token_approve_cpi(
+    source_account: &str,
+    delegate: &str,
+    authority: &str,
+    amount: &str,
+    with_signer: bool,
+    signers: Option<&[Vec<String>]>,
+) -> String {
+    format!(
+        r#"// Approve delegate
+    Approve {{
+        source: {},
+        delegate: {},
+        authority: {},
+        amount: {},
+    }}{}"#,
+        source_account,
+        delegate,
+        authority,
+        amount,
+        invoke_call(with_signer, signers)
+    )
+}
+
+/// Generate a Pinocchio token revoke CPI call
+pub fn token_revoke_cpi(
+    source_account: &str,
+    authority: &str,
+    with_signer: bool,
+    signers: Option<&[Vec<String>]>,
+) -> String {
+    format!(
+        r#"// Revoke delegate
+    Revoke {{
+        source: {},
+        authority: {},
+    }}{}"#,
+        source_account,
+        authority,
+        invoke_call(with_signer, signers)
+    )
+}
+
+/// Generate a Pinocchio freeze account CPI call
+pub fn token_freeze_cpi(
+    account: &str,
+    mint_account: &str,
+    freeze_authority: &str,
+    with_signer: bool,
+    signers: Option<&[Vec<String>]>,
+) -> String {
+    format!(
+        r#"// Freeze token account
+    FreezeAccount {{
+        account: {},
+        mint: {},
+        freeze_authority: {},
+    }}{}"#,
+        account,
+        mint_account,
+        freeze_authority,
+        invoke_call(with_signer, signers)
+    )
+}
+
+/// Generate a Pinocchio thaw account CPI call
+pub fn token_thaw_cpi(
+    account: &str,
+    mint_account: &str,
+    freeze_authority: &str,
+    with_signer: bool,
+    signers: Option<&[Vec<String>]>,
+) -> String {
+    format!(
+        r#"// Thaw token account
+    ThawAccount {{
+        account: {},
+        mint: {},
+        freeze_authority: {},
+    }}{}"#,
+        account,
+        mint_account,
+        freeze_authority,
+        invoke_call(with_signer, signers)
+    )
+}
+
+/// `.invoke()?;`, or `.invoke_signed(...)?;` with one `Signer` per PDA seed list. A signed
+/// context whose seeds couldn't be resolved fails the build instead of invoking unsigned
+fn invoke_call(with_signer: bool, signers: Option<&[Vec<String>]>) -> String {
+    match (with_signer, signers) {
+        (true, Some(signers)) => {
+            // No trailing comma: seeds! doesn't take one
+            let signers_code: Vec<String> = signers
+                .iter()
+                .map(|seeds| {
+                    let seeds_code: Vec<String> = seeds.iter().map(|s| format!("        {}", s)).collect();
+                    format!(
+                        "pinocchio::instruction::Signer::from(&pinocchio::seeds!(\n{}\n    ))",
+                        seeds_code.join(",\n")
+                    )
+                })
+                .collect();
+            format!(".invoke_signed(&[{}])?;\n", signers_code.join(", "))
+        }
+        (true, None) => concat!(
+            ".invoke()?;\n",
+            "    compile_error!(\"uncpi: signer seeds of this CPI could not be resolved; pass them to invoke_signed\");\n"
         )
+        .to_string(),
+        (false, _) => ".invoke()?;\n".to_string(),
+    }
+}
+
+/// Suffix for the CPI's leading comment
+fn signer_note(with_signer: bool, signers: Option<&[Vec<String>]>) -> &'static str {
+    match (with_signer, signers) {
+        (true, Some(_)) => " with PDA signer",
+        (true, None) => " with unresolved PDA signer",
+        _ => "",
     }
 }
 
+/// Generate a Pinocchio raw CPI from an already built `Instruction`
+///
+/// Each entry of `signers` is one PDA's seed list, passed to `invoke_signed`.
+pub fn raw_invoke_cpi(instruction: &str, account_infos: &[String], signers: Option<&[Vec<String>]>) -> String {
+    let infos: Vec<String> = account_infos
+        .iter()
+        .map(|info| {
+            if info.starts_with('&') {
+                info.clone()
+            } else {
+                format!("&{}", info)
+            }
+        })
+        .collect();
+    match signers {
+        Some(signers) => {
+            let signers_code: Vec<String> = signers
+                .iter()
+                .map(|seeds| {
+                    format!(
+                        "pinocchio::instruction::Signer::from(&pinocchio::seeds!({}))",
+                        seeds.join(", ")
+                    )
+                })
+                .collect();
+            format!(
+                "pinocchio::cpi::invoke_signed({}, &[{}], &[{}])",
+                instruction,
+                infos.join(", "),
+                signers_code.join(", ")
+            )
+        }
+        None => format!("pinocchio::cpi::invoke({}, &[{}])", instruction, infos.join(", ")),
+    }
+}
+
+/// Generate a Pinocchio `Instruction` literal
+///
+/// `accounts` holds `(pubkey, is_writable, is_signer)` for each `AccountMeta`, with the
+/// pubkey already a `&Pubkey` expression.
+pub fn raw_instruction(program_id: &str, accounts: &[(String, bool, String)], data: &str) -> String {
+    let metas: Vec<String> = accounts
+        .iter()
+        .map(|(pubkey, is_writable, is_signer)| {
+            format!(
+                "pinocchio::instruction::AccountMeta::new({}, {}, {})",
+                pubkey, is_writable, is_signer
+            )
+        })
+        .collect();
+    format!(
+        "pinocchio::instruction::Instruction {{ program_id: {}, accounts: &[{}], data: {} }}",
+        program_id,
+        metas.join(", "),
+        data
+    )
+}
+
 /// Generate a Pinocchio token burn CPI call
 pub fn token_burn_cpi(
     mint_account: &str,
@@ -134,17 +340,11 @@ pub fn state_deserialize_read(state_type: &str, account_name: &str) -> String {
 }
 
 /// Generate state deserialization code (mutable)
-/// Matches the pattern used in transformer: let {account}_state = {StateType}::from_account_info_mut({account})?
-pub fn state_deserialize_write(state_type: &str, account_name: &str, needs_mut: bool) -> String {
-    if needs_mut {
-        format!(
-            "let mut {}_state = {}::from_account_info_mut({})?;",
-            account_name, state_type, account_name
-        )
-    } else {
-        format!(
-            "let {}_state = {}::from_account_info_mut({})?;",
-            account_name, state_type, account_name
-        )
-    }
+/// Matches the pattern used in transformer: let mut {account}_state = {StateType}::from_account_info_mut({account})?
+/// The binding is always `mut`: writing through the RefMut guard needs it
+pub fn state_deserialize_write(state_type: &str, account_name: &str) -> String {
+    format!(
+        "let mut {}_state = {}::from_account_info_mut({})?;",
+        account_name, state_type, account_name
+    )
 }
@@ -1,48 +1,129 @@
 //! Emit Pinocchio code from IR
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::ir::*;
-use crate::parser::SourceExtras;
+use crate::parser::{ConstantDef, SourceExtras};
 
+/// `name . member`, with the `(` of a method call captured
+static MEMBER_ACCESS_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?:^|[^\w.])(\w+)\s*\.\s*\w+(\s*\()?").unwrap());
+
+/// The generated crate, held in memory until it is written or printed
+#[derive(Debug, Default)]
+pub struct GeneratedCrate {
+    /// Contents by path relative to the output directory
+    pub files: BTreeMap<PathBuf, String>,
+}
+
+impl GeneratedCrate {
+    /// Add the files of `other`, replacing any at the same path
+    pub fn extend(&mut self, other: GeneratedCrate) {
+        self.files.extend(other.files);
+    }
+
+    /// Write every file under `output_dir`, creating directories as needed
+    pub fn write(&self, output_dir: &Path) -> Result<()> {
+        for (path, content) in &self.files {
+            let path = output_dir.join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, content).with_context(|| format!("Failed to write {:?}", path))?;
+        }
+        Ok(())
+    }
+
+    /// Print every file to stdout under a `==> path <==` header (`--dry-run`)
+    pub fn print(&self) {
+        for (path, content) in &self.files {
+            println!("==> {} <==", path.display());
+            print!("{}", content);
+            if !content.ends_with('\n') {
+                println!();
+            }
+            println!();
+        }
+    }
+}
+
+/// Generate the Pinocchio crate in memory. Handlers already in `kept_dir` (the output
+/// directory of a previous run) are only read, for the hand-written regions they keep.
 pub fn emit_with_extras(
     program: &PinocchioProgram,
-    output_dir: &Path,
+    kept_dir: Option<&Path>,
     extras: Option<&SourceExtras>,
-) -> Result<()> {
-    fs::create_dir_all(output_dir)?;
+) -> Result<GeneratedCrate> {
+    let src_dir = PathBuf::from("src");
+    let mut files = BTreeMap::from([
+        (PathBuf::from("Cargo.toml"), emit_cargo_toml(program)),
+        (src_dir.join("lib.rs"), emit_lib_rs(program, extras.is_some())),
+        (src_dir.join("prelude.rs"), emit_prelude_rs()),
+        (src_dir.join("state.rs"), emit_state_rs(program)),
+        (src_dir.join("error.rs"), emit_error_rs(program)),
+    ]);
+
+    // src/events.rs (if the program has #[event] structs)
+    if !program.events.is_empty() {
+        files.insert(src_dir.join("events.rs"), emit_events_rs(program)?);
+    }
 
-    // Emit Cargo.toml
-    emit_cargo_toml(program, output_dir)?;
+    // src/helpers.rs (if we have extras)
+    let mut helper_names = Vec::new();
+    if let Some(extras) = extras {
+        let helpers = emit_helpers_rs(extras, program);
+        helper_names = public_item_names(&helpers);
+        files.insert(src_dir.join("helpers.rs"), helpers);
+    }
 
-    // Emit src/lib.rs
-    let src_dir = output_dir.join("src");
-    fs::create_dir_all(&src_dir)?;
-    emit_lib_rs(program, &src_dir, extras.is_some())?;
+    // src/instructions/
+    let constants = extras.map(|e| e.constants.as_slice()).unwrap_or_default();
+    files.extend(emit_instructions(program, kept_dir, constants, &helper_names)?);
 
-    // Emit src/state.rs
-    emit_state_rs(program, &src_dir)?;
+    // MIGRATION.md (if any account needs manual validation)
+    if let Some(migration) = emit_migration_md(program) {
+        files.insert(PathBuf::from("MIGRATION.md"), migration);
+    }
 
-    // Emit src/error.rs
-    emit_error_rs(program, &src_dir)?;
+    Ok(GeneratedCrate { files })
+}
 
-    // Emit src/helpers.rs (if we have extras)
-    if let Some(extras) = extras {
-        emit_helpers_rs(extras, program, &src_dir)?;
+/// MIGRATION.md: `/// CHECK:` accounts, which the generated handlers take as they come
+fn emit_migration_md(program: &PinocchioProgram) -> Option<String> {
+    let checks: Vec<String> = program
+        .instructions
+        .iter()
+        .flat_map(|inst| {
+            inst.accounts.iter().filter_map(move |acc| {
+                let check = acc.check_doc.as_ref()?;
+                Some(format!("- [ ] `{}` / `{}`: {}\n", inst.name, acc.name, check))
+            })
+        })
+        .collect();
+    if checks.is_empty() {
+        return None;
     }
 
-    // Emit src/instructions/
-    emit_instructions(program, &src_dir)?;
-
-    // Emit security.json for program metadata
-    emit_security_json(program, output_dir)?;
+    let mut content = String::new();
+    content.push_str("# Migration notes\n\n");
+    content.push_str("## Unchecked accounts\n\n");
+    content.push_str("Anchor accepted these accounts on the strength of their `/// CHECK:` comment and\n");
+    content.push_str("Pinocchio does not validate them either. Make sure each handler performs the\n");
+    content.push_str("checks described:\n\n");
+    for check in checks {
+        content.push_str(&check);
+    }
 
-    Ok(())
+    Some(content)
 }
 
-fn emit_security_json(program: &PinocchioProgram, output_dir: &Path) -> Result<()> {
+/// Emit security.json with the program's (placeholder) security metadata
+pub fn emit_security_json(program: &PinocchioProgram) -> Result<GeneratedCrate> {
     let security = serde_json::json!({
         "name": program.name,
         "project_url": "",
@@ -59,11 +140,26 @@ fn emit_security_json(program: &PinocchioProgram, output_dir: &Path) -> Result<(
     });
 
     let content = serde_json::to_string_pretty(&security)?;
-    fs::write(output_dir.join("security.json"), content)?;
-    Ok(())
+    Ok(GeneratedCrate {
+        files: BTreeMap::from([(PathBuf::from("security.json"), content)]),
+    })
+}
+
+/// Dump each pipeline stage's IR as pretty JSON, to diff what was parsed against what was emitted
+pub fn emit_ir_json(
+    anchor: &AnchorProgram,
+    analysis: &ProgramAnalysis,
+    program: &PinocchioProgram,
+) -> Result<GeneratedCrate> {
+    let files = BTreeMap::from([
+        (PathBuf::from("anchor_ir.json"), serde_json::to_string_pretty(anchor)?),
+        (PathBuf::from("analysis.json"), serde_json::to_string_pretty(analysis)?),
+        (PathBuf::from("pinocchio_ir.json"), serde_json::to_string_pretty(program)?),
+    ]);
+    Ok(GeneratedCrate { files })
 }
 
-fn emit_helpers_rs(extras: &SourceExtras, program: &PinocchioProgram, src_dir: &Path) -> Result<()> {
+fn emit_helpers_rs(extras: &SourceExtras, program: &PinocchioProgram) -> String {
     let mut content = String::new();
 
     content.push_str("//! Constants and helper functions extracted from original source\n\n");
@@ -72,7 +168,9 @@ fn emit_helpers_rs(extras: &SourceExtras, program: &PinocchioProgram, src_dir: &
     if !extras.constants.is_empty() {
         content.push_str("// Constants\n");
         for c in &extras.constants {
-            content.push_str(&format!("pub const {}: {} = {};\n", c.name, c.ty, c.value));
+            // Pinocchio has no pubkey! macro here, so literal keys are written out as bytes
+            let value = pubkey_literal_bytes(&c.value).unwrap_or_else(|| c.value.clone());
+            content.push_str(&format!("pub const {}: {} = {};\n", c.name, c.ty, value));
         }
         content.push('\n');
     }
@@ -234,6 +332,19 @@ fn emit_helpers_rs(extras: &SourceExtras, program: &PinocchioProgram, src_dir: &
     content.push_str("    Ok(u64::from_le_bytes(data[36..44].try_into().unwrap()))\n");
     content.push_str("}\n\n");
 
+    content.push_str("/// Get mint decimals from account info\n");
+    content.push_str("#[inline(always)]\n");
+    content.push_str(
+        "pub fn get_mint_decimals(account: &AccountInfo) -> Result<u8, ProgramError> {\n",
+    );
+    content.push_str("    let data = account.try_borrow_data()?;\n");
+    content.push_str("    if data.len() < 45 {\n");
+    content.push_str("        return Err(ProgramError::InvalidAccountData);\n");
+    content.push_str("    }\n");
+    content.push_str("    // Mint decimals follow the supply at offset 44\n");
+    content.push_str("    Ok(data[44])\n");
+    content.push_str("}\n\n");
+
     content.push_str("/// Integer square root for u128 (no_std compatible)\n");
     content.push_str("#[inline(always)]\n");
     content.push_str("pub fn integer_sqrt(n: u128) -> u128 {\n");
@@ -249,8 +360,7 @@ fn emit_helpers_rs(extras: &SourceExtras, program: &PinocchioProgram, src_dir: &
     content.push_str("    x\n");
     content.push_str("}\n\n");
 
-    fs::write(src_dir.join("helpers.rs"), content)?;
-    Ok(())
+    content
 }
 
 fn clean_helper_signature(sig: &str) -> String {
@@ -395,8 +505,8 @@ fn has_comma_outside_strings(s: &str) -> bool {
     false
 }
 
-fn emit_cargo_toml(program: &PinocchioProgram, output_dir: &Path) -> Result<()> {
-    let content = format!(
+fn emit_cargo_toml(program: &PinocchioProgram) -> String {
+    format!(
         r#"[package]
 name = "{}"
 version = "0.1.0"
@@ -411,7 +521,7 @@ cpi = ["no-entrypoint"]
 
 [dependencies]
 pinocchio = "0.8"
-{}
+{}{}{}{}
 
 [profile.release]
 overflow-checks = false
@@ -429,14 +539,202 @@ incremental = false
             ""
         } else {
             "pinocchio-token = \"0.3\""
+        },
+        if program.config.pinocchio_log {
+            "\npinocchio-log = \"0.4\""
+        } else {
+            ""
+        },
+        if program
+            .instructions
+            .iter()
+            .any(|inst| inst.accounts.iter().any(|acc| acc.is_associated_token))
+        {
+            "\npinocchio-associated-token-account = \"0.1\""
+        } else {
+            ""
+        },
+        if program.instructions.iter().any(|inst| {
+            inst.accounts.iter().any(|acc| acc.is_init || acc.is_init_if_needed || acc.realloc.is_some())
+                || inst.body.contains("pinocchio_system")
+        }) {
+            "\npinocchio-system = \"0.2\""
+        } else {
+            ""
         }
+    )
+}
+
+/// Generate a cargo-fuzz crate in `fuzz/` that feeds arbitrary instruction data
+/// to the generated `process_instruction`
+pub fn emit_fuzz_target(program: &PinocchioProgram) -> GeneratedCrate {
+    let fuzz_dir = PathBuf::from("fuzz");
+
+    let cargo_toml = format!(
+        r#"[package]
+name = "{name}-fuzz"
+version = "0.0.0"
+publish = false
+edition = "2021"
+
+[package.metadata]
+cargo-fuzz = true
+
+[dependencies]
+libfuzzer-sys = "0.4"
+pinocchio = "0.8"
+
+[dependencies.{name}]
+path = ".."
+features = ["no-entrypoint"]
+
+[[bin]]
+name = "dispatch"
+path = "fuzz_targets/dispatch.rs"
+test = false
+doc = false
+bench = false
+
+# Keep the fuzz crate out of any parent workspace
+[workspace]
+members = ["."]
+"#,
+        name = program.name
     );
 
-    fs::write(output_dir.join("Cargo.toml"), content)?;
-    Ok(())
+    let program_id = if program.program_id.is_some() {
+        format!("{}::ID", program.name)
+    } else {
+        "[0u8; 32]".to_string()
+    };
+    let mut target = String::new();
+    target.push_str("#![no_main]\n\n");
+    target.push_str("use libfuzzer_sys::fuzz_target;\n");
+    target.push_str("use pinocchio::pubkey::Pubkey;\n\n");
+    target.push_str("fuzz_target!(|instruction_data: &[u8]| {\n");
+    target.push_str(&format!("    let program_id: Pubkey = {};\n", program_id));
+    target.push_str("    // No accounts: exercises discriminator dispatch and the account count checks\n");
+    // With the lazy entrypoint, process_instruction takes an InstructionContext
+    let dispatch = if program.config.lazy_entrypoint {
+        "dispatch"
+    } else {
+        "process_instruction"
+    };
+    target.push_str(&format!(
+        "    let _ = {}::{}(&program_id, &[], instruction_data);\n",
+        program.name, dispatch
+    ));
+    target.push_str("});\n");
+    let files = BTreeMap::from([
+        (fuzz_dir.join("Cargo.toml"), cargo_toml),
+        (fuzz_dir.join("fuzz_targets").join("dispatch.rs"), target),
+    ]);
+    GeneratedCrate { files }
+}
+
+/// Generate `tests/instructions.rs`: smoke tests that call the dispatcher with no accounts,
+/// checking that each discriminator reaches its handler and that the handler rejects the
+/// missing accounts. Run with `cargo test` in the output crate.
+pub fn emit_tests(program: &PinocchioProgram) -> GeneratedCrate {
+    let name = &program.name;
+    let program_id = if program.program_id.is_some() {
+        format!("{}::ID", name)
+    } else {
+        "[0u8; 32]".to_string()
+    };
+    // With the lazy entrypoint, process_instruction takes an InstructionContext
+    let dispatch = if program.config.lazy_entrypoint {
+        "dispatch"
+    } else {
+        "process_instruction"
+    };
+    let disc_bytes = |disc: &[u8]| -> String {
+        disc.iter().map(|b| format!("{:#04x}", b)).collect::<Vec<_>>().join(", ")
+    };
+
+    let mut content = String::new();
+    content.push_str("//! Smoke tests generated by uncpi. Each instruction's discriminator must reach its\n");
+    content.push_str("//! handler, which rejects a call that passes none of its accounts.\n\n");
+    content.push_str("use pinocchio::program_error::ProgramError;\n");
+    content.push_str("use pinocchio::pubkey::Pubkey;\n");
+    content.push_str("use pinocchio::ProgramResult;\n\n");
+    content.push_str(&format!("const PROGRAM_ID: Pubkey = {};\n\n", program_id));
+    content.push_str("fn call(instruction_data: &[u8]) -> ProgramResult {\n");
+    content.push_str(&format!("    {}::{}(&PROGRAM_ID, &[], instruction_data)\n", name, dispatch));
+    content.push_str("}\n\n");
+
+    content.push_str("#[test]\n");
+    content.push_str("fn short_instruction_data_is_rejected() {\n");
+    content.push_str("    assert_eq!(call(&[]), Err(ProgramError::InvalidInstructionData));\n");
+    content.push_str("}\n");
+
+    // Any discriminator of the dispatched length that no instruction uses
+    let disc_len = program.instructions.first().map_or(1, |inst| inst.discriminator.len());
+    let unknown = (0..=u8::MAX)
+        .map(|b| vec![b; disc_len])
+        .find(|disc| program.instructions.iter().all(|inst| &inst.discriminator != disc));
+    if let Some(unknown) = unknown {
+        content.push_str("\n#[test]\n");
+        content.push_str("fn unknown_discriminator_is_rejected() {\n");
+        content.push_str(&format!(
+            "    assert_eq!(call(&[{}]), Err(ProgramError::InvalidInstructionData));\n",
+            disc_bytes(&unknown)
+        ));
+        content.push_str("}\n");
+    }
+
+    for inst in &program.instructions {
+        content.push_str("\n#[test]\n");
+        if inst.accounts.is_empty() {
+            // Zeroed bytes stand in for the arguments; routing must return exactly
+            // what the handler returns for them
+            content.push_str(&format!("fn {}_is_dispatched() {{\n", inst.name));
+            content.push_str(&format!("    let mut data = vec![{}];\n", disc_bytes(&inst.discriminator)));
+            content.push_str("    data.resize(data.len() + 1024, 0);\n");
+            content.push_str(&format!(
+                "    let expected = {}::instructions::{}(&PROGRAM_ID, &[], &data[{}..]);\n",
+                name,
+                inst.name,
+                inst.discriminator.len()
+            ));
+            content.push_str("    assert_eq!(call(&data), expected);\n");
+        } else {
+            content.push_str(&format!("fn {}_requires_its_accounts() {{\n", inst.name));
+            content.push_str(&format!(
+                "    // Routed to {}, which takes {} account(s)\n",
+                inst.name,
+                inst.accounts.len()
+            ));
+            content.push_str(&format!(
+                "    assert_eq!(call(&[{}]), Err(ProgramError::NotEnoughAccountKeys));\n",
+                disc_bytes(&inst.discriminator)
+            ));
+        }
+        content.push_str("}\n");
+    }
+
+    let files = BTreeMap::from([(PathBuf::from("tests").join("instructions.rs"), content)]);
+    GeneratedCrate { files }
+}
+
+/// Pinocchio imports shared by every instruction handler
+fn emit_prelude_rs() -> String {
+    let mut content = String::new();
+
+    content.push_str("//! Imports shared by the instruction handlers\n\n");
+    content.push_str("pub use pinocchio::{\n");
+    content.push_str("    account_info::AccountInfo,\n");
+    content.push_str("    msg,\n");
+    content.push_str("    program_error::ProgramError,\n");
+    content.push_str("    pubkey::Pubkey,\n");
+    content.push_str("    ProgramResult,\n");
+    content.push_str("    sysvars::{clock::Clock, Sysvar},\n");
+    content.push_str("};\n");
+
+    content
 }
 
-fn emit_lib_rs(program: &PinocchioProgram, src_dir: &Path, has_helpers: bool) -> Result<()> {
+fn emit_lib_rs(program: &PinocchioProgram, has_helpers: bool) -> String {
     let mut content = String::new();
 
     // Use no_std for smallest binary size
@@ -451,12 +749,17 @@ fn emit_lib_rs(program: &PinocchioProgram, src_dir: &Path, has_helpers: bool) ->
     content.push_str("};\n\n");
 
     // Modules
+    content.push_str("mod prelude;\n");
     content.push_str("mod state;\n");
     content.push_str("mod error;\n");
     if has_helpers {
         content.push_str("mod helpers;\n");
     }
-    content.push_str("mod instructions;\n\n");
+    if !program.events.is_empty() {
+        content.push_str("pub mod events;\n");
+    }
+    // Public so integration tests and clients can call handlers as crate::instructions::x
+    content.push_str("pub mod instructions;\n\n");
 
     content.push_str("pub use state::*;\n");
     content.push_str("pub use error::*;\n");
@@ -485,10 +788,29 @@ fn emit_lib_rs(program: &PinocchioProgram, src_dir: &Path, has_helpers: bool) ->
     }
 
     // Entrypoint
+    let lazy = program.config.lazy_entrypoint;
     content.push_str("#[cfg(not(feature = \"no-entrypoint\"))]\n");
-    content.push_str("use pinocchio::entrypoint;\n");
+    // entrypoint! also installs the default allocator, which no_allocator! replaces
+    let (entrypoint_macro, no_alloc) = match (lazy, program.config.no_alloc) {
+        (true, _) => ("lazy_program_entrypoint", program.config.no_alloc),
+        (false, true) => ("program_entrypoint", true),
+        (false, false) => ("entrypoint", false),
+    };
+    if no_alloc {
+        content.push_str(&format!(
+            "use pinocchio::{{no_allocator, {}}};\n",
+            entrypoint_macro
+        ));
+    } else {
+        content.push_str(&format!("use pinocchio::{};\n", entrypoint_macro));
+    }
     content.push_str("#[cfg(not(feature = \"no-entrypoint\"))]\n");
-    content.push_str("entrypoint!(process_instruction);\n\n");
+    content.push_str(&format!("{}!(process_instruction);\n", entrypoint_macro));
+    if no_alloc {
+        content.push_str("#[cfg(not(feature = \"no-entrypoint\"))]\n");
+        content.push_str("no_allocator!();\n");
+    }
+    content.push('\n');
 
     // Panic handler (required for no_std)
     content.push_str("#[cfg(target_os = \"solana\")]\n");
@@ -498,35 +820,93 @@ fn emit_lib_rs(program: &PinocchioProgram, src_dir: &Path, has_helpers: bool) ->
     content.push_str("}\n\n");
 
     // Discriminator constants
-    content.push_str("// Instruction discriminators (Anchor-compatible)\n");
+    let anchor_compat = program.config.anchor_compat;
+    let disc_len = program.config.disc_len;
+    if anchor_compat && disc_len == 8 {
+        content.push_str("// Instruction discriminators (Anchor-compatible)\n");
+    } else if anchor_compat {
+        content.push_str(&format!(
+            "// Instruction discriminators (Anchor's, cut to the first {} bytes)\n",
+            disc_len
+        ));
+    } else {
+        content.push_str("// Instruction selectors (leading byte of the instruction data)\n");
+    }
     for inst in &program.instructions {
         let disc_bytes: Vec<String> = inst
             .discriminator
             .iter()
             .map(|b| format!("{:#04x}", b))
             .collect();
+        let (ty, value) = if anchor_compat {
+            (format!("[u8; {}]", disc_len), format!("[{}]", disc_bytes.join(", ")))
+        } else {
+            ("u8".to_string(), disc_bytes.join(", "))
+        };
         content.push_str(&format!(
-            "const {}_DISC: [u8; 8] = [{}];\n",
+            "const {}_DISC: {} = {};\n",
             to_screaming_snake_str(&inst.name),
-            disc_bytes.join(", ")
+            ty,
+            value
         ));
     }
     content.push('\n');
 
+    if !program.events.is_empty() {
+        content.push_str("// Event discriminators (Anchor-compatible), prefixed to logged event data\n");
+        for event in &program.events {
+            let disc_bytes: Vec<String> = event
+                .discriminator
+                .iter()
+                .map(|b| format!("{:#04x}", b))
+                .collect();
+            content.push_str(&format!(
+                "pub const {}_EVENT_DISC: [u8; 8] = [{}];\n",
+                to_screaming_snake_str(&event.name),
+                disc_bytes.join(", ")
+            ));
+        }
+        content.push('\n');
+    }
+
+    // The lazy entrypoint hands over an InstructionContext; collect its accounts
+    // so the handlers keep taking `&[AccountInfo]`
+    if lazy {
+        content.push_str(&emit_lazy_process_instruction(program));
+    }
+
     // Main dispatch function
-    content.push_str("pub fn process_instruction(\n");
+    if lazy {
+        content.push_str("pub fn dispatch(\n");
+    } else {
+        content.push_str("pub fn process_instruction(\n");
+    }
     content.push_str("    program_id: &Pubkey,\n");
     content.push_str("    accounts: &[AccountInfo],\n");
     content.push_str("    instruction_data: &[u8],\n");
     content.push_str(") -> ProgramResult {\n");
-    content.push_str("    if instruction_data.len() < 8 {\n");
-    content.push_str("        return Err(ProgramError::InvalidInstructionData);\n");
-    content.push_str("    }\n\n");
+    // ID is only emitted when declare_id! was found
+    if program.config.check_program_id && program.program_id.is_some() {
+        content.push_str("    if program_id != &ID {\n");
+        content.push_str("        return Err(ProgramError::IncorrectProgramId);\n");
+        content.push_str("    }\n\n");
+    }
+    if anchor_compat {
+        content.push_str(&format!("    if instruction_data.len() < {} {{\n", disc_len));
+        content.push_str("        return Err(ProgramError::InvalidInstructionData);\n");
+        content.push_str("    }\n\n");
 
-    content.push_str("    let (disc, data) = instruction_data.split_at(8);\n");
-    content.push_str("    let disc: [u8; 8] = disc.try_into().unwrap();\n\n");
+        content.push_str(&format!("    let (disc, data) = instruction_data.split_at({});\n", disc_len));
+        content.push_str(&format!("    let disc: [u8; {}] = disc.try_into().unwrap();\n\n", disc_len));
 
-    content.push_str("    match disc {\n");
+        content.push_str("    match disc {\n");
+    } else {
+        content.push_str("    let (disc, data) = instruction_data\n");
+        content.push_str("        .split_first()\n");
+        content.push_str("        .ok_or(ProgramError::InvalidInstructionData)?;\n\n");
+
+        content.push_str("    match *disc {\n");
+    }
 
     for inst in &program.instructions {
         content.push_str(&format!(
@@ -540,8 +920,65 @@ fn emit_lib_rs(program: &PinocchioProgram, src_dir: &Path, has_helpers: bool) ->
     content.push_str("    }\n");
     content.push_str("}\n");
 
-    fs::write(src_dir.join("lib.rs"), content)?;
-    Ok(())
+    content
+}
+
+/// `process_instruction(context)` for lazy_program_entrypoint!: reads every account
+/// into a stack buffer, then hands the slice to `dispatch`
+fn emit_lazy_process_instruction(program: &PinocchioProgram) -> String {
+    // The Rent sysvar is appended after the declared accounts for token account init
+    let max_accounts = program
+        .instructions
+        .iter()
+        .map(|inst| {
+            inst.accounts.len() + usize::from(inst.accounts.iter().any(inits_token_account))
+        })
+        .max()
+        .unwrap_or(0);
+
+    let mut content = String::new();
+    content.push_str("/// Most accounts any instruction reads; accounts past these are read past, not kept\n");
+    content.push_str(&format!("const MAX_ACCOUNTS: usize = {};\n\n", max_accounts));
+
+    content.push_str("/// Lazy entrypoint: reads the accounts out of the InstructionContext, then\n");
+    content.push_str("/// `dispatch` routes on the discriminator as the eager entrypoint does\n");
+    content.push_str("pub fn process_instruction(\n");
+    content.push_str("    mut context: pinocchio::entrypoint::InstructionContext,\n");
+    content.push_str(") -> ProgramResult {\n");
+    content.push_str(
+        "    let mut buffer = [const { core::mem::MaybeUninit::<AccountInfo>::uninit() }; MAX_ACCOUNTS];\n",
+    );
+    content.push_str("    let mut count = 0;\n");
+    // instruction_data() is only readable once every account has been consumed
+    content.push_str("    while context.remaining() > 0 {\n");
+    // Trailing accounts (Anchor's remaining_accounts) are accepted like the eager entrypoint
+    // does, but no handler reads them, so they are skipped rather than buffered
+    content.push_str("        if count == MAX_ACCOUNTS {\n");
+    content.push_str("            context.next_account()?;\n");
+    content.push_str("            continue;\n");
+    content.push_str("        }\n");
+    content.push_str("        let account = match context.next_account()? {\n");
+    content.push_str("            pinocchio::entrypoint::MaybeAccount::Account(account) => account,\n");
+    content.push_str("            pinocchio::entrypoint::MaybeAccount::Duplicated(index) => {\n");
+    content.push_str("                let index = index as usize;\n");
+    content.push_str("                if index >= count {\n");
+    content.push_str("                    return Err(ProgramError::InvalidAccountData);\n");
+    content.push_str("                }\n");
+    content.push_str("                unsafe { buffer[index].assume_init_ref().clone() }\n");
+    content.push_str("            }\n");
+    content.push_str("        };\n");
+    content.push_str("        buffer[count].write(account);\n");
+    content.push_str("        count += 1;\n");
+    content.push_str("    }\n");
+    content.push_str(
+        "    let accounts = unsafe { core::slice::from_raw_parts(buffer.as_ptr() as *const AccountInfo, count) };\n\n",
+    );
+    content.push_str("    let instruction_data = context.instruction_data()?;\n");
+    content.push_str("    let program_id = context.program_id()?;\n");
+    content.push_str("    dispatch(program_id, accounts, instruction_data)\n");
+    content.push_str("}\n\n");
+
+    content
 }
 
 fn to_screaming_snake_str(s: &str) -> String {
@@ -555,54 +992,73 @@ fn to_screaming_snake_str(s: &str) -> String {
     result
 }
 
+/// `pubkey!("...")` (or `Pubkey::from_str_const("...")`) as a `[u8; 32]` literal, which is
+/// what Pinocchio's Pubkey is
+pub(crate) fn pubkey_literal_bytes(expr: &str) -> Option<String> {
+    let expr = expr.replace(' ', "");
+    let literal = Regex::new(r#"^(?:[\w:]+::)?(?:pubkey!|Pubkey::from_str_const)\("(\w+)"\)$"#).unwrap();
+    let bytes = bs58_decode(&literal.captures(&expr)?[1]).ok()?;
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:#04x}", b)).collect();
+    Some(format!("[{}]", bytes.join(", ")))
+}
+
+/// Decode a base58 Solana address into its 32 bytes
 fn bs58_decode(s: &str) -> Result<Vec<u8>> {
-    // Simple base58 decode for Solana addresses
     const ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
 
-    let mut result: Vec<u8> = vec![0; 32];
-    let mut scratch: Vec<u64> = vec![0; 44]; // Enough for 32 bytes
-
+    // Big-endian bytes of the encoded number, grown one base58 digit at a time
+    let mut bytes: Vec<u8> = Vec::with_capacity(32);
     for c in s.bytes() {
-        let mut val = ALPHABET
+        let mut carry = ALPHABET
             .iter()
             .position(|&x| x == c)
-            .ok_or_else(|| anyhow::anyhow!("Invalid base58 character"))?
-            as u64;
+            .ok_or_else(|| anyhow::anyhow!("Invalid base58 character '{}' in {}", c as char, s))?
+            as u32;
 
-        for digit in scratch.iter_mut() {
-            val += *digit * 58;
-            *digit = val & 0xFF;
-            val >>= 8;
+        for byte in bytes.iter_mut().rev() {
+            carry += *byte as u32 * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
         }
     }
 
-    // Convert scratch to result
-    for (i, &b) in scratch.iter().take(32).enumerate() {
-        result[31 - i] = b as u8;
-    }
-
-    // Handle leading zeros
+    // Each leading '1' encodes a leading zero byte
     let leading_zeros = s.bytes().take_while(|&c| c == b'1').count();
-    for item in result.iter_mut().take(leading_zeros) {
-        *item = 0;
-    }
+    let mut result = vec![0; leading_zeros];
+    result.extend(bytes);
 
+    if result.len() != 32 {
+        anyhow::bail!("{} decodes to {} bytes, expected 32", s, result.len());
+    }
     Ok(result)
 }
 
-fn emit_state_rs(program: &PinocchioProgram, src_dir: &Path) -> Result<()> {
+fn emit_state_rs(program: &PinocchioProgram) -> String {
     let mut content = String::new();
 
-    content
-        .push_str("use pinocchio::{account_info::AccountInfo, program_error::ProgramError};\n\n");
+    let field_accessors = program.config.field_accessors;
+    content.push_str(
+        "use pinocchio::{\n    account_info::{AccountInfo, Ref, RefMut},\n    program_error::ProgramError,\n};\n\n",
+    );
 
     for state in &program.state_structs {
         // Struct definition
-        content.push_str("#[repr(C)]\n");
+        if program.config.pack_fields {
+            content.push_str("/// Fields reordered by alignment (--pack-fields): this is not the byte layout of\n");
+            content.push_str("/// the original program, so existing accounts of this type will not deserialize\n");
+        }
+        if !field_accessors {
+            content.push_str("#[repr(C)]\n");
+        }
         content.push_str("#[derive(Clone, Copy)]\n");
         content.push_str(&format!("pub struct {} {{\n", state.name));
 
-        for field in &state.fields {
+        // #[borsh(skip)] fields are not in the account data, so the cast struct leaves them out
+        for field in state.fields.iter().filter(|f| !f.borsh_skip) {
             // Transform Vec<T> to [T; N] if is_vec
             if field.is_vec {
                 if let Some(ref vec_info) = field.vec_info {
@@ -619,10 +1075,9 @@ fn emit_state_rs(program: &PinocchioProgram, src_dir: &Path) -> Result<()> {
                 }
             } else {
                 // Transform String to [u8; N] if max_len is specified
-                let field_type = if field.ty == "String" && field.max_len.is_some() {
-                    format!("[u8; {}]", field.max_len.unwrap())
-                } else {
-                    field.ty.clone()
+                let field_type = match field.max_len {
+                    Some(max_len) if field.ty == "String" => format!("[u8; {}]", max_len),
+                    _ => field.ty.clone(),
                 };
                 content.push_str(&format!("    pub {}: {},\n", field.name, field_type));
             }
@@ -632,849 +1087,5158 @@ fn emit_state_rs(program: &PinocchioProgram, src_dir: &Path) -> Result<()> {
 
         // Impl block
         content.push_str(&format!("impl {} {{\n", state.name));
-        content.push_str(&format!("    pub const SIZE: usize = {};\n\n", state.size));
+        let vec_fields: Vec<_> = state.fields.iter().filter_map(|f| f.vec_info.clone()).collect();
+        if !field_accessors && !vec_fields.is_empty() {
+            // The vecs are inline arrays plus length fields, so let the compiler lay it out
+            content.push_str("    pub const SIZE: usize = core::mem::size_of::<Self>();\n");
+        } else {
+            content.push_str(&format!("    pub const SIZE: usize = {};\n", state.size));
+        }
+        // `space = 8 + Config::INIT_SPACE` refers to it in init and realloc sizes
+        if let Some(init_space) = state.init_space {
+            content.push_str(&format!("    pub const INIT_SPACE: usize = {};\n", init_space));
+        }
+        let disc_len = state.discriminator.len();
+        let disc_bytes: Vec<String> = state.discriminator.iter().map(|b| format!("{:#04x}", b)).collect();
+        content.push_str(&format!(
+            "    pub const DISCRIMINATOR: [u8; {}] = [{}];\n\n",
+            disc_len,
+            disc_bytes.join(", ")
+        ));
 
-        // from_account_info
+        if field_accessors {
+            content.push_str(&emit_state_views(state));
+            continue;
+        }
+
+        // from_account_info: the returned guard keeps the data borrowed while the state is
+        // in use, so a conflicting borrow fails instead of aliasing it
         content.push_str("    #[inline(always)]\n");
         content.push_str(
-            "    pub fn from_account_info(info: &AccountInfo) -> Result<&Self, ProgramError> {\n",
+            "    pub fn from_account_info(info: &AccountInfo) -> Result<Ref<'_, Self>, ProgramError> {\n",
         );
         content.push_str("        let data = info.try_borrow_data()?;\n");
-        content.push_str("        if data.len() < 8 + Self::SIZE {\n");
+        content.push_str(&format!("        if data.len() < {} + Self::SIZE {{\n", disc_len));
         content.push_str("            return Err(ProgramError::InvalidAccountData);\n");
         content.push_str("        }\n");
-        content.push_str("        // Skip 8-byte discriminator\n");
-        content.push_str("        Ok(unsafe { &*(data[8..].as_ptr() as *const Self) })\n");
+        content.push_str(&format!("        // Skip {}-byte discriminator\n", disc_len));
+        content.push_str(&format!(
+            "        Ok(Ref::map(data, |data| unsafe {{ &*(data[{}..].as_ptr() as *const Self) }}))\n",
+            disc_len
+        ));
         content.push_str("    }\n\n");
 
         // from_account_info_mut
         content.push_str("    #[inline(always)]\n");
-        content.push_str("    pub fn from_account_info_mut(info: &AccountInfo) -> Result<&mut Self, ProgramError> {\n");
-        content.push_str("        let mut data = info.try_borrow_mut_data()?;\n");
-        content.push_str("        if data.len() < 8 + Self::SIZE {\n");
+        content.push_str("    pub fn from_account_info_mut(info: &AccountInfo) -> Result<RefMut<'_, Self>, ProgramError> {\n");
+        content.push_str("        let data = info.try_borrow_mut_data()?;\n");
+        content.push_str(&format!("        if data.len() < {} + Self::SIZE {{\n", disc_len));
         content.push_str("            return Err(ProgramError::InvalidAccountData);\n");
         content.push_str("        }\n");
-        content.push_str("        Ok(unsafe { &mut *(data[8..].as_mut_ptr() as *mut Self) })\n");
+        content.push_str(&format!(
+            "        Ok(RefMut::map(data, |data| unsafe {{ &mut *(data[{}..].as_mut_ptr() as *mut Self) }}))\n",
+            disc_len
+        ));
         content.push_str("    }\n");
 
-        content.push_str("}\n\n");
-    }
-
-    fs::write(src_dir.join("state.rs"), content)?;
-    Ok(())
-}
-
-fn emit_error_rs(program: &PinocchioProgram, src_dir: &Path) -> Result<()> {
-    let mut content = String::new();
-
-    content.push_str("use pinocchio::program_error::ProgramError;\n\n");
+        // Zero-copy loads for AccountLoader accounts
+        if state.is_zero_copy {
+            for (name, ret, deserialize) in [
+                ("load", "Ref<'_, Self>", "from_account_info"),
+                ("load_mut", "RefMut<'_, Self>", "from_account_info_mut"),
+            ] {
+                content.push('\n');
+                for line in crate::zero_copy::generate_safety_doc(false).lines() {
+                    content.push_str(&format!("    {}\n", line));
+                }
+                content.push_str("    #[inline(always)]\n");
+                content.push_str(&format!(
+                    "    pub unsafe fn {}(info: &AccountInfo) -> Result<{}, ProgramError> {{\n",
+                    name, ret
+                ));
+                content.push_str(&format!("        Self::{}(info)\n", deserialize));
+                content.push_str("    }\n");
+            }
+        }
 
-    // Only generate error enum if there are errors defined
-    if program.errors.is_empty() {
-        // Generate a placeholder comment if no errors
-        content.push_str("// No custom errors defined in this program\n");
-    } else {
-        content.push_str("#[repr(u32)]\n");
-        content.push_str("#[derive(Clone, Copy, Debug)]\n");
-        content.push_str("pub enum Error {\n");
+        content.push_str("}\n\n");
 
-        for error in &program.errors {
-            content.push_str(&format!("    /// {}\n", error.msg));
-            content.push_str(&format!("    {} = {},\n", error.name, error.code));
+        if !vec_fields.is_empty() {
+            let helpers = crate::collections::generate_vec_helpers(&state.name, &vec_fields);
+            content.push_str(helpers.trim_start());
+            content.push('\n');
         }
+    }
 
+    // Custom instruction arg types, filled in by the instruction's arg parsing
+    for struct_def in &program.types {
+        if struct_def.fields.iter().all(|f| is_copy_type(&f.ty, program)) {
+            content.push_str("#[derive(Clone, Copy)]\n");
+        } else {
+            content.push_str("#[derive(Clone)]\n");
+        }
+        content.push_str(&format!("pub struct {} {{\n", struct_def.name));
+        for field in &struct_def.fields {
+            content.push_str(&format!("    pub {}: {},\n", field.name, arg_field_type(&field.ty)));
+        }
         content.push_str("}\n\n");
+    }
 
-        // Impl From<Error> for ProgramError
-        content.push_str("impl From<Error> for ProgramError {\n");
-        content.push_str("    fn from(e: Error) -> Self {\n");
-        content.push_str("        ProgramError::Custom(e as u32)\n");
-        content.push_str("    }\n");
-        content.push_str("}\n");
+    for enum_def in &program.enums {
+        let fields = enum_def.variants.iter().flat_map(|v| &v.fields);
+        if fields.clone().all(|f| is_copy_type(&f.ty, program)) {
+            content.push_str("#[derive(Clone, Copy, PartialEq, Eq)]\n");
+        } else {
+            content.push_str("#[derive(Clone, PartialEq, Eq)]\n");
+        }
+        content.push_str(&format!("pub enum {} {{\n", enum_def.name));
+        for variant in &enum_def.variants {
+            let types: Vec<String> = variant.fields.iter().map(|f| arg_field_type(&f.ty)).collect();
+            if variant.fields.is_empty() {
+                content.push_str(&format!("    {},\n", variant.name));
+            } else if variant.is_tuple {
+                content.push_str(&format!("    {}({}),\n", variant.name, types.join(", ")));
+            } else {
+                let fields: Vec<String> = variant
+                    .fields
+                    .iter()
+                    .zip(&types)
+                    .map(|(f, ty)| format!("{}: {}", f.name, ty))
+                    .collect();
+                content.push_str(&format!("    {} {{ {} }},\n", variant.name, fields.join(", ")));
+            }
+        }
+        content.push_str("}\n\n");
     }
 
-    fs::write(src_dir.join("error.rs"), content)?;
-    Ok(())
+    content
 }
 
-fn emit_instructions(program: &PinocchioProgram, src_dir: &Path) -> Result<()> {
-    let inst_dir = src_dir.join("instructions");
-    fs::create_dir_all(&inst_dir)?;
-
-    // mod.rs
-    let mut mod_content = String::new();
-    for inst in &program.instructions {
-        mod_content.push_str(&format!("mod {};\n", inst.name));
+/// Whether `ty` is plain old data: primitives, keys, arrays of them, and program types made
+/// only of those. Heap-backed fields (String, Vec, Box) are not Copy
+fn is_copy_type(ty: &str, program: &PinocchioProgram) -> bool {
+    let ty = ty.replace(' ', "");
+    match ty.as_str() {
+        "bool" | "u8" | "i8" | "u16" | "i16" | "u32" | "i32" | "u64" | "i64" | "u128" | "i128"
+        | "f32" | "f64" | "Pubkey" | "pubkey::Pubkey" => return true,
+        _ => {}
     }
-    mod_content.push('\n');
-    for inst in &program.instructions {
-        mod_content.push_str(&format!("pub use {}::{};\n", inst.name, inst.name));
+    if let Some((elem, _)) = ty.strip_prefix('[').and_then(|t| t.strip_suffix(']')).and_then(|t| t.rsplit_once(';')) {
+        return is_copy_type(elem, program);
     }
-
-    fs::write(inst_dir.join("mod.rs"), mod_content)?;
-
-    // Individual instruction files
-    for inst in &program.instructions {
-        emit_instruction(inst, program, &inst_dir)?;
+    if let Some(inner) = ty.strip_prefix("Option<").and_then(|t| t.strip_suffix('>')) {
+        return is_copy_type(inner, program);
     }
-
-    Ok(())
+    if let Some(struct_def) = program.types.iter().find(|t| t.name == ty) {
+        return struct_def.fields.iter().all(|f| is_copy_type(&f.ty, program));
+    }
+    if let Some(enum_def) = program.enums.iter().find(|e| e.name == ty) {
+        return enum_def.variants.iter().flat_map(|v| &v.fields).all(|f| is_copy_type(&f.ty, program));
+    }
+    false
 }
 
-fn emit_instruction(
-    inst: &PinocchioInstruction,
-    program: &PinocchioProgram,
-    inst_dir: &Path,
-) -> Result<()> {
+/// `from_account_info` / `from_account_info_mut` returning views that read and write each
+/// field at its Borsh offset, so the account data needs no alignment. A view holds the data
+/// borrow, like the cast layout's guards. Closes the impl block opened by the caller.
+fn emit_state_views(state: &PinocchioState) -> String {
+    let name = &state.name;
     let mut content = String::new();
 
-    content.push_str("#![allow(unused_variables, unused_imports)]\n\n");
-    content.push_str("use pinocchio::{\n");
-    content.push_str("    account_info::AccountInfo,\n");
-    content.push_str("    msg,\n");
-    content.push_str("    program_error::ProgramError,\n");
-    content.push_str("    pubkey::Pubkey,\n");
-    content.push_str("    ProgramResult,\n");
-    content.push_str("    sysvars::{clock::Clock, Sysvar},\n");
-    content.push_str("};\n");
+    content.push_str("    #[inline(always)]\n");
+    content.push_str(&format!(
+        "    pub fn from_account_info(info: &AccountInfo) -> Result<{}Ref<'_>, ProgramError> {{\n",
+        name
+    ));
+    content.push_str("        let data = info.try_borrow_data()?;\n");
+    content.push_str(&format!(
+        "        if data.len() < {} + Self::SIZE {{\n",
+        state.discriminator.len()
+    ));
+    content.push_str("            return Err(ProgramError::InvalidAccountData);\n");
+    content.push_str("        }\n");
+    content.push_str(&format!("        Ok({}Ref {{ data }})\n", name));
+    content.push_str("    }\n\n");
 
-    // Add pinocchio_token if the instruction uses token operations
-    let needs_token_imports = inst.body.contains("token::")
-        || inst.body.contains("Transfer")
-        || inst.body.contains("mint_to")
-        || inst.body.contains("burn")
-        || inst
-            .accounts
-            .iter()
-            .any(|acc| acc.is_init && acc.token_mint.is_some());
+    content.push_str("    #[inline(always)]\n");
+    content.push_str(&format!(
+        "    pub fn from_account_info_mut(info: &AccountInfo) -> Result<{}Mut<'_>, ProgramError> {{\n",
+        name
+    ));
+    content.push_str("        let data = info.try_borrow_mut_data()?;\n");
+    content.push_str(&format!(
+        "        if data.len() < {} + Self::SIZE {{\n",
+        state.discriminator.len()
+    ));
+    content.push_str("            return Err(ProgramError::InvalidAccountData);\n");
+    content.push_str("        }\n");
+    content.push_str(&format!("        Ok({}Mut {{ data }})\n", name));
+    content.push_str("    }\n");
+    content.push_str("}\n\n");
 
-    if needs_token_imports {
-        let mut imports = vec!["Transfer", "MintTo", "Burn"];
+    let accessors: Vec<_> = state
+        .fields
+        .iter()
+        .zip(field_starts(state))
+        .map(|(f, start)| (f, start.and_then(|start| field_accessor(f, &start))))
+        .collect();
+    let getters: Vec<String> = accessors
+        .iter()
+        .map(|(field, accessor)| match accessor {
+            Some((ty, get, _)) => format!(
+                "    #[inline(always)]\n    pub fn {}(&self) -> {} {{\n        {}\n    }}\n",
+                field.name, ty, get
+            ),
+            None if field.borsh_skip => {
+                format!("    // {} is #[borsh(skip)]: not stored in the account data\n", field.name)
+            }
+            None => format!("    // TODO: No accessor for {}: {}\n", field.name, field.ty),
+        })
+        .collect();
+    let setters = accessors.iter().filter_map(|(field, accessor)| {
+        let (ty, _, set) = accessor.as_ref()?;
+        let mut setter = format!(
+            "    #[inline(always)]\n    pub fn set_{}(&mut self, value: {}) {{\n        {}\n    }}\n",
+            field.name, ty, set
+        );
+        // Byte arrays also get a per-byte setter, since `arr()[i] = x` would write a copy
+        if let Some(range) = set.strip_suffix(".copy_from_slice(&value);") {
+            setter.push_str(&format!(
+                "\n    #[inline(always)]\n    pub fn set_{}_at(&mut self, index: usize, value: u8) {{\n        {}[index] = value;\n    }}\n",
+                field.name, range
+            ));
+        }
+        Some(setter)
+    });
 
-        // Add InitializeAccount2 if we're initializing token accounts
-        if inst
-            .accounts
-            .iter()
-            .any(|acc| acc.is_init && acc.token_mint.is_some())
-        {
-            imports.push("InitializeAccount2");
+    content.push_str(&format!("/// Read-only view of a {} account's data\n", name));
+    content.push_str(&format!("pub struct {}Ref<'a> {{\n    data: Ref<'a, [u8]>,\n}}\n\n", name));
+    content.push_str(&format!("impl {}Ref<'_> {{\n{}}}\n\n", name, getters.join("\n")));
+
+    content.push_str(&format!("/// Mutable view of a {} account's data\n", name));
+    content.push_str(&format!("pub struct {}Mut<'a> {{\n    data: RefMut<'a, [u8]>,\n}}\n\n", name));
+    let methods: Vec<String> = getters.iter().cloned().chain(setters).collect();
+    content.push_str(&format!("impl {}Mut<'_> {{\n{}}}\n\n", name, methods.join("\n")));
+
+    content
+}
+
+/// Where a field's bytes start
+enum FieldStart {
+    Fixed(usize),
+    /// Statements leaving the offset in `cursor`, for fields after a variable-length one
+    Runtime(Vec<String>),
+}
+
+/// Start of each field. Past the first variable-length field the offset is found at runtime
+/// by stepping a cursor over the fields in between; None once a field's encoded size can't
+/// be read back from the data.
+fn field_starts(state: &PinocchioState) -> Vec<Option<FieldStart>> {
+    let mut starts = Vec::new();
+    let mut cursor: Option<Vec<String>> = None;
+
+    for field in &state.fields {
+        if field.borsh_skip {
+            starts.push(None);
+            continue;
         }
+        match (field.offset, &mut cursor) {
+            (Some(offset), _) => {
+                starts.push(Some(FieldStart::Fixed(offset)));
+                cursor = Some(vec![format!("let mut cursor = {};", offset)]);
+            }
+            (None, Some(steps)) => starts.push(Some(FieldStart::Runtime(steps.clone()))),
+            (None, None) => starts.push(None),
+        }
+        cursor = match (cursor, cursor_step(field)) {
+            (Some(mut steps), Some(step)) => {
+                steps.push(step);
+                Some(steps)
+            }
+            _ => None,
+        };
+    }
 
-        content.push_str(&format!(
-            "use pinocchio_token::instructions::{{{}}};\n",
-            imports.join(", ")
+    starts
+}
+
+/// Statement moving `cursor` past a field's Borsh encoding
+fn cursor_step(field: &PinocchioField) -> Option<String> {
+    let len_prefix = "u32::from_le_bytes(self.data[cursor..cursor + 4].try_into().unwrap()) as usize";
+    let ty = match &field.vec_info {
+        Some(vec_info) => format!("Vec<{}>", vec_info.element_type.replace(' ', "")),
+        None => field.ty.replace(' ', ""),
+    };
+    // A #[max_len] String is laid out as a byte array but encoded with its length prefix
+    let bounded_string = !field.is_vec && field.max_len.is_some();
+    if ty == "String" || ty == "Vec<u8>" || bounded_string {
+        return Some(format!("cursor += 4 + {};", len_prefix));
+    }
+    if let Some(inner) = ty.strip_prefix("Vec<").and_then(|t| t.strip_suffix('>')) {
+        let size = fixed_borsh_size(inner)?;
+        return Some(format!("cursor += 4 + {} * {};", size, len_prefix));
+    }
+    if let Some(inner) = ty.strip_prefix("Option<").and_then(|t| t.strip_suffix('>')) {
+        let size = fixed_borsh_size(inner)?;
+        return Some(format!(
+            "cursor += if self.data[cursor] == 0 {{ 1 }} else {{ {} }};",
+            1 + size
         ));
     }
-    content.push('\n');
+    // Fixed-size fields take the same room the transformer laid out for them
+    Some(format!("cursor += {};", field.size))
+}
 
-    // Only import Error if the program has custom errors
-    if !program.errors.is_empty() {
-        content.push_str("use crate::error::Error;\n");
+/// Value type, getter and setter bodies for a field stored little-endian at `start`
+fn field_accessor(field: &PinocchioField, start: &FieldStart) -> Option<(String, String, String)> {
+    // A #[max_len] String holds a length prefix and only its contents, not the whole array
+    if !field.is_vec && field.max_len.is_some() {
+        return None;
     }
-    content.push_str("use crate::helpers::*;\n");
+    let ty = field.ty.replace(' ', "");
+    let size = match ty.as_str() {
+        "u8" | "i8" | "bool" => 1,
+        "u16" | "i16" => 2,
+        "u32" | "i32" => 4,
+        "u64" | "i64" => 8,
+        "u128" | "i128" => 16,
+        _ if !field.is_vec && ty == format!("[u8;{}]", field.size) => field.size,
+        _ => return None,
+    };
+    let (prelude, start, end) = match start {
+        FieldStart::Fixed(offset) => (String::new(), offset.to_string(), (offset + size).to_string()),
+        FieldStart::Runtime(steps) => (
+            steps.iter().map(|step| format!("{}\n        ", step)).collect(),
+            "cursor".to_string(),
+            format!("cursor + {}", size),
+        ),
+    };
+    let (get, set) = match ty.as_str() {
+        "u8" => (
+            format!("self.data[{}]", start),
+            format!("self.data[{}] = value;", start),
+        ),
+        "bool" => (
+            format!("self.data[{}] != 0", start),
+            format!("self.data[{}] = value as u8;", start),
+        ),
+        _ if ty.starts_with('[') => (
+            format!("self.data[{}..{}].try_into().unwrap()", start, end),
+            format!("self.data[{}..{}].copy_from_slice(&value);", start, end),
+        ),
+        _ => (
+            format!("{}::from_le_bytes(self.data[{}..{}].try_into().unwrap())", ty, start, end),
+            format!("self.data[{}..{}].copy_from_slice(&value.to_le_bytes());", start, end),
+        ),
+    };
+    let ty = if ty.starts_with('[') { field.ty.clone() } else { ty };
+    Some((ty, format!("{}{}", prelude, get), format!("{}{}", prelude, set)))
+}
 
-    // Import state structs if referenced in body or validations
-    let mut imported_states = std::collections::HashSet::new();
-    for state in &program.state_structs {
-        // Check if referenced in instruction body
-        if inst.body.contains(&state.name) {
-            imported_states.insert(state.name.clone());
+/// Event structs whose `emit()` logs the discriminator and Borsh-encoded fields with
+/// sol_log_data, matching what Anchor's emit! writes to the transaction logs. The record is
+/// built on the stack, so every field needs a fixed Borsh size
+fn emit_events_rs(program: &PinocchioProgram) -> Result<String> {
+    let mut content = String::new();
+
+    for event in &program.events {
+        if let Some(field) = event.fields.iter().find(|f| fixed_borsh_size(&f.ty).is_none()) {
+            anyhow::bail!(
+                "Event '{}': field '{}' of type {} has no fixed Borsh size, so emit() can't encode it",
+                event.name,
+                field.name,
+                field.ty
+            );
         }
 
-        // Check if referenced in validations (for early deserialization)
-        for validation in &inst.validations {
-            let validation_str = match validation {
-                Validation::PdaCheck { seeds, .. } => seeds.join(" "),
-                Validation::Custom { code } => code.clone(),
-                _ => String::new(),
-            };
+        content.push_str(&format!("pub struct {} {{\n", event.name));
+        for field in &event.fields {
+            content.push_str(&format!("    pub {}: {},\n", field.name, arg_field_type(&field.ty)));
+        }
+        content.push_str("}\n\n");
 
-            // Check if any account fields are referenced that would require this state type
-            for acc in &inst.accounts {
-                if state.name.to_lowercase().contains(&acc.name.to_lowercase())
-                    && validation_str.contains(&format!("{} . ", acc.name))
-                {
-                    imported_states.insert(state.name.clone());
+        content.push_str(&format!("impl {} {{\n", event.name));
+        content.push_str("    pub fn emit(&self) {\n");
+        let disc = format!("crate::{}_EVENT_DISC", to_screaming_snake_str(&event.name));
+        let sizes: Vec<usize> = event.fields.iter().filter_map(|f| fixed_borsh_size(&f.ty)).collect();
+        let total: usize = sizes.iter().sum();
+        content.push_str(&format!("        let mut data = [0u8; {}];\n", 8 + total));
+        content.push_str(&format!("        data[..8].copy_from_slice(&{});\n", disc));
+        let mut offset = 8;
+        for (field, size) in event.fields.iter().zip(sizes) {
+            let range = format!("{}..{}", offset, offset + size);
+            let line = match field.ty.replace(' ', "").as_str() {
+                "u8" => format!("data[{}] = self.{};", offset, field.name),
+                "i8" | "bool" => format!("data[{}] = self.{} as u8;", offset, field.name),
+                ty if ty.starts_with('[') || ty.ends_with("Pubkey") => {
+                    format!("data[{}].copy_from_slice(&self.{});", range, field.name)
                 }
-            }
+                _ => format!(
+                    "data[{}].copy_from_slice(&self.{}.to_le_bytes());",
+                    range, field.name
+                ),
+            };
+            content.push_str(&format!("        {}\n", line));
+            offset += size;
         }
+        content.push_str("        pinocchio::log::sol_log_data(&[&data]);\n");
+        content.push_str("    }\n");
+        content.push_str("}\n\n");
     }
 
-    for state_name in &imported_states {
-        content.push_str(&format!("use crate::state::{};\n", state_name));
-    }
-    content.push('\n');
+    Ok(content)
+}
 
-    // Check if we need Rent sysvar for token account initialization
-    let needs_rent_sysvar = inst
-        .accounts
-        .iter()
-        .any(|acc| acc.is_init && acc.token_mint.is_some());
+/// Borsh size of a fixed-size type, None for String, Vec, Option and unknown types
+fn fixed_borsh_size(ty: &str) -> Option<usize> {
+    let ty = ty.replace(' ', "");
+    let size = match ty.as_str() {
+        "bool" | "u8" | "i8" => 1,
+        "u16" | "i16" => 2,
+        "u32" | "i32" => 4,
+        "u64" | "i64" => 8,
+        "u128" | "i128" => 16,
+        "Pubkey" | "pubkey::Pubkey" => 32,
+        _ => {
+            let len = ty.strip_prefix("[u8;")?.strip_suffix(']')?;
+            return len.parse().ok();
+        }
+    };
+    Some(size)
+}
 
-    let rent_sysvar_index = if needs_rent_sysvar {
-        inst.accounts.len()
+fn emit_error_rs(program: &PinocchioProgram) -> String {
+    let mut content = String::new();
+
+    content.push_str("use pinocchio::program_error::ProgramError;\n\n");
+
+    // Only generate error enum if there are errors defined
+    if program.errors.is_empty() {
+        // Generate a placeholder comment if no errors
+        content.push_str("// No custom errors defined in this program\n");
     } else {
-        0
-    };
+        content.push_str("#[repr(u32)]\n");
+        content.push_str("#[derive(Clone, Copy, Debug)]\n");
+        content.push_str("pub enum Error {\n");
 
-    // Account indices as constants for clarity
-    if !inst.accounts.is_empty() {
-        content.push_str("// Account indices\n");
-        for acc in &inst.accounts {
-            content.push_str(&format!(
-                "const {}: usize = {};\n",
-                to_screaming_snake(&acc.name),
-                acc.index
-            ));
-        }
-        if needs_rent_sysvar {
-            content.push_str(&format!(
-                "const RENT_SYSVAR: usize = {};\n",
-                rent_sysvar_index
-            ));
+        for error in &program.errors {
+            content.push_str(&format!("    /// {}\n", error.msg));
+            content.push_str(&format!("    {} = {},\n", error.name, error.code));
         }
-        content.push('\n');
-    }
 
-    // Function signature
-    content.push_str(&format!(
-        "pub fn {}(\n    program_id: &Pubkey,\n    accounts: &[AccountInfo],\n    data: &[u8],\n) -> ProgramResult {{\n",
-        inst.name
-    ));
+        content.push_str("}\n\n");
 
-    if inst.accounts.is_empty() {
-        content.push_str("    // No accounts required\n");
-        content.push_str("    Ok(())\n");
+        // Impl From<Error> for ProgramError
+        content.push_str("impl From<Error> for ProgramError {\n");
+        content.push_str("    fn from(e: Error) -> Self {\n");
+        content.push_str("        ProgramError::Custom(e as u32)\n");
+        content.push_str("    }\n");
         content.push_str("}\n");
-        fs::write(inst_dir.join(format!("{}.rs", inst.name)), content)?;
-        return Ok(());
     }
 
-    // Account validation
-    let min_accounts = if needs_rent_sysvar {
-        inst.accounts.len() + 1
-    } else {
-        inst.accounts.len()
-    };
+    content
+}
 
-    content.push_str(&format!(
-        "    // Validate account count\n    if accounts.len() < {} {{\n        return Err(ProgramError::NotEnoughAccountKeys);\n    }}\n\n",
-        min_accounts
-    ));
+fn emit_instructions(
+    program: &PinocchioProgram,
+    kept_dir: Option<&Path>,
+    constants: &[ConstantDef],
+    helper_names: &[String],
+) -> Result<BTreeMap<PathBuf, String>> {
+    let inst_dir = PathBuf::from("src").join("instructions");
 
-    // Get account references with better naming
-    content.push_str("    // Get accounts\n");
-    for acc in &inst.accounts {
-        content.push_str(&format!(
-            "    let {} = &accounts[{}];\n",
-            acc.name,
-            to_screaming_snake(&acc.name)
-        ));
+    // mod.rs
+    let mut mod_content = String::new();
+    for inst in &program.instructions {
+        mod_content.push_str(&format!("mod {};\n", inst.name));
     }
-    if needs_rent_sysvar {
-        content.push_str("    let rent_sysvar = &accounts[RENT_SYSVAR];\n");
+    mod_content.push('\n');
+    for inst in &program.instructions {
+        mod_content.push_str(&format!("pub use {}::{};\n", inst.name, inst.name));
     }
-    content.push('\n');
 
-    // Detect which instruction args are used in PDA seeds and parse them early
-    let mut args_used_in_pda: Vec<String> = Vec::new();
-    for validation in &inst.validations {
-        if let Validation::PdaCheck { seeds, .. } = validation {
-            for seed in seeds {
-                // Check if any instruction arg names appear in the seed
-                for arg in &inst.args {
-                    if seed.contains(&arg.name) && !args_used_in_pda.contains(&arg.name) {
-                        args_used_in_pda.push(arg.name.clone());
-                    }
-                }
-            }
-        }
+    let mut files = BTreeMap::from([(inst_dir.join("mod.rs"), mod_content)]);
+
+    // Individual instruction files, keeping the hand-written regions of the ones on disk
+    for inst in &program.instructions {
+        let path = inst_dir.join(format!("{}.rs", inst.name));
+        let kept_path = kept_dir.map(|dir| dir.join(&path));
+        let content = emit_instruction(inst, program, kept_path.as_deref(), constants, helper_names)?;
+        files.insert(path, content);
     }
 
-    // Parse args needed for PDA seeds BEFORE account validation
-    if !args_used_in_pda.is_empty() && !inst.args.is_empty() {
-        content.push_str("    // Parse instruction arguments needed for PDA verification\n");
-        let mut offset = 0usize;
-        for arg in &inst.args {
-            let (size, parse_code) = get_arg_parse_code(&arg.ty, offset, &arg.name);
-            if args_used_in_pda.contains(&arg.name) {
-                content.push_str(&format!("    {}\n", parse_code));
-            }
-            offset += size;
-        }
-        content.push('\n');
+    Ok(files)
+}
+
+/// Generate create-if-absent (for init_if_needed) and resize code for a realloc account.
+/// Expects `realloc_rent` to be in scope so both steps share the rent computation.
+fn emit_realloc(
+    acc: &PinocchioAccount,
+    realloc: &ReallocInfo,
+    signer_seeds: Option<&[String]>,
+    disc_len: usize,
+) -> String {
+    let mut content = String::new();
+    let name = &acc.name;
+    let payer = &realloc.payer;
+
+    // init_if_needed must run before the resize so a fresh account can be grown
+    if let (true, Some(space)) = (acc.is_init_if_needed, &acc.init_space) {
+        let init_payer = acc.init_payer.as_ref().unwrap_or(payer);
+        let mut init = emit_create_account(
+            acc,
+            space,
+            init_payer,
+            "realloc_rent",
+            "program_id",
+            signer_seeds,
+            disc_len,
+        );
+        init.push_str(&emit_write_discriminator(acc, disc_len));
+        content.push_str(&wrap_init_if_needed(acc, &init, "program_id"));
     }
 
-    // Deserialize state accounts early if their fields are referenced in validations
-    let mut state_accounts_to_deserialize: Vec<(String, String)> = Vec::new(); // (account_name, state_type)
-    for validation in &inst.validations {
-        let validation_str = match validation {
-            Validation::PdaCheck { seeds, .. } => seeds.join(" "),
-            Validation::Custom { code } => code.clone(),
-            _ => String::new(),
-        };
+    content.push_str(&format!("    // Realloc {}\n", name));
+    content.push_str(&format!("    let {}_new_len: usize = {};\n", name, realloc.space));
+    content.push_str(&format!(
+        "    let {}_rent_required = realloc_rent.minimum_balance({}_new_len);\n",
+        name, name
+    ));
+    content.push_str(&format!(
+        "    if {}_rent_required > {}.lamports() {{\n",
+        name, name
+    ));
+    content.push_str(&format!(
+        "        let top_up = {}_rent_required - {}.lamports();\n",
+        name, name
+    ));
+    // The payer is system-owned, so only the system program can debit it
+    content.push_str(&format!(
+        "        pinocchio_system::instructions::Transfer {{\n            from: {},\n            to: {},\n            lamports: top_up,\n        }}\n        .invoke()?;\n",
+        payer, name
+    ));
+    // Shrinking frees rent, which goes back to the payer
+    content.push_str(&format!(
+        "    }} else if {}.lamports() > {}_rent_required {{\n",
+        name, name
+    ));
+    content.push_str(&format!(
+        "        let refund = {}.lamports() - {}_rent_required;\n",
+        name, name
+    ));
+    content.push_str(&format!(
+        "        *{}.try_borrow_mut_lamports()? -= refund;\n",
+        name
+    ));
+    content.push_str(&format!(
+        "        *{}.try_borrow_mut_lamports()? += refund;\n",
+        payer
+    ));
+    content.push_str("    }\n");
+    content.push_str(&format!(
+        "    {}.realloc({}_new_len, {})?;\n\n",
+        name, name, realloc.zero
+    ));
 
-        // Check if any account's fields are referenced
-        for acc in &inst.accounts {
-            // Check if this account has a state type
-            if let Some(state_type) = &acc.state_type {
-                // Check if state fields are referenced in validation
-                // The transformer may have already converted "account . field" to "account_state . field"
-                // So we check for BOTH patterns
-                let account_pattern = format!("{} . ", acc.name);
-                let state_pattern = format!("{}_state . ", acc.name);
-                let key_pattern = format!("{} . key", acc.name);
+    content
+}
 
-                if (validation_str.contains(&account_pattern) || validation_str.contains(&state_pattern))
-                    && !validation_str.contains(&key_pattern)
-                    && !state_accounts_to_deserialize
-                        .iter()
-                        .any(|(name, _)| name == &acc.name)
-                {
-                    state_accounts_to_deserialize.push((acc.name.clone(), state_type.clone()));
-                }
-            }
-        }
-    }
+/// Create `acc` with `space` bytes (at least `min_len`) owned by `owner`, funded by `payer`
+/// through the system program. A PDA signs with `signer_seeds` plus its `_bump_{name}`. An address that already
+/// holds lamports is topped up, allocated and assigned instead, since CreateAccount rejects it.
+fn emit_create_account(
+    acc: &PinocchioAccount,
+    space: &str,
+    payer: &str,
+    rent: &str,
+    owner: &str,
+    signer_seeds: Option<&[String]>,
+    min_len: usize,
+) -> String {
+    let name = &acc.name;
+    let mut content = String::new();
 
-    if !state_accounts_to_deserialize.is_empty() {
-        content.push_str("    // Deserialize state accounts needed for validation\n");
-        for (acc_name, state_type) in &state_accounts_to_deserialize {
+    // Program accounts always hold at least their discriminator
+    let min_space = min_len.to_string();
+    let space = match space.trim().parse::<usize>() {
+        Ok(len) if len < min_len => min_space.as_str(),
+        _ => space,
+    };
+
+    content.push_str(&format!("    // Create {}\n", name));
+    content.push_str(&format!("    let init_len: usize = {};\n", space));
+    content.push_str(&format!("    let init_lamports = {}.minimum_balance(init_len);\n", rent));
+    let invoke = match signer_seeds {
+        Some(seeds) => {
+            let seeds: Vec<String> = seeds
+                .iter()
+                .cloned()
+                .chain([format!("&[_bump_{}]", name)])
+                .collect();
             content.push_str(&format!(
-                "    let {}_state = {}::from_account_info({})?;\n",
-                acc_name, state_type, acc_name
+                "    let {}_seeds = pinocchio::seeds!({});\n",
+                name,
+                seeds.join(", ")
             ));
+            format!(
+                ".invoke_signed(&[pinocchio::instruction::Signer::from(&{}_seeds)])?;",
+                name
+            )
         }
-        content.push('\n');
-    }
-
-    // Emit validations
-    let mut has_validations = false;
-    for validation in &inst.validations {
-        match validation {
-            Validation::IsSigner { account_idx } => {
-                if !has_validations {
-                    content.push_str("    // Validate accounts\n");
-                    has_validations = true;
-                }
-                let acc = &inst.accounts[*account_idx];
-                content.push_str(&format!(
+        None => ".invoke()?;".to_string(),
+    };
+    content.push_str(&format!("    if {}.lamports() == 0 {{\n", name));
+    content.push_str(&format!(
+        "        pinocchio_system::instructions::CreateAccount {{\n            from: {},\n            to: {},\n            lamports: init_lamports,\n            space: init_len as u64,\n            owner: {},\n        }}\n        {}\n",
+        payer, name, owner, invoke
+    ));
+    content.push_str("    } else {\n");
+    content.push_str(&format!(
+        "        let top_up = init_lamports.saturating_sub({}.lamports());\n",
+        name
+    ));
+    content.push_str("        if top_up > 0 {\n");
+    content.push_str(&format!(
+        "            pinocchio_system::instructions::Transfer {{\n                from: {},\n                to: {},\n                lamports: top_up,\n            }}\n            .invoke()?;\n",
+        payer, name
+    ));
+    content.push_str("        }\n");
+    content.push_str(&format!(
+        "        pinocchio_system::instructions::Allocate {{\n            account: {},\n            space: init_len as u64,\n        }}\n        {}\n",
+        name, invoke
+    ));
+    content.push_str(&format!(
+        "        pinocchio_system::instructions::Assign {{\n            account: {},\n            owner: {},\n        }}\n        {}\n",
+        name, owner, invoke
+    ));
+    content.push_str("    }\n");
+
+    content
+}
+
+/// Seeds a PDA being created signs with, recorded while its address was checked
+fn signer_seeds<'a>(create_seeds: &'a [(String, Vec<String>)], acc: &PinocchioAccount) -> Option<&'a [String]> {
+    create_seeds
+        .iter()
+        .find(|(name, _)| name == &acc.name)
+        .map(|(_, seeds)| seeds.as_slice())
+}
+
+/// Stamp a freshly created state account with its type's discriminator
+fn emit_write_discriminator(acc: &PinocchioAccount, disc_len: usize) -> String {
+    match &acc.state_type {
+        Some(state_type) => format!(
+            "    {}.try_borrow_mut_data()?[..{}].copy_from_slice(&{}::DISCRIMINATOR);\n",
+            acc.name, disc_len, state_type
+        ),
+        None => String::new(),
+    }
+}
+
+/// Run `init` only while the account is still empty. An account that already exists
+/// must be owned by `owner`, as Anchor checks for init_if_needed.
+fn wrap_init_if_needed(acc: &PinocchioAccount, init: &str, owner: &str) -> String {
+    let name = &acc.name;
+    let mut content = String::new();
+
+    content.push_str(&format!("    // {} is init_if_needed: skip if it already exists\n", name));
+    content.push_str(&format!("    if {}.data_is_empty() {{\n", name));
+    for line in init.trim_end().lines() {
+        if line.is_empty() {
+            content.push('\n');
+        } else {
+            content.push_str(&format!("    {}\n", line));
+        }
+    }
+    // Anchor's ConstraintOwner error code
+    content.push_str(&format!(
+        "    }} else if !{}.is_owned_by({}) {{\n        return Err(ProgramError::Custom(2004));\n    }}\n\n",
+        name, owner
+    ));
+
+    content
+}
+
+fn inits_token_account(acc: &PinocchioAccount) -> bool {
+    (acc.is_init || acc.is_init_if_needed) && acc.token_mint.is_some() && !acc.is_associated_token
+}
+
+/// Whether `code` reads a field of `account` (`pool . bump`), as opposed to
+/// calling an AccountInfo method on it (`pool . key ()`)
+fn reads_account_field(code: &str, account: &str) -> bool {
+    MEMBER_ACCESS_RE
+        .captures_iter(code)
+        .any(|caps| &caps[1] == account && caps.get(2).is_none())
+}
+
+/// Point field reads of `account` at its deserialized `{account}_state`, leaving
+/// AccountInfo method calls like `pool . key ()` alone
+fn read_state_fields(code: &str, account: &str) -> String {
+    let re = Regex::new(&format!(
+        r"(^|[^\w.])({})\s*\.\s*(\w+)(\s*\()?",
+        regex::escape(account)
+    ))
+    .unwrap();
+    re.replace_all(code, |caps: &regex::Captures| match caps.get(4) {
+        Some(_) => caps[0].to_string(),
+        None => format!("{}{}_state.{}", &caps[1], account, &caps[3]),
+    })
+    .to_string()
+}
+
+/// Named accounts for one instruction, taken from the account slice after a single
+/// length check against ACCOUNTS_LEN
+fn emit_accounts_struct(inst: &PinocchioInstruction, name: &str, needs_rent_sysvar: bool) -> String {
+    let mut fields = String::new();
+    let mut parsed = String::new();
+    for acc in &inst.accounts {
+        let idx = account_index_const(&acc.name);
+        // Nothing validates unchecked accounts, so keep the reason they are safe in sight
+        if let Some(check) = &acc.check_doc {
+            fields.push_str(&format!("    // {}\n", check));
+        }
+        if acc.is_optional {
+            // Anchor passes the program id in place of an absent optional account
+            fields.push_str(&format!("    pub {}: Option<&'a AccountInfo>,\n", acc.name));
+            parsed.push_str(&format!(
+                "            {}: if accounts[{idx}].key() == program_id {{ None }} else {{ Some(&accounts[{idx}]) }},\n",
+                acc.name,
+                idx = idx
+            ));
+        } else {
+            fields.push_str(&format!("    pub {}: &'a AccountInfo,\n", acc.name));
+            parsed.push_str(&format!("            {}: &accounts[{}],\n", acc.name, idx));
+        }
+    }
+    if needs_rent_sysvar {
+        fields.push_str("    pub rent_sysvar: &'a AccountInfo,\n");
+        parsed.push_str("            rent_sysvar: &accounts[ACCOUNT_RENT_SYSVAR],\n");
+    }
+
+    let mut content = String::new();
+    content.push_str(&format!("/// Accounts for `{}`, in instruction order\n", inst.name));
+    content.push_str(&format!("pub struct {}<'a> {{\n{}}}\n\n", name, fields));
+    content.push_str(&format!("impl<'a> {}<'a> {{\n", name));
+    content.push_str("    #[inline(always)]\n");
+    content.push_str(
+        "    pub fn parse(program_id: &Pubkey, accounts: &'a [AccountInfo]) -> Result<Self, ProgramError> {\n",
+    );
+    content.push_str("        if accounts.len() < ACCOUNTS_LEN {\n");
+    content.push_str("            return Err(ProgramError::NotEnoughAccountKeys);\n");
+    content.push_str("        }\n");
+    content.push_str(&format!("        Ok(Self {{\n{}        }})\n", parsed));
+    content.push_str("    }\n}\n\n");
+    content
+}
+
+/// Turn `state.field` reads and assignments into the getters / setters emitted for
+/// `--field-accessors`, e.g. `pool_state.total += x;` -> `pool_state.set_total(pool_state.total() + x);`
+fn use_field_accessors(code: &str, state_var: &str) -> String {
+    let var = regex::escape(state_var);
+    let read = Regex::new(&format!(r"(^|[^\w.])({})\s*\.\s*(\w+)(\s*\()?", var)).unwrap();
+    let read_fields = |code: &str| {
+        read.replace_all(code, |caps: &regex::Captures| match caps.get(4) {
+            Some(_) => caps[0].to_string(),
+            None => format!("{}{}.{}()", &caps[1], state_var, &caps[3]),
+        })
+        .to_string()
+    };
+
+    // `var.bytes[i] op= x` goes through the per-byte setter
+    let assign_at = Regex::new(&format!(
+        r"(^|[^\w.])({})\s*\.\s*(\w+)\s*\[([^\]]+)\]\s*(\+|-|\*|/|%|\||&|\^|<<|>>)?=([^=][^;]*);",
+        var
+    ))
+    .unwrap();
+    let code = assign_at.replace_all(code, |caps: &regex::Captures| {
+        let index = read_fields(caps[4].trim());
+        let value = read_fields(caps[6].trim());
+        let value = match caps.get(5) {
+            None => value,
+            Some(op) => format!("{}.{}()[{}] {} ({})", state_var, &caps[3], index, op.as_str(), value),
+        };
+        format!("{}{}.set_{}_at({}, {});", &caps[1], state_var, &caps[3], index, value)
+    });
+
+    let assign = Regex::new(&format!(
+        r"(^|[^\w.])({})\s*\.\s*(\w+)\s*(\+|-|\*|/|%|\||&|\^|<<|>>)?=([^=][^;]*);",
+        var
+    ))
+    .unwrap();
+    let code = assign.replace_all(&code, |caps: &regex::Captures| {
+        let value = read_fields(caps[5].trim());
+        let value = match caps.get(4) {
+            None => value,
+            Some(op) => {
+                let operand = if value.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.') {
+                    value
+                } else {
+                    format!("({})", value)
+                };
+                format!("{}.{}() {} {}", state_var, &caps[3], op.as_str(), operand)
+            }
+        };
+        format!("{}{}.set_{}({});", &caps[1], state_var, &caps[3], value)
+    });
+
+    read_fields(&code)
+}
+
+/// A `{account}_state` Ref / RefMut from `from_account_info(_mut)`: the account's data stays
+/// borrowed until it's dropped
+struct StateGuard {
+    var: String,
+    account: String,
+    decl: String,
+}
+
+impl StateGuard {
+    /// The read-only guard taken for validations
+    fn new(account: &str, state_type: &str) -> Self {
+        StateGuard {
+            var: format!("{}_state", account),
+            account: account.to_string(),
+            decl: format!("let {}_state = {}::from_account_info({})?;", account, state_type, account),
+        }
+    }
+}
+
+/// Whether `code` binds `var` with `let`
+fn declares(code: &str, var: &str) -> bool {
+    Regex::new(&format!(r"\blet\s+(?:mut\s+)?{}\s*=", regex::escape(var)))
+        .unwrap()
+        .is_match(code)
+}
+
+/// Statements that open a block rather than make a call
+const NESTING_KEYWORDS: &[&str] = &["if ", "for ", "while ", "loop ", "loop{", "match ", "{", "unsafe "];
+
+fn is_cpi_call(code: &str) -> bool {
+    code.contains(".invoke()") || code.contains(".invoke_signed(") || code.contains("cpi::invoke")
+}
+
+fn mentions(code: &str, var: &str) -> bool {
+    Regex::new(&format!(r"\b{}\b", regex::escape(var))).unwrap().is_match(code)
+}
+
+/// `invoke` refuses an account whose data is borrowed elsewhere, so a state guard is dropped
+/// before each top-level statement with a CPI that passes its account and taken again after
+/// it if the handler still reads the state. Fields the CPI reads itself (`pool_state.bump` in
+/// its seeds) are copied out first. Inside an `if` / loop holding the CPI, every other use of
+/// the state borrows it afresh for that one statement. Returns the handler lines and the
+/// guards still held at the end.
+fn release_state_guards(
+    lines: Vec<String>,
+    mut held: Vec<StateGuard>,
+) -> Result<(Vec<String>, Vec<StateGuard>)> {
+    let decl_re =
+        Regex::new(r"^let (?:mut )?(\w+_state) = \w+::from_account_info(?:_mut)?\((\w+)\)\?;$")
+            .unwrap();
+    let literal_re = Regex::new(r#""(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)'"#).unwrap();
+
+    // A line at depth 0 ending in `;` or `}` closes a top-level statement, unless a method
+    // call (`}.invoke()?;`) or `else` continues it on the next line
+    let mut statements: Vec<Vec<String>> = Vec::new();
+    let mut current = Vec::new();
+    let mut depth = 0i32;
+    let mut lines = lines.into_iter().peekable();
+    while let Some(line) = lines.next() {
+        let continues = lines
+            .peek()
+            .is_some_and(|next| next.starts_with('.') || next.starts_with("else"));
+        let is_comment = line.starts_with("//");
+        if !is_comment {
+            for c in literal_re.replace_all(&line, "").chars() {
+                match c {
+                    '{' | '(' | '[' => depth += 1,
+                    '}' | ')' | ']' => depth -= 1,
+                    _ => {}
+                }
+            }
+        }
+        let closes = depth == 0
+            && !is_comment
+            && (line.ends_with(';') || (line.ends_with('}') && !continues));
+        current.push(line);
+        if closes {
+            statements.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        statements.push(current);
+    }
+
+    let mut out = Vec::new();
+    for (i, statement) in statements.iter().enumerate() {
+        let text = statement.join("\n");
+
+        // Taking the same state again replaces the guard held so far
+        let decl = statement.iter().find(|l| !l.starts_with("//")).and_then(|l| decl_re.captures(l));
+        if let Some(caps) = decl {
+            if let Some(pos) = held.iter().position(|g| g.var == caps[1]) {
+                out.push(format!("drop({});", &caps[1]));
+                held.remove(pos);
+            }
+            out.extend(statement.iter().cloned());
+            held.push(StateGuard {
+                var: caps[1].to_string(),
+                account: caps[2].to_string(),
+                decl: caps[0].to_string(),
+            });
+            continue;
+        }
+
+        if !is_cpi_call(&text) {
+            out.extend(statement.iter().cloned());
+            continue;
+        }
+
+        // The CPI is nested when the statement is a block, e.g. `if amount > 0 { .. }`
+        let nested = statement
+            .iter()
+            .find(|l| !l.starts_with("//"))
+            .is_some_and(|l| NESTING_KEYWORDS.iter().any(|k| l.starts_with(k)));
+        // The parts of the statement that make the calls; the rest runs before or after them
+        let cpi_parts: Vec<&str> = if nested {
+            text.split(';').filter(|part| is_cpi_call(part)).collect()
+        } else {
+            vec![text.as_str()]
+        };
+
+        let mut statement = statement.clone();
+        let mut released = Vec::new();
+        for (idx, guard) in held.iter().enumerate() {
+            let passes_account = Regex::new(&format!(r"(?:^|[^\w.]){}\b", regex::escape(&guard.account)))
+                .unwrap()
+                .is_match(&text);
+            if !passes_account {
+                continue;
+            }
+            let field_re = Regex::new(&format!(
+                r"\b{}\s*\.\s*(\w+)\b(\s*\(|\s*(?:[-+*/%&|^]|<<|>>)?=[^=])?",
+                regex::escape(&guard.var)
+            ))
+            .unwrap();
+            // Method calls and writes on the state can't be copied out ahead of the CPI, and
+            // keeping the guard would fail the CPI with AccountBorrowFailed
+            if cpi_parts
+                .iter()
+                .any(|part| field_re.captures_iter(part).any(|caps| caps.get(2).is_some()))
+            {
+                anyhow::bail!(
+                    "a CPI passing '{}' also writes {} in the same statement, which keeps its data \
+                     borrowed during the call; write the state in a statement of its own",
+                    guard.account,
+                    guard.var
+                );
+            }
+            let mut fields: Vec<String> = cpi_parts
+                .iter()
+                .flat_map(|part| field_re.captures_iter(part).map(|caps| caps[1].to_string()))
+                .collect();
+            fields.sort();
+            fields.dedup();
+            if nested {
+                // A copy taken before the block would miss the block's own writes
+                let written = field_re
+                    .captures_iter(&text)
+                    .find(|caps| caps.get(2).is_some() && fields.contains(&caps[1].to_string()));
+                if let Some(caps) = written {
+                    anyhow::bail!(
+                        "a CPI passing '{}' reads {}.{}, which the enclosing block also changes; \
+                         move the CPI out of the block",
+                        guard.account,
+                        guard.var,
+                        &caps[1]
+                    );
+                }
+            }
+            for field in &fields {
+                out.push(format!("let {}_{} = {}.{};", guard.var, field, guard.var, field));
+            }
+            let copied = Regex::new(&format!(
+                r"\b{}\s*\.\s*({})\b",
+                regex::escape(&guard.var),
+                fields.join("|")
+            ))
+            .unwrap();
+            // Whatever else the block does with the state borrows it until the end of
+            // that statement only
+            let guard_re = Regex::new(&format!(r"\b{}\b", regex::escape(&guard.var))).unwrap();
+            let borrow = guard.decl.split_once(" = ").map(|(_, expr)| expr.trim_end_matches(';'));
+            for line in &mut statement {
+                if !fields.is_empty() {
+                    *line = copied.replace_all(line, format!("{}_$1", guard.var)).into_owned();
+                }
+                if let (true, Some(borrow)) = (nested, borrow) {
+                    *line = guard_re.replace_all(line, format!("({})", borrow)).into_owned();
+                }
+            }
+            out.push(format!("drop({});", guard.var));
+            released.push(idx);
+        }
+        out.extend(statement);
+
+        let rest = statements[i + 1..].iter().flatten().cloned().collect::<Vec<_>>().join("\n");
+        for idx in released.into_iter().rev() {
+            if mentions(&rest, &held[idx].var) {
+                // A guard the rest of the handler only reads is taken again without `mut`
+                let write_re = Regex::new(&format!(
+                    r"&\s*mut\s+{var}\b|\b{var}\s*\.\s*\w+\s*(?:\.|\(|\[|(?:[-+*/%&|^]|<<|>>)?=[^=])",
+                    var = regex::escape(&held[idx].var)
+                ))
+                .unwrap();
+                if write_re.is_match(&rest) {
+                    out.push(held[idx].decl.clone());
+                } else {
+                    out.push(held[idx].decl.replacen("let mut ", "let ", 1));
+                }
+            } else {
+                held.remove(idx);
+            }
+        }
+    }
+
+    Ok((out, held))
+}
+
+/// Route each early `return Ok(())` in the handler logic through `close_call`, first dropping
+/// the state guards still borrowing a `closed` account's data there. Guards are tracked through the
+/// top-level `let x_state = ..` and `drop(x_state);` lines; returns the lines and the guards
+/// still held at the end.
+fn close_on_early_returns(
+    lines: Vec<String>,
+    mut held: Vec<StateGuard>,
+    closed: &[&str],
+    close_call: &str,
+) -> (Vec<String>, Vec<StateGuard>) {
+    let decl_re =
+        Regex::new(r"^let (?:mut )?(\w+_state) = \w+::from_account_info(?:_mut)?\((\w+)\)\?;$")
+            .unwrap();
+    let drop_re = Regex::new(r"^drop\((\w+)\);$").unwrap();
+    let return_re = Regex::new(r"\breturn\s+Ok\s*\(\s*\(\s*\)\s*\)").unwrap();
+
+    let mut out = Vec::new();
+    let mut depth = 0i32;
+    for line in lines {
+        if depth == 0 {
+            if let Some(caps) = decl_re.captures(&line) {
+                held.retain(|g| g.var != caps[1]);
+                held.push(StateGuard {
+                    var: caps[1].to_string(),
+                    account: caps[2].to_string(),
+                    decl: caps[0].to_string(),
+                });
+            } else if let Some(caps) = drop_re.captures(&line) {
+                held.retain(|g| g.var != caps[1]);
+            }
+        }
+        let drops: Vec<String> = held
+            .iter()
+            .filter(|g| closed.contains(&g.account.as_str()))
+            .map(|g| format!("drop({});", g.var))
+            .collect();
+        let exit = if drops.is_empty() {
+            format!("return {}", close_call)
+        } else {
+            format!("{{ {} return {} }}", drops.join(" "), close_call)
+        };
+        let line = return_re.replace_all(&line, exit.as_str()).into_owned();
+        if !line.starts_with("//") {
+            depth += line.matches('{').count() as i32 - line.matches('}').count() as i32;
+        }
+        out.push(line);
+    }
+    (out, held)
+}
+
+/// Drain `close = target` accounts and mark them closed
+fn emit_close(acc: &PinocchioAccount, target: &str, disc_len: usize) -> String {
+    let name = &acc.name;
+    let mut content = String::new();
+
+    content.push_str(&format!("    // Close {}, sending its lamports to {}\n", name, target));
+    content.push_str(&format!("    let {}_lamports = {}.lamports();\n", name, name));
+    content.push_str(&format!(
+        "    *{}.try_borrow_mut_lamports()? += {}_lamports;\n",
+        target, name
+    ));
+    content.push_str(&format!("    *{}.try_borrow_mut_lamports()? = 0;\n", name));
+    // Clear the state, then stamp Anchor's CLOSED_ACCOUNT_DISCRIMINATOR so the
+    // account can't be read back as valid state before the runtime reclaims it
+    content.push_str("    {\n");
+    content.push_str(&format!(
+        "        let mut {}_data = {}.try_borrow_mut_data()?;\n",
+        name, name
+    ));
+    content.push_str(&format!("        for byte in {}_data.iter_mut() {{\n", name));
+    content.push_str("            *byte = 0;\n");
+    content.push_str("        }\n");
+    content.push_str(&format!("        if {}_data.len() >= {} {{\n", name, disc_len));
+    content.push_str(&format!(
+        "            {}_data[..{len}].copy_from_slice(&[255u8; {len}]);\n",
+        name,
+        len = disc_len
+    ));
+    content.push_str("        }\n");
+    content.push_str("    }\n");
+
+    content
+}
+
+fn emit_instruction(
+    inst: &PinocchioInstruction,
+    program: &PinocchioProgram,
+    kept_path: Option<&Path>,
+    constants: &[ConstantDef],
+    helper_names: &[String],
+) -> Result<String> {
+    let mut content = String::new();
+
+    content.push_str("use crate::prelude::*;\n");
+
+    // Add pinocchio_token if the instruction uses token operations
+    // Token instructions beyond the default Transfer / MintTo / Burn
+    let extra_token_imports: Vec<&str> = [
+        "TransferChecked",
+        "MintToChecked",
+        "Approve",
+        "Revoke",
+        "FreezeAccount",
+        "ThawAccount",
+    ]
+    .into_iter()
+    .filter(|ix| inst.body.contains(&format!("{} {{", ix)))
+    .collect();
+
+    let needs_token_imports = !extra_token_imports.is_empty()
+        || inst.body.contains("token::")
+        || inst.body.contains("Transfer")
+        || inst.body.contains("mint_to")
+        || inst.body.contains("burn")
+        || inst
+            .accounts
+            .iter()
+            .any(inits_token_account);
+
+    if needs_token_imports {
+        let mut imports = vec!["Transfer", "MintTo", "Burn"];
+
+        // Add InitializeAccount2 if we're initializing token accounts
+        if inst
+            .accounts
+            .iter()
+            .any(inits_token_account)
+        {
+            imports.push("InitializeAccount2");
+        }
+        imports.extend(extra_token_imports);
+
+        content.push_str(&format!(
+            "use pinocchio_token::instructions::{{{}}};\n",
+            imports.join(", ")
+        ));
+    }
+    content.push('\n');
+
+    // Only import Error if the program has custom errors
+    if !program.errors.is_empty() {
+        content.push_str("use crate::error::Error;\n");
+    }
+    content.push_str("use crate::helpers::*;\n");
+
+    // Import state structs if referenced in body or validations
+    let mut imported_states = std::collections::HashSet::new();
+    for state in &program.state_structs {
+        // Check if referenced in instruction body
+        if inst.body.contains(&state.name) {
+            imported_states.insert(state.name.clone());
+        }
+
+        // Accounts created here are stamped with their type's DISCRIMINATOR
+        if inst.accounts.iter().any(|acc| {
+            (acc.is_init || acc.is_init_if_needed)
+                && acc.init_space.is_some()
+                && acc.state_type.as_deref() == Some(state.name.as_str())
+        }) {
+            imported_states.insert(state.name.clone());
+        }
+
+        // Check if referenced in validations (for early deserialization)
+        for validation in &inst.validations {
+            let validation_str = match validation {
+                Validation::PdaCheck { seeds, bump, .. } => seeds.iter().chain(bump).cloned().collect::<Vec<_>>().join(" "),
+                Validation::Custom { code } => code.clone(),
+                _ => String::new(),
+            };
+
+            // Check if any account fields are referenced that would require this state type
+            for acc in &inst.accounts {
+                if state.name.to_lowercase().contains(&acc.name.to_lowercase())
+                    && validation_str.contains(&format!("{} . ", acc.name))
+                {
+                    imported_states.insert(state.name.clone());
+                }
+                if acc.state_type.as_deref() == Some(state.name.as_str())
+                    && validation_str.contains(&format!("{}_state.", acc.name))
+                {
+                    imported_states.insert(state.name.clone());
+                }
+            }
+        }
+    }
+
+    // Custom arg types are built while parsing args, including ones nested in them
+    let mut arg_types: Vec<&str> = inst.args.iter().map(|arg| arg.ty.as_str()).collect();
+    while let Some(ty) = arg_types.pop() {
+        let ty = ty.replace(' ', "");
+        if let Some(t) = program.types.iter().find(|t| t.name == ty) {
+            if imported_states.insert(t.name.clone()) {
+                arg_types.extend(t.fields.iter().map(|f| f.ty.as_str()));
+            }
+        } else if let Some(e) = program.enums.iter().find(|e| e.name == ty) {
+            if imported_states.insert(e.name.clone()) {
+                arg_types.extend(e.variants.iter().flat_map(|v| &v.fields).map(|f| f.ty.as_str()));
+            }
+        }
+    }
+
+    for state_name in &imported_states {
+        content.push_str(&format!("use crate::state::{};\n", state_name));
+    }
+    content.push('\n');
+
+    // Check if we need Rent sysvar for token account initialization
+    let needs_rent_sysvar = inst
+        .accounts
+        .iter()
+        .any(inits_token_account);
+
+    let rent_sysvar_index = if needs_rent_sysvar {
+        inst.accounts.len()
+    } else {
+        0
+    };
+
+    // Account indices as constants for clarity
+    let accounts_struct_name = format!("{}Accounts", to_pascal_case(&inst.name));
+    if !inst.accounts.is_empty() {
+        content.push_str("// Account indices\n");
+        for acc in &inst.accounts {
+            content.push_str(&format!(
+                "const {}: usize = {};\n",
+                account_index_const(&acc.name),
+                acc.index
+            ));
+        }
+        if needs_rent_sysvar {
+            content.push_str(&format!(
+                "const ACCOUNT_RENT_SYSVAR: usize = {};\n",
+                rent_sysvar_index
+            ));
+        }
+        let accounts_len = if needs_rent_sysvar {
+            inst.accounts.len() + 1
+        } else {
+            inst.accounts.len()
+        };
+        content.push_str(&format!("const ACCOUNTS_LEN: usize = {};\n\n", accounts_len));
+        content.push_str(&emit_accounts_struct(inst, &accounts_struct_name, needs_rent_sysvar));
+    }
+
+    // Instruction args struct (--arg-structs)
+    let use_arg_struct = program.config.arg_structs && !inst.args.is_empty();
+    let args_struct_name = format!("{}Args", to_pascal_case(&inst.name));
+    if use_arg_struct {
+        content.push_str(&emit_args_struct(inst, &args_struct_name, program)?);
+    }
+
+    // Keep rarely called handlers out of the dispatch hot path
+    if program.config.cold_instructions.contains(&inst.name) {
+        content.push_str("#[inline(never)]\n#[cold]\n");
+    }
+
+    // Function signature
+    content.push_str(&format!(
+        "pub fn {}(\n    program_id: &Pubkey,\n    accounts: &[AccountInfo],\n    data: &[u8],\n) -> ProgramResult {{\n",
+        inst.name
+    ));
+
+    // Get accounts, checked against ACCOUNTS_LEN once by the accounts struct
+    if inst.accounts.is_empty() {
+        content.push_str("    // No accounts required\n");
+    } else {
+        let mut names: Vec<&str> = inst.accounts.iter().map(|a| a.name.as_str()).collect();
+        if needs_rent_sysvar {
+            names.push("rent_sysvar");
+        }
+        content.push_str("    // Get accounts\n");
+        content.push_str(&format!(
+            "    let {name} {{ {} }} = {name}::parse(program_id, accounts)?;\n",
+            names.join(", "),
+            name = accounts_struct_name
+        ));
+    }
+    content.push('\n');
+
+    // Everything after the args struct is parsed refers to its fields
+    let args_start = content.len();
+    if use_arg_struct {
+        content.push_str(&format!(
+            "    let args = {}::try_from(data)?;\n\n",
+            args_struct_name
+        ));
+    }
+
+    // Detect which instruction args are used in PDA seeds or constraints and parse them early.
+    // Only bare uses count: `config . fee` is a state field even when an arg is named `fee`.
+    let arg_res: Vec<(&str, Regex)> = inst
+        .args
+        .iter()
+        .map(|arg| {
+            let re = Regex::new(&format!(r"(?:^|[^.\s])\s*\b{}\b", regex::escape(&arg.name))).unwrap();
+            (arg.name.as_str(), re)
+        })
+        .collect();
+    let mut args_used_in_validations: Vec<String> = Vec::new();
+    for validation in inst.validations.iter().filter(|_| !use_arg_struct) {
+        let exprs: Vec<&String> = match validation {
+            // A provided bump is part of the seeds passed to create_program_address
+            Validation::PdaCheck { seeds, bump, .. } => seeds.iter().chain(bump).collect(),
+            Validation::Custom { code } => vec![code],
+            _ => continue,
+        };
+        for expr in exprs {
+            // Check if any instruction arg names appear in the seed or constraint
+            for (name, arg_re) in &arg_res {
+                if arg_re.is_match(expr) && !args_used_in_validations.iter().any(|used| used == name) {
+                    args_used_in_validations.push(name.to_string());
+                }
+            }
+        }
+    }
+
+    // Parse args needed for PDA seeds and constraints BEFORE account validation
+    if !args_used_in_validations.is_empty() && !inst.args.is_empty() {
+        content.push_str("    // Parse instruction arguments needed for account validation\n");
+        let mut offset = 0usize;
+        for arg in &inst.args {
+            let (size, parse_code) = get_arg_parse_code(&arg.ty, offset, &arg.name, program)
+                .with_context(|| format!("Instruction '{}'", inst.name))?;
+            if args_used_in_validations.contains(&arg.name) {
+                content.push_str(&indent_lines(&parse_code, "    "));
+            }
+            offset += size;
+        }
+        content.push('\n');
+    }
+
+    // Hand-written logic kept from a previous run stands in for the transformed body
+    let existing = kept_path.and_then(|path| Some((path, fs::read_to_string(path).ok()?)));
+    let kept = match existing {
+        Some((path, existing)) => kept_regions(&existing)
+            .with_context(|| format!("Failed to read kept regions from {:?}", path))?,
+        None => Vec::new(),
+    };
+
+    // Deserialize state accounts early if their fields are referenced in validations
+    let mut state_accounts_to_deserialize: Vec<(String, String)> = Vec::new(); // (account_name, state_type)
+    for validation in &inst.validations {
+        // A stored bump (`bump = pool.bump`) is read from state just like a seed
+        let validation_str = match validation {
+            Validation::PdaCheck { seeds, bump, .. } => {
+                seeds.iter().chain(bump).cloned().collect::<Vec<_>>().join(" ")
+            }
+            Validation::Custom { code } => code.clone(),
+            _ => String::new(),
+        };
+
+        // Check if any account's fields are referenced
+        for acc in &inst.accounts {
+            // Check if this account has a state type
+            if let Some(state_type) = &acc.state_type {
+                // Check if state fields are referenced in validation
+                // The transformer may have already converted "account . field" to "account_state . field"
+                // So we check for BOTH patterns
+                let state_pattern = format!("{}_state . ", acc.name);
+                let state_pattern_compact = format!("{}_state.", acc.name);
+
+                // `pool . key ()` is an AccountInfo call; only field reads need the state
+                if (reads_account_field(&validation_str, &acc.name)
+                    || validation_str.contains(&state_pattern)
+                    || validation_str.contains(&state_pattern_compact))
+                    && !state_accounts_to_deserialize
+                        .iter()
+                        .any(|(name, _)| name == &acc.name)
+                {
+                    state_accounts_to_deserialize.push((acc.name.clone(), state_type.clone()));
+                }
+            }
+        }
+    }
+
+    if !state_accounts_to_deserialize.is_empty() {
+        content.push_str("    // Deserialize state accounts needed for validation\n");
+        for (acc_name, state_type) in &state_accounts_to_deserialize {
+            content.push_str(&format!(
+                "    let {}_state = {}::from_account_info({})?;\n",
+                acc_name, state_type, acc_name
+            ));
+        }
+        content.push('\n');
+    }
+
+    // Emit validations
+    let mut create_seeds: Vec<(String, Vec<String>)> = Vec::new();
+    let mut has_validations = false;
+    for validation in &inst.validations {
+        if !has_validations
+            && matches!(
+                validation,
+                Validation::IsSigner { .. }
+                    | Validation::IsWritable { .. }
+                    | Validation::PdaCheck { .. }
+                    | Validation::OwnerCheck { .. }
+                    | Validation::KeyEquals { .. }
+                    | Validation::Custom { .. }
+            )
+        {
+            content.push_str("    // Validate accounts\n");
+            has_validations = true;
+        }
+        let validation_start = content.len();
+
+        match validation {
+            Validation::IsSigner { account_idx } => {
+                let acc = &inst.accounts[*account_idx];
+                content.push_str(&format!(
                     "    if !{}.is_signer() {{\n        return Err(ProgramError::MissingRequiredSignature);\n    }}\n",
                     acc.name
                 ));
             }
-            Validation::IsWritable { account_idx } => {
-                if !has_validations {
-                    content.push_str("    // Validate accounts\n");
-                    has_validations = true;
+            Validation::IsWritable { account_idx } => {
+                let acc = &inst.accounts[*account_idx];
+                content.push_str(&format!(
+                    "    if !{}.is_writable() {{\n        return Err(ProgramError::Immutable);\n    }}\n",
+                    acc.name
+                ));
+            }
+            Validation::OwnerCheck { account_idx, owner, error } => {
+                let acc = &inst.accounts[*account_idx];
+                content.push_str(&format!(
+                    "    if !{}.is_owned_by({}) {{\n        return Err({});\n    }}\n",
+                    acc.name, owner, error
+                ));
+            }
+            Validation::KeyEquals {
+                account_idx,
+                expected,
+            } => {
+                // Anchor's ConstraintTokenMint error code
+                let acc = &inst.accounts[*account_idx];
+                content.push_str(&format!(
+                    "    if *{}.key() != {} {{\n        return Err(ProgramError::Custom(2014));\n    }}\n",
+                    acc.name, expected
+                ));
+            }
+            Validation::PdaCheck {
+                account_idx,
+                seeds,
+                bump,
+            } => {
+                let acc = &inst.accounts[*account_idx];
+                // Generate actual PDA validation code
+                let mut seeds_code: Vec<String> = seeds
+                    .iter()
+                    .map(|s| {
+                        let mut seed = s.clone();
+
+                        // Transform state field references: "pool . bump" -> "pool_state.bump"
+                        // while "pool . key ()" stays on the AccountInfo
+                        for (state_acc, _) in &state_accounts_to_deserialize {
+                            seed = read_state_fields(&seed, state_acc);
+                        }
+
+                        // `&expr` already borrows bytes (`&id.to_le_bytes()`, `&[bump]`), so it
+                        // stays as written; `.as_ref()` is only dropped when doubled up. A
+                        // Pinocchio key is already a byte array, so `&k.key().to_bytes()` borrows it
+                        let borrowed = seed.trim().strip_prefix('&').map(str::trim_start);
+                        if let Some(inner) = borrowed {
+                            let compact = inner.replace(' ', "");
+                            if let Some(key) = compact.strip_suffix(".key().to_bytes()") {
+                                format!("{}.key().as_ref()", key)
+                            } else if compact.ends_with(".as_ref()") {
+                                inner.to_string()
+                            } else {
+                                seed
+                            }
+                        } else if seed.starts_with("b\"") {
+                            format!("{}.as_ref()", seed)
+                        } else if seed.contains(".key()") {
+                            let acc_name = seed
+                                .replace(".key()", "")
+                                .replace(".as_ref()", "")
+                                .replace(" ", "");
+                            format!("{}.key().as_ref()", acc_name)
+                        } else if seed.contains("as_ref") {
+                            seed
+                        } else if let Some(c) = constants.iter().find(|c| c.name == seed.trim()) {
+                            // Resolve constant seeds by their declared type
+                            match c.ty.replace(' ', "").as_str() {
+                                "&str" | "&'staticstr" => format!("{}.as_bytes()", seed),
+                                ty if ty.starts_with('&') => seed,
+                                _ => format!("{}.as_ref()", seed),
+                            }
+                        } else {
+                            format!("{}.as_ref()", seed)
+                        }
+                    })
+                    .collect();
+
+                // If bump is explicitly provided, add it to seeds (with state field transformation)
+                let mut provided_bump = None;
+                if let Some(bump_var) = bump {
+                    let mut transformed_bump = bump_var.clone();
+                    // Transform state field references in bump
+                    for (state_acc, _) in &state_accounts_to_deserialize {
+                        transformed_bump = read_state_fields(&transformed_bump, state_acc);
+                    }
+                    seeds_code.push(format!("&[{}]", transformed_bump));
+                    provided_bump = Some(transformed_bump);
+                }
+
+                // Generate the PDA verification code
+                content.push_str(&format!("    // Verify PDA for {}\n", acc.name));
+
+                // Check if this PDA references its own state fields (self-referential)
+                // Check in ORIGINAL seeds OR bump for the pattern "accountname . "
+                let is_self_referential = seeds
+                    .iter()
+                    .any(|s| s.contains(&format!("{} . ", acc.name)))
+                    || bump
+                        .as_ref()
+                        .is_some_and(|b| b.contains(&format!("{} . ", acc.name)));
+
+                // Canonical bump (`bump` alone): use find_program_address. A provided bump is
+                // used as-is, even for init, since the client may have derived the address with
+                // a non-canonical bump. That includes one stored in the account's own state
+                // (`bump = vault.bump`), unless the account is being created and has no state yet.
+                // For find_program_address, don't include the bump in seeds (it's what we're finding)
+                // Remove the last seed if it contains a bump reference
+                let mut find_seeds = seeds_code.clone();
+                if let Some(last) = find_seeds.last() {
+                    // Remove if it's a bump seed (contains .bump or is a byte array reference)
+                    if last.contains(".bump")
+                        || (last.starts_with("&[") && !last.contains("b\""))
+                    {
+                        find_seeds.pop();
+                    }
+                }
+                // Accounts created here sign for the system program with these seeds and the bump
+                if acc.is_init || acc.is_init_if_needed {
+                    create_seeds.push((acc.name.clone(), find_seeds.clone()));
+                }
+
+                let creates = acc.is_init || acc.is_init_if_needed;
+                if bump.is_none() || (is_self_referential && creates) {
+                    // Find the bump (not provided, or only stored in state the account lacks yet)
+                    content.push_str(&format!(
+                        "    let (expected_{}, _bump_{}) = pinocchio::pubkey::find_program_address(\n",
+                        acc.name, acc.name
+                    ));
+                    content.push_str(&format!("        &[{}],\n", find_seeds.join(", ")));
+                    content.push_str("        program_id,\n");
+                    content.push_str("    );\n");
+                } else {
+                    // If bump is provided from another account's field, use create_program_address
+                    content.push_str(&format!(
+                        "    let expected_{} = pinocchio::pubkey::create_program_address(\n",
+                        acc.name
+                    ));
+                    content.push_str(&format!("        &[{}],\n", seeds_code.join(", ")));
+                    content.push_str("        program_id,\n");
+                    content.push_str("    )?;\n");
+                    // Signer seeds for creating the account must use the same bump
+                    if let (true, Some(provided)) = (creates, &provided_bump) {
+                        content.push_str(&format!(
+                            "    let _bump_{}: u8 = {};\n",
+                            acc.name, provided
+                        ));
+                    }
+                }
+                content.push_str(&format!(
+                    "    if {}.key() != &expected_{} {{\n",
+                    acc.name, acc.name
+                ));
+                content.push_str("        return Err(ProgramError::InvalidSeeds);\n");
+                content.push_str("    }\n");
+            }
+            Validation::Custom { code } => {
+                // Transform state field references in custom validation code
+                let mut transformed_code = code.clone();
+                for (state_acc, _) in &state_accounts_to_deserialize {
+                    let pattern = format!("{} . ", state_acc);
+                    transformed_code =
+                        transformed_code.replace(&pattern, &format!("{}_state.", state_acc));
+                }
+
+                // Transform token account field access: account.mint -> get_token_mint(account)?
+                for acc in &inst.accounts {
+                    // SPL token accounts by declared type (TokenAccount / InterfaceAccount) or
+                    // their token::mint / associated_token constraints, never by name
+                    let is_token_account =
+                        acc.is_token_account || acc.is_associated_token || acc.token_mint.is_some();
+
+                    if is_token_account {
+                        transformed_code = transformed_code.replace(
+                            &format!("{} . mint", acc.name),
+                            &format!("get_token_mint({})?", acc.name),
+                        );
+                        transformed_code = transformed_code.replace(
+                            &format!("{} . owner", acc.name),
+                            &format!("get_token_owner({})?", acc.name),
+                        );
+                    }
+                }
+
+                // Fix Pubkey comparisons: add dereference for .key() in comparisons
+                // Replace " == X.key ()" patterns
+                for acc in &inst.accounts {
+                    // Pattern: == account.key () -> == *account.key()
+                    transformed_code = transformed_code.replace(
+                        &format!(" == {} . key ()", acc.name),
+                        &format!(" == *{}.key()", acc.name),
+                    );
+                    transformed_code = transformed_code.replace(
+                        &format!(" != {} . key ()", acc.name),
+                        &format!(" != *{}.key()", acc.name),
+                    );
+                    transformed_code = transformed_code.replace(
+                        &format!("{} . key () == ", acc.name),
+                        &format!("*{}.key() == ", acc.name),
+                    );
+                    transformed_code = transformed_code.replace(
+                        &format!("{} . key () != ", acc.name),
+                        &format!("*{}.key() != ", acc.name),
+                    );
+                }
+
+                content.push_str(&format!("    {}\n", transformed_code));
+            }
+        }
+
+        // Checks on an optional account only run when it was passed
+        let optional_acc = match validation {
+            Validation::IsSigner { account_idx }
+            | Validation::IsWritable { account_idx }
+            | Validation::OwnerCheck { account_idx, .. }
+            | Validation::KeyEquals { account_idx, .. }
+            | Validation::PdaCheck { account_idx, .. } => {
+                Some(&inst.accounts[*account_idx]).filter(|acc| acc.is_optional)
+            }
+            _ => None,
+        };
+        if let Some(acc) = optional_acc {
+            let check = content.split_off(validation_start);
+            content.push_str(&format!("    if let Some({}) = {} {{\n", acc.name, acc.name));
+            for line in check.lines() {
+                content.push_str(&format!("    {}\n", line));
+            }
+            content.push_str("    }\n");
+        }
+    }
+
+    if has_validations {
+        content.push('\n');
+    }
+
+    // Validation guards are released once the checks are done, unless the handler goes on
+    // reading the state through them or an init account is seeded from it
+    let handler_text = if kept.is_empty() { inst.body.clone() } else { kept.concat() };
+    let seeded_from = |var: &str| {
+        create_seeds
+            .iter()
+            .any(|(_, seeds)| seeds.iter().any(|seed| mentions(seed, var)))
+    };
+    let mut held_guards = Vec::new();
+    if !state_accounts_to_deserialize.is_empty() {
+        let mut released = String::new();
+        for (acc_name, state_type) in &state_accounts_to_deserialize {
+            let guard = StateGuard::new(acc_name, state_type);
+            if !seeded_from(&guard.var)
+                && (declares(&handler_text, &guard.var) || !mentions(&handler_text, &guard.var))
+            {
+                released.push_str(&format!("    drop({});\n", guard.var));
+            } else {
+                held_guards.push(guard);
+            }
+        }
+        if !released.is_empty() {
+            content.push_str(&released);
+            content.push('\n');
+        }
+    }
+
+    // Parse remaining instruction arguments (skip those already parsed for validation)
+    let remaining_args: Vec<_> = inst
+        .args
+        .iter()
+        .filter(|arg| !args_used_in_validations.contains(&arg.name))
+        .collect();
+
+    if !remaining_args.is_empty() && !use_arg_struct {
+        content.push_str("    // Parse instruction arguments\n");
+
+        let mut offset = 0usize;
+        for arg in &inst.args {
+            let (size, parse_code) = get_arg_parse_code(&arg.ty, offset, &arg.name, program)
+                .with_context(|| format!("Instruction '{}'", inst.name))?;
+            // Only emit if not already parsed for validation
+            if !args_used_in_validations.contains(&arg.name) {
+                content.push_str(&indent_lines(&parse_code, "    "));
+            }
+            offset += size;
+        }
+        content.push('\n');
+    }
+
+    // Generate token account initialization code if needed
+    for acc in &inst.accounts {
+        if let (true, Some(mint_name), Some(authority_name)) = (
+            acc.is_init || acc.is_init_if_needed,
+            &acc.token_mint,
+            &acc.token_authority,
+        ) {
+            let default_payer = "authority".to_string();
+            let payer_name = acc.init_payer.as_ref().unwrap_or(&default_payer);
+
+            // The ATA program creates the account at its canonical address and checks
+            // the derivation; CreateIdempotent accepts one that already exists
+            if acc.is_associated_token {
+                content.push_str(&format!(
+                    "    // Create associated token account: {}\n",
+                    acc.name
+                ));
+                content.push_str(&format!(
+                    "    pinocchio_associated_token_account::instructions::{} {{\n        funding_account: {},\n        account: {},\n        wallet: {},\n        mint: {},\n        system_program: {},\n        token_program: {},\n    }}.invoke()?;\n\n",
+                    if acc.is_init_if_needed { "CreateIdempotent" } else { "Create" },
+                    payer_name,
+                    acc.name,
+                    authority_name,
+                    mint_name,
+                    acc.system_program.as_deref().unwrap_or("system_program"),
+                    acc.token_program.as_deref().unwrap_or("token_program")
+                ));
+                continue;
+            }
+
+            let init_start = content.len();
+            content.push_str(&format!("    // Initialize token account: {}\n", acc.name));
+
+            // Verify rent sysvar address
+            content.push_str("    // Verify Rent sysvar\n");
+            content.push_str("    const RENT_SYSVAR_ID: [u8; 32] = [\n");
+            content.push_str(
+                "        6, 167, 213, 23, 24, 199, 116, 201, 40, 86, 99, 152, 105, 29,\n",
+            );
+            content
+                .push_str("        94, 182, 139, 94, 184, 163, 155, 75, 109, 92, 115, 85, 91,\n");
+            content.push_str("        33, 0, 0, 0, 0,\n");
+            content.push_str("    ];\n");
+            content.push_str("    if *rent_sysvar.key() != RENT_SYSVAR_ID {\n");
+            content.push_str("        return Err(ProgramError::InvalidArgument);\n");
+            content.push_str("    }\n\n");
+
+            content.push_str(&emit_create_account(
+                acc,
+                "165", // SPL Token Account size
+                payer_name,
+                "pinocchio::sysvars::rent::Rent::get()?",
+                "&pinocchio_token::ID",
+                signer_seeds(&create_seeds, acc),
+                0,
+            ));
+            content.push('\n');
+
+            content.push_str(&format!(
+                "    pinocchio_token::instructions::InitializeAccount2 {{\n        account: {},\n        mint: {},\n        owner: {},\n        rent_sysvar: rent_sysvar,\n    }}.invoke()?;\n\n",
+                acc.name, mint_name, authority_name
+            ));
+
+            if acc.is_init_if_needed {
+                let init = content.split_off(init_start);
+                content.push_str(&wrap_init_if_needed(acc, &init, "&pinocchio_token::ID"));
+            }
+        }
+    }
+
+    // Create init and init_if_needed state accounts; realloc accounts are created below
+    for acc in &inst.accounts {
+        let is_program_state = program
+            .state_structs
+            .iter()
+            .any(|s| acc.state_type.as_ref() == Some(&s.name));
+        if let (true, true, None, None, Some(space)) = (
+            acc.is_init || acc.is_init_if_needed,
+            is_program_state,
+            &acc.token_mint,
+            &acc.realloc,
+            &acc.init_space,
+        ) {
+            let payer = acc.init_payer.as_deref().unwrap_or("authority");
+            let mut init = emit_create_account(
+                acc,
+                space,
+                payer,
+                "pinocchio::sysvars::rent::Rent::get()?",
+                "program_id",
+                signer_seeds(&create_seeds, acc),
+                program.config.disc_len,
+            );
+            init.push_str(&emit_write_discriminator(acc, program.config.disc_len));
+            if acc.is_init_if_needed {
+                content.push_str(&wrap_init_if_needed(acc, &init, "program_id"));
+            } else {
+                content.push_str(&init);
+                content.push('\n');
+            }
+        }
+    }
+
+    // Resize realloc accounts, creating init_if_needed ones first
+    if inst.accounts.iter().any(|acc| acc.realloc.is_some()) {
+        content.push_str("    let realloc_rent = pinocchio::sysvars::rent::Rent::get()?;\n\n");
+    }
+    for acc in &inst.accounts {
+        if let Some(realloc) = &acc.realloc {
+            // Resizing needs the data unborrowed; the handler's state is taken again after
+            let guard = held_guards.iter().find(|g| g.account == acc.name);
+            if let Some(guard) = guard {
+                content.push_str(&format!("    drop({});\n", guard.var));
+            }
+            content.push_str(&emit_realloc(
+                acc,
+                realloc,
+                signer_seeds(&create_seeds, acc),
+                program.config.disc_len,
+            ));
+            if let Some(guard) = guard {
+                content.push_str(&format!("    {}\n\n", guard.decl));
+            }
+        }
+    }
+
+    // `close = target` accounts are closed by one helper, called at every `Ok` exit
+    let closed: Vec<&str> = inst
+        .accounts
+        .iter()
+        .filter(|acc| acc.close.is_some())
+        .map(|acc| acc.name.as_str())
+        .collect();
+    let close_params: Vec<&str> = inst
+        .accounts
+        .iter()
+        .map(|acc| acc.name.as_str())
+        .filter(|name| {
+            closed.contains(name) || inst.accounts.iter().any(|acc| acc.close.as_deref() == Some(*name))
+        })
+        .collect();
+    let close_call = (!closed.is_empty()).then(|| format!("close_accounts({})", close_params.join(", ")));
+
+    // Add transformed body or placeholder
+    let body_ends_with_ok = kept.is_empty()
+        && (inst.body.trim().ends_with("Ok (())") || inst.body.trim().ends_with("Ok(())"));
+
+    if !kept.is_empty() {
+        // Filled in last so the arg struct rewrite can't touch user code
+        content.push_str(KEPT_PLACEHOLDER);
+    } else if !inst.body.is_empty() && inst.body != "{}" {
+        content.push_str("    // Transformed instruction logic\n");
+        // Add the transformed body (will have some TODO markers)
+        // First, fix any multi-line msg! macros
+        let mut fixed_body = fix_msg_macros(&inst.body);
+        // Fix .key() assignments that need dereferencing
+        fixed_body = fix_key_dereferencing(&fixed_body);
+        // Remove redundant self-assignments like "let escrow = & mut escrow ;"
+        fixed_body = remove_redundant_assignments(&fixed_body);
+        // Replace custom error enum names with Error::
+        fixed_body = fix_error_enum_names(&fixed_body, program);
+        let mut lines = Vec::new();
+        for line in fixed_body.lines() {
+            let trimmed = line.trim();
+            if !trimmed.is_empty() {
+                // Skip duplicate Ok(()) if body already has it
+                if body_ends_with_ok && (trimmed == "Ok (())" || trimmed == "Ok(())") {
+                    continue;
+                }
+                lines.push(trimmed.to_string());
+            }
+        }
+        (lines, held_guards) = release_state_guards(lines, held_guards)?;
+        if let Some(call) = &close_call {
+            (lines, held_guards) = close_on_early_returns(lines, held_guards, &closed, call);
+        }
+        for line in lines {
+            content.push_str(&format!("    {}\n", line));
+        }
+    } else {
+        content.push_str("    // TODO: Implement instruction logic\n");
+    }
+
+    // Closes run after the instruction logic, like Anchor's exit handling
+    if let Some(call) = &close_call {
+        for guard in held_guards.iter().filter(|g| closed.contains(&g.account.as_str())) {
+            content.push_str(&format!("    drop({});\n", guard.var));
+        }
+        content.push_str(&format!("    {}?;\n", call));
+    }
+
+    // Only add Ok(()) if body doesn't already have it
+    if !body_ends_with_ok {
+        content.push_str("\n    Ok(())\n");
+    } else {
+        content.push_str("    Ok(())\n");
+    }
+    content.push_str("}\n");
+
+    if close_call.is_some() {
+        content.push_str("\n/// Close the `close = ..` accounts; runs wherever the handler returns `Ok`\n");
+        content.push_str(&format!(
+            "fn close_accounts({}) -> ProgramResult {{\n",
+            close_params
+                .iter()
+                .map(|name| format!("{}: &AccountInfo", name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+        for acc in &inst.accounts {
+            if let Some(target) = &acc.close {
+                content.push_str(&emit_close(acc, target, program.config.disc_len));
+            }
+        }
+        content.push_str("    Ok(())\n");
+        content.push_str("}\n");
+    }
+
+    if use_arg_struct {
+        let arg_names: Vec<&str> = inst.args.iter().map(|a| a.name.as_str()).collect();
+        let handler_rest = content.split_off(args_start);
+        content.push_str(&rewrite_arg_refs(&handler_rest, &arg_names));
+    }
+
+    if program.config.field_accessors {
+        for acc in inst.accounts.iter().filter(|a| a.state_type.is_some()) {
+            content = use_field_accessors(&content, &format!("{}_state", acc.name));
+        }
+    }
+
+    if !kept.is_empty() {
+        content = content.replacen(KEPT_PLACEHOLDER, &kept.concat(), 1);
+    }
+
+    Ok(prune_unused(&content, &inst.name, helper_names))
+}
+
+/// `pub` items a generated module defines, by name
+fn public_item_names(code: &str) -> Vec<String> {
+    let item_re = Regex::new(r"(?m)^pub (?:const|static|fn|struct|enum|type) (\w+)").unwrap();
+    item_re.captures_iter(code).map(|caps| caps[1].to_string()).collect()
+}
+
+/// Drop the imports a handler doesn't use and prefix its unused parameters with `_`, so the
+/// handler builds without warnings
+fn prune_unused(content: &str, inst_name: &str, helper_names: &[String]) -> String {
+    // Everything but the imports and comments
+    let code = content
+        .lines()
+        .filter(|l| !l.starts_with("use ") && !l.trim_start().starts_with("//"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let error_re = Regex::new(r"\bError\s*::").unwrap();
+
+    let mut out = String::new();
+    for line in content.split_inclusive('\n') {
+        let import = line.trim_end().strip_prefix("use ").and_then(|l| l.strip_suffix(';'));
+        let keep = match import {
+            Some("crate::helpers::*") => helper_names.iter().any(|name| mentions(&code, name)),
+            Some("crate::error::Error") => error_re.is_match(&code),
+            Some(path) if path.starts_with("crate::state::") => {
+                mentions(&code, &path["crate::state::".len()..])
+            }
+            Some(path) if path.starts_with("pinocchio_token::instructions::{") => {
+                let names = path["pinocchio_token::instructions::{".len()..].trim_end_matches('}');
+                let used: Vec<&str> = names.split(", ").filter(|name| mentions(&code, name)).collect();
+                if !used.is_empty() {
+                    out.push_str(&format!("use pinocchio_token::instructions::{{{}}};\n", used.join(", ")));
+                }
+                false
+            }
+            _ => true,
+        };
+        if keep {
+            out.push_str(line);
+        }
+    }
+
+    // The parameters are only read by the statements after the signature
+    let signature = format!("pub fn {}(\n", inst_name);
+    if let (Some(start), Some(code_start)) = (out.find(&signature), code.find(&signature)) {
+        let body = code[code_start..].split_once(") -> ProgramResult {").map_or("", |(_, rest)| rest);
+        let params_end = out[start..].find(") -> ProgramResult {").map_or(out.len(), |i| start + i);
+        let mut params = out[start..params_end].to_string();
+        for param in ["program_id", "accounts", "data"] {
+            if !mentions(body, param) {
+                params = params.replace(&format!("    {}: ", param), &format!("    _{}: ", param));
+            }
+        }
+        out.replace_range(start..params_end, &params);
+    }
+    out
+}
+
+const KEPT_PLACEHOLDER: &str = "    // uncpi:kept\n";
+
+/// Regions of a previously generated handler wrapped in `// uncpi:keep` ... `// uncpi:end`,
+/// markers included. They replace the transformed instruction logic on the next run.
+fn kept_regions(existing: &str) -> Result<Vec<String>> {
+    let mut regions = Vec::new();
+    let mut current: Option<String> = None;
+
+    for line in existing.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("// uncpi:keep") {
+            if current.is_some() {
+                anyhow::bail!("nested `// uncpi:keep`");
+            }
+            current = Some(String::new());
+        }
+        if let Some(region) = current.as_mut() {
+            region.push_str(line);
+            region.push('\n');
+            if trimmed.starts_with("// uncpi:end") {
+                regions.extend(current.take());
+            }
+        } else if trimmed.starts_with("// uncpi:end") {
+            anyhow::bail!("`// uncpi:end` without a matching `// uncpi:keep`");
+        }
+    }
+
+    // Dropping an unterminated region would lose the user's code
+    if current.is_some() {
+        anyhow::bail!("`// uncpi:keep` without a matching `// uncpi:end`");
+    }
+    Ok(regions)
+}
+
+/// Generate `{Name}Args` with a `TryFrom<&[u8]>` impl using the same layout as inline parsing
+fn emit_args_struct(
+    inst: &PinocchioInstruction,
+    struct_name: &str,
+    program: &PinocchioProgram,
+) -> Result<String> {
+    let mut content = String::new();
+
+    content.push_str(&format!("/// Arguments for `{}`\n", inst.name));
+    content.push_str(&format!("pub struct {} {{\n", struct_name));
+    for arg in &inst.args {
+        content.push_str(&format!("    pub {}: {},\n", arg.name, arg_field_type(&arg.ty)));
+    }
+    content.push_str("}\n\n");
+
+    content.push_str(&format!("impl TryFrom<&[u8]> for {} {{\n", struct_name));
+    content.push_str("    type Error = ProgramError;\n\n");
+    content.push_str("    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {\n");
+    let mut offset = 0usize;
+    for arg in &inst.args {
+        let (size, parse_code) = get_arg_parse_code(&arg.ty, offset, &arg.name, program)
+            .with_context(|| format!("Instruction '{}'", inst.name))?;
+        content.push_str(&indent_lines(&parse_code, "        "));
+        offset += size;
+    }
+    let fields: Vec<String> = inst
+        .args
+        .iter()
+        .map(|arg| {
+            // Pubkeys are parsed as references into `data`
+            if arg.ty.replace(' ', "").to_lowercase() == "pubkey" {
+                format!("{}: *{}", arg.name, arg.name)
+            } else {
+                arg.name.clone()
+            }
+        })
+        .collect();
+    content.push_str(&format!("        Ok(Self {{ {} }})\n", fields.join(", ")));
+    content.push_str("    }\n");
+    content.push_str("}\n\n");
+
+    Ok(content)
+}
+
+fn arg_field_type(ty: &str) -> String {
+    match ty.replace(' ', "").as_str() {
+        "Pubkey" | "pubkey::Pubkey" => "[u8; 32]".to_string(),
+        _ => ty.to_string(),
+    }
+}
+
+/// Rewrite bare references to instruction args as `args.x` fields. Field accesses,
+/// struct field keys, strings and comments are left alone; struct shorthand `Foo { x }`
+/// becomes `Foo { x: args.x }`.
+fn rewrite_arg_refs(code: &str, arg_names: &[&str]) -> String {
+    let chars: Vec<char> = code.chars().collect();
+    let mut result = String::new();
+    // For each open brace: is it a struct literal (`Name {`)?
+    let mut braces: Vec<bool> = Vec::new();
+    let mut in_string = false;
+    let mut i = 0;
+
+    let prev_non_space = |s: &str| s.trim_end().chars().last();
+    let next_non_space = |from: usize| chars[from..].iter().copied().find(|c| !c.is_whitespace());
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if in_string {
+            result.push(c);
+            if c == '\\' && i + 1 < chars.len() {
+                result.push(chars[i + 1]);
+                i += 1;
+            } else if c == '"' {
+                in_string = false;
+            }
+            i += 1;
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '/' if chars.get(i + 1) == Some(&'/') => {
+                // Copy line comments through untouched
+                while i < chars.len() && chars[i] != '\n' {
+                    result.push(chars[i]);
+                    i += 1;
+                }
+                continue;
+            }
+            '{' => {
+                let before = result.trim_end();
+                let last_word: String = before
+                    .chars()
+                    .rev()
+                    .take_while(|c| c.is_alphanumeric() || *c == '_')
+                    .collect();
+                braces.push(last_word.chars().last().is_some_and(|c| c.is_uppercase()));
+            }
+            '}' => {
+                braces.pop();
+            }
+            _ => {}
+        }
+
+        let starts_ident = (c.is_alphabetic() || c == '_')
+            && !result.chars().last().is_some_and(|p| p.is_alphanumeric() || p == '_');
+        if !starts_ident {
+            result.push(c);
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+            i += 1;
+        }
+        let ident: String = chars[start..i].iter().collect();
+
+        if !arg_names.contains(&ident.as_str()) {
+            result.push_str(&ident);
+            continue;
+        }
+
+        let prev = prev_non_space(&result);
+        let next = next_non_space(i);
+        let is_field_access = prev == Some('.');
+        let after: String = chars[i..].iter().collect();
+        let is_path = result.trim_end().ends_with("::") || after.trim_start().starts_with("::");
+        let is_field_key = next == Some(':') && !is_path;
+        let in_struct_literal = braces.last().copied().unwrap_or(false);
+
+        if is_field_access || is_path || is_field_key || result.trim_end().ends_with("let") {
+            result.push_str(&ident);
+        } else if in_struct_literal
+            && matches!(prev, Some('{') | Some(','))
+            && matches!(next, Some(',') | Some('}'))
+        {
+            result.push_str(&format!("{}: args.{}", ident, ident));
+        } else {
+            result.push_str(&format!("args.{}", ident));
+        }
+    }
+
+    result
+}
+
+fn to_pascal_case(s: &str) -> String {
+    s.split('_')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// Index constant of an account, prefixed so it can't shadow one of the program's own
+/// constants (`address = ADMIN` on an account named `admin`)
+fn account_index_const(name: &str) -> String {
+    format!("ACCOUNT_{}", to_screaming_snake(name))
+}
+
+fn to_screaming_snake(s: &str) -> String {
+    let mut result = String::new();
+    for (i, c) in s.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            result.push('_');
+        }
+        result.push(c.to_uppercase().next().unwrap());
+    }
+    result
+}
+
+/// Borsh enums are a one-byte tag followed by the variant's fields. Offsets of later
+/// args are static, so every variant's payload must have the same size.
+fn get_enum_parse_code(
+    enum_def: &PinocchioEnumDef,
+    offset: usize,
+    name: &str,
+    program: &PinocchioProgram,
+) -> Result<(usize, String)> {
+    let mut payload_size = None;
+    let mut arms = Vec::new();
+    for (tag, variant) in enum_def.variants.iter().enumerate() {
+        let mut size = 0usize;
+        let mut lines = Vec::new();
+        let mut values = Vec::new();
+        for field in &variant.fields {
+            let local = format!("{}_{}", name, field.name);
+            let (field_size, field_code) =
+                get_arg_parse_code(&field.ty, offset + 1 + size, &local, program)?;
+            lines.push(field_code);
+            // Pubkeys are parsed as references into `data`
+            let value = if field.ty.replace(' ', "").to_lowercase() == "pubkey" {
+                format!("*{}", local)
+            } else {
+                local
+            };
+            values.push(if variant.is_tuple {
+                value
+            } else {
+                format!("{}: {}", field.name, value)
+            });
+            size += field_size;
+        }
+        if *payload_size.get_or_insert(size) != size {
+            anyhow::bail!(
+                "Arg '{}' of type {}: variant payloads differ in size, so later args have no fixed offset",
+                name,
+                enum_def.name
+            );
+        }
+
+        let constructor = if variant.fields.is_empty() {
+            format!("{}::{}", enum_def.name, variant.name)
+        } else if variant.is_tuple {
+            format!("{}::{}({})", enum_def.name, variant.name, values.join(", "))
+        } else {
+            format!("{}::{} {{ {} }}", enum_def.name, variant.name, values.join(", "))
+        };
+        if lines.is_empty() {
+            arms.push(format!("    {} => {},", tag, constructor));
+        } else {
+            arms.push(format!(
+                "    {} => {{\n{}        {}\n    }}",
+                tag,
+                indent_lines(&lines.join("\n"), "        "),
+                constructor
+            ));
+        }
+    }
+
+    let code = format!(
+        "let {} = match data.get({}).copied().ok_or(ProgramError::InvalidInstructionData)? {{\n{}\n    _ => return Err(ProgramError::InvalidInstructionData),\n}};",
+        name,
+        offset,
+        arms.join("\n")
+    );
+    Ok((1 + payload_size.unwrap_or(0), code))
+}
+
+fn indent_lines(code: &str, indent: &str) -> String {
+    code.lines().map(|line| format!("{}{}\n", indent, line)).collect()
+}
+
+/// Returns (size, parse_code) for a given type
+fn get_arg_parse_code(
+    ty: &str,
+    offset: usize,
+    name: &str,
+    program: &PinocchioProgram,
+) -> Result<(usize, String)> {
+    let ty_clean = ty.replace(" ", "").to_lowercase();
+
+    let parsed = match ty_clean.as_str() {
+        "u8" => (1, format!(
+            "let {} = data.get({}).copied().ok_or(ProgramError::InvalidInstructionData)?;",
+            name, offset
+        )),
+        "i8" => (1, format!(
+            "let {} = data.get({}).map(|&b| b as i8).ok_or(ProgramError::InvalidInstructionData)?;",
+            name, offset
+        )),
+        "u16" => (2, format!(
+            "let {} = u16::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
+            name, offset, offset + 2
+        )),
+        "i16" => (2, format!(
+            "let {} = i16::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
+            name, offset, offset + 2
+        )),
+        "u32" => (4, format!(
+            "let {} = u32::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
+            name, offset, offset + 4
+        )),
+        "i32" => (4, format!(
+            "let {} = i32::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
+            name, offset, offset + 4
+        )),
+        "u64" => (8, format!(
+            "let {} = u64::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
+            name, offset, offset + 8
+        )),
+        "i64" => (8, format!(
+            "let {} = i64::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
+            name, offset, offset + 8
+        )),
+        "u128" => (16, format!(
+            "let {} = u128::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
+            name, offset, offset + 16
+        )),
+        "i128" => (16, format!(
+            "let {} = i128::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
+            name, offset, offset + 16
+        )),
+        "bool" => (1, format!(
+            "let {} = data.get({}).copied().ok_or(ProgramError::InvalidInstructionData)? != 0;",
+            name, offset
+        )),
+        "pubkey" => (32, format!(
+            "let {}: &[u8; 32] = data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap();",
+            name, offset, offset + 32
+        )),
+        // Fixed-size byte arrays
+        "[u8;32]" => (32, format!(
+            "let {}: [u8; 32] = data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap();",
+            name, offset, offset + 32
+        )),
+        "[u8;64]" => (64, format!(
+            "let {}: [u8; 64] = data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap();",
+            name, offset, offset + 64
+        )),
+        _ => {
+            // Check for generic [u8; N] pattern
+            if ty_clean.starts_with("[u8;") && ty_clean.ends_with("]") {
+                if let Some(n_str) = ty_clean.strip_prefix("[u8;").and_then(|s| s.strip_suffix("]")) {
+                    if let Ok(n) = n_str.parse::<usize>() {
+                        return Ok((n, format!(
+                            "let {}: [u8; {}] = data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap();",
+                            name, n, offset, offset + n
+                        )));
+                    }
+                }
+            }
+            // Custom structs are Borsh-encoded field by field: parse each into
+            // `{name}_{field}`, then assemble the struct
+            if let Some(struct_def) = program.types.iter().find(|t| t.name == ty.replace(' ', "")) {
+                let mut size = 0usize;
+                let mut lines = Vec::new();
+                let mut inits = Vec::new();
+                for field in &struct_def.fields {
+                    let local = format!("{}_{}", name, field.name);
+                    let (field_size, field_code) =
+                        get_arg_parse_code(&field.ty, offset + size, &local, program)?;
+                    lines.push(field_code);
+                    // Pubkeys are parsed as references into `data`
+                    if field.ty.replace(' ', "").to_lowercase() == "pubkey" {
+                        inits.push(format!("{}: *{}", field.name, local));
+                    } else {
+                        inits.push(format!("{}: {}", field.name, local));
+                    }
+                    size += field_size;
+                }
+                lines.push(format!("let {} = {} {{ {} }};", name, struct_def.name, inits.join(", ")));
+                return Ok((size, lines.join("\n")));
+            }
+
+            if let Some(enum_def) = program.enums.iter().find(|e| e.name == ty.replace(' ', "")) {
+                return get_enum_parse_code(enum_def, offset, name, program);
+            }
+
+            anyhow::bail!("Arg '{}' has type {}, which can't be deserialized at a fixed offset", name, ty)
+        }
+    };
+    Ok(parsed)
+}
+
+/// Fix .key() calls that need dereferencing in assignments
+fn fix_key_dereferencing(body: &str) -> String {
+    let mut result = String::new();
+    
+    for line in body.lines() {
+        let mut new_line = line.to_string();
+        
+        // Pattern: something = account.key () ; (with spaces)
+        // Pattern: something = account.key() ; (no spaces)
+        // Also handle: account . key () (space before dot)
+        if line.contains(" = ") && line.contains("key ()")  {
+            // Check if not already dereferenced
+            if !line.contains(" = *") && !line.contains(" =*") {
+                // More precise: Only add * right before the identifier that has .key()
+                // Find the position of " = " and add * after it, unless the key is only
+                // used inside the value (e.g. `Instruction { program_id: x.key (), .. }`)
+                if let Some(eq_pos) = new_line.find(" = ") {
+                    let before = &new_line[..eq_pos + 3]; // Include " = "
+                    let after = &new_line[eq_pos + 3..];
+                    if after.trim_end_matches([';', ' ']).ends_with("key ()") {
+                        new_line = format!("{}*{}", before, after);
+                    }
+                }
+            }
+        }
+        
+        result.push_str(&new_line);
+        result.push('\n');
+    }
+    
+    // Clean up potential double dereferences
+    result = result.replace(" = **", " = *");
+    
+    result
+}
+
+/// Remove redundant self-assignments like "let x = &mut x;"
+fn remove_redundant_assignments(body: &str) -> String {
+    let mut result = String::new();
+    
+    for line in body.lines() {
+        let trimmed = line.trim();
+        
+        // Pattern: let varname = &mut varname ; or let varname = *& mut varname ;
+        // These are self-assignments that should be removed
+        let mut skip_line = false;
+        
+        if trimmed.starts_with("let ") && trimmed.contains(" = ") {
+            // Extract variable name
+            if let Some(eq_pos) = trimmed.find(" = ") {
+                let before_eq = &trimmed[4..eq_pos].trim(); // After "let "
+                let after_eq = &trimmed[eq_pos + 3..].trim();
+                
+                // Check if it's a self-assignment
+                // Pattern: "let x = & mut x ;" or "let x = *& mut x ;"
+                let self_ref_pattern1 = format!("& mut {} ;", before_eq);
+                let self_ref_pattern2 = format!("*& mut {} ;", before_eq);
+                let self_ref_pattern3 = format!("&mut {} ;", before_eq);
+                let self_ref_pattern4 = format!("*&mut {} ;", before_eq);
+                
+                if after_eq.contains(&self_ref_pattern1) || 
+                   after_eq.contains(&self_ref_pattern2) ||
+                   after_eq.contains(&self_ref_pattern3) ||
+                   after_eq.contains(&self_ref_pattern4) {
+                    skip_line = true;
+                }
+            }
+        }
+        
+        if !skip_line {
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+    
+    result
+}
+
+/// Replace the program's error enum name (e.g. ErrorCode, VotingError) with Error
+fn fix_error_enum_names(body: &str, program: &PinocchioProgram) -> String {
+    let Some(name) = program.error_enum.as_deref().filter(|name| *name != "Error") else {
+        return body.to_string();
+    };
+    let re = Regex::new(&format!(r"\b{}(\s*::)", regex::escape(name))).unwrap();
+    re.replace_all(body, "Error$1").into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transformer::Config;
+    use crate::{analyzer, parser, transformer};
+    use tempfile::TempDir;
+
+    /// Run the full pipeline and return the emitted source of one instruction
+    fn emit_instruction_source(source: &str, instruction: &str) -> String {
+        let program = transform_source(source, &Config::default());
+        let extras = parser::parse_extras_source(source).unwrap();
+        let mut generated = emit_with_extras(&program, None, Some(&extras)).unwrap();
+        generated
+            .files
+            .remove(&Path::new("src/instructions").join(format!("{}.rs", instruction)))
+            .unwrap()
+    }
+
+    /// Parse, analyze and transform `source`
+    fn transform_source(source: &str, config: &Config) -> PinocchioProgram {
+        let anchor = parser::parse_anchor_source(source).unwrap();
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        transformer::transform(&anchor, &analysis, config).unwrap()
+    }
+
+    /// Emit a program into memory, with no handlers on disk to keep regions from
+    fn generate(program: &PinocchioProgram) -> GeneratedCrate {
+        emit_with_extras(program, None, None).unwrap()
+    }
+
+    #[test]
+    fn test_accountless_instruction_keeps_args_and_body() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod calc {
+                use super::*;
+                pub fn square(_ctx: Context<Compute>, value: u64) -> Result<()> {
+                    let result = value * value;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Compute {}
+        "#;
+        let code = emit_instruction_source(source, "square");
+
+        assert!(!code.contains("accounts.len()"));
+        assert!(code.contains("let value = u64::from_le_bytes(data.get(0..8)"));
+        assert!(code.contains("let result = value * value"));
+    }
+
+    #[test]
+    fn test_optional_account_presence_checks() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod referrals {
+                use super::*;
+                pub fn trade(ctx: Context<Trade>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Trade<'info> {
+                #[account(mut)]
+                pub user: Signer<'info>,
+                #[account(mut, constraint = referrer.key() != user.key())]
+                pub referrer: Option<SystemAccount<'info>>,
+                pub cosigner: Option<Signer<'info>>,
+            }
+        "#;
+        let code = emit_instruction_source(source, "trade");
+
+        assert!(code.contains(
+            "referrer: if accounts[ACCOUNT_REFERRER].key() == program_id { None } else { Some(&accounts[ACCOUNT_REFERRER]) },"
+        ));
+        assert!(code.contains("pub referrer: Option<&'a AccountInfo>,"));
+        assert!(code.contains("user: &accounts[ACCOUNT_USER],"));
+        assert!(code.contains("    if let Some(referrer) = referrer {\n        if !referrer.is_writable() {"));
+        assert!(code.contains("    if let Some(cosigner) = cosigner {\n        if !cosigner.is_signer() {"));
+        assert!(code.contains("if let Some(referrer) = referrer {\n        if !(*referrer.key() != *user.key())"));
+        // Required accounts are still checked unconditionally
+        assert!(code.contains("    if !user.is_signer() {"));
+    }
+    const SEED_SOURCE: &str = r#"
+        use anchor_lang::prelude::*;
+
+        pub const VAULT_SEED: SEED_TYPE = SEED_VALUE;
+
+        #[program]
+        pub mod vaults {
+            use super::*;
+            pub fn open(ctx: Context<Open>) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        #[derive(Accounts)]
+        pub struct Open<'info> {
+            #[account(seeds = [VAULT_SEED, owner.key().as_ref()], bump)]
+            pub vault: SystemAccount<'info>,
+            pub owner: Signer<'info>,
+        }
+    "#;
+
+    #[test]
+    fn test_slice_const_seed_used_as_is() {
+        let source = SEED_SOURCE
+            .replace("SEED_TYPE", "&[u8]")
+            .replace("SEED_VALUE", "b\"vault\"");
+        let code = emit_instruction_source(&source, "open");
+
+        assert!(code.contains("&[VAULT_SEED, owner"));
+    }
+
+    #[test]
+    fn test_array_const_seed_as_ref() {
+        let source = SEED_SOURCE
+            .replace("SEED_TYPE", "[u8; 5]")
+            .replace("SEED_VALUE", "*b\"vault\"");
+        let code = emit_instruction_source(&source, "open");
+
+        assert!(code.contains("&[VAULT_SEED.as_ref(), owner"));
+    }
+    #[test]
+    fn test_init_if_needed_runs_before_realloc() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod notes {
+                use super::*;
+                pub fn write(ctx: Context<Write>, len: u32) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            #[instruction(len: u32)]
+            pub struct Write<'info> {
+                #[account(
+                    init_if_needed,
+                    payer = author,
+                    space = 8 + 4,
+                    realloc = 8 + 4 + len as usize,
+                    realloc::payer = author,
+                    realloc::zero = false,
+                )]
+                pub note: Account<'info, Note>,
+                #[account(mut)]
+                pub author: Signer<'info>,
+                pub system_program: Program<'info, System>,
+            }
+
+            #[account]
+            pub struct Note {
+                pub len: u32,
+            }
+        "#;
+        let code = emit_instruction_source(source, "write");
+
+        let rent = code.find("let realloc_rent = pinocchio::sysvars::rent::Rent::get()?;").unwrap();
+        let create = code.find("if note.data_is_empty() {").unwrap();
+        let resize = code.find("note.realloc(note_new_len, false)?;").unwrap();
+        assert!(rent < create && create < resize);
+        assert_eq!(code.matches("Rent::get()").count(), 1);
+
+        assert!(code.contains("let init_len: usize = 8 + 4;"));
+        assert!(code.contains("let note_new_len: usize = 8 + 4 + len as usize;"));
+        assert!(code.contains("pinocchio_system::instructions::Transfer {\n            from: author,\n            to: note,\n            lamports: top_up,\n        }\n        .invoke()?;"));
+        // The author is system-owned, so only the system program may debit it
+        assert!(!code.contains("*author.try_borrow_mut_lamports()? -="));
+        // Arguments used in the size must be parsed before the resize
+        assert!(code.find("let len = ").unwrap() < resize);
+    }
+    #[test]
+    fn test_init_space_defined_where_referenced() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod profiles {
+                use super::*;
+                pub fn create(ctx: Context<Create>) -> Result<()> {
+                    Ok(())
+                }
+
+                pub fn resize(ctx: Context<Resize>, extra: u32) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Create<'info> {
+                #[account(init, payer = owner, space = 8 + Config::INIT_SPACE)]
+                pub config: Account<'info, Config>,
+                #[account(mut)]
+                pub owner: Signer<'info>,
+                pub system_program: Program<'info, System>,
+            }
+
+            #[derive(Accounts)]
+            #[instruction(extra: u32)]
+            pub struct Resize<'info> {
+                #[account(
+                    mut,
+                    realloc = 8 + Profile::INIT_SPACE + extra as usize,
+                    realloc::payer = owner,
+                    realloc::zero = false,
+                )]
+                pub profile: Account<'info, Profile>,
+                #[account(mut)]
+                pub owner: Signer<'info>,
+                pub system_program: Program<'info, System>,
+            }
+
+            #[account]
+            #[derive(InitSpace)]
+            pub struct Config {
+                pub admin: Pubkey,
+                pub fee: u64,
+            }
+
+            #[account]
+            #[derive(InitSpace)]
+            pub struct Profile {
+                #[max_len(32)]
+                pub name: String,
+                #[max_len(4)]
+                pub scores: Vec<u16>,
+            }
+
+            #[account]
+            pub struct Unsized {
+                pub value: u64,
+            }
+        "#;
+        let program = transform_source(source, &Config::default());
+        let generated = generate(&program);
+
+        let state = &generated.files[Path::new("src/state.rs")];
+        syn::parse_file(state).unwrap();
+        // Borsh sizes at the #[max_len] bounds, without the discriminator
+        assert!(state.contains("pub const INIT_SPACE: usize = 40;"), "{}", state);
+        assert!(state.contains("pub const INIT_SPACE: usize = 48;"), "{}", state);
+        assert_eq!(state.matches("INIT_SPACE").count(), 2, "{}", state);
+
+        // Every `Type::INIT_SPACE` the handlers use is defined on that type
+        let referenced = Regex::new(r"\b(\w+)\s*::\s*INIT_SPACE\b").unwrap();
+        let mut types = Vec::new();
+        for code in generated.files.values() {
+            types.extend(referenced.captures_iter(code).map(|caps| caps[1].to_string()));
+        }
+        types.sort();
+        types.dedup();
+        assert_eq!(types, ["Config", "Profile"]);
+        let resize = &generated.files[Path::new("src/instructions/resize.rs")];
+        let compact = resize.replace(char::is_whitespace, "");
+        assert!(compact.contains("letprofile_new_len:usize=8+Profile::INIT_SPACE+extraasusize;"), "{}", resize);
+        for ty in types {
+            let start = state.find(&format!("impl {} {{", ty)).unwrap();
+            let end = start + state[start..].find("\n}\n").unwrap();
+            assert!(state[start..end].contains("pub const INIT_SPACE: usize ="), "{}", ty);
+        }
+    }
+
+    #[test]
+    fn test_init_marker_account_writes_discriminator() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod markers {
+                use super::*;
+                pub fn mark(ctx: Context<Mark>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Mark<'info> {
+                #[account(init, payer = payer, space = 8)]
+                pub marker: Account<'info, Marker>,
+                #[account(init, payer = payer, space = 0)]
+                pub flag: Account<'info, Flag>,
+                #[account(mut)]
+                pub payer: Signer<'info>,
+                pub system_program: Program<'info, System>,
+            }
+
+            #[account]
+            pub struct Marker {}
+
+            #[account]
+            pub struct Flag {}
+        "#;
+        let code = emit_instruction_source(source, "mark");
+
+        assert!(code.contains("use crate::state::Marker;"));
+        let create = code.find("// Create marker\n    let init_len: usize = 8;").unwrap();
+        let fresh = code.find("if marker.lamports() == 0 {").unwrap();
+        let cpi = code
+            .find("pinocchio_system::instructions::CreateAccount {\n            from: payer,\n            to: marker,\n            lamports: init_lamports,\n            space: init_len as u64,\n            owner: program_id,\n        }\n        .invoke()?;")
+            .unwrap();
+        let assign = code.find("pinocchio_system::instructions::Assign {\n            account: marker,").unwrap();
+        let disc = code
+            .find("marker.try_borrow_mut_data()?[..8].copy_from_slice(&Marker::DISCRIMINATOR);")
+            .unwrap();
+        assert!(create < fresh && fresh < cpi && cpi < assign && assign < disc);
+        assert!(!code.contains("*payer.try_borrow_mut_lamports()?"));
+        // space = 0 still leaves room for the discriminator
+        assert!(code.contains("// Create flag\n    let init_len: usize = 8;"));
+        assert!(code.contains("flag.try_borrow_mut_data()?[..8].copy_from_slice(&Flag::DISCRIMINATOR);"));
+        syn::parse_file(&code).unwrap();
+    }
+
+    #[test]
+    fn test_init_requires_system_program_account() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod notes {
+                use super::*;
+                pub fn open(ctx: Context<Open>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Open<'info> {
+                #[account(init, payer = author, space = 8 + 32)]
+                pub note: Account<'info, Note>,
+                #[account(mut)]
+                pub author: Signer<'info>,
+            }
+
+            #[account]
+            pub struct Note {
+                pub author: Pubkey,
+            }
+        "#;
+        let code = emit_instruction_source(source, "open");
+
+        // The Accounts struct has no system program, but creating the note needs it
+        assert!(code.contains("const ACCOUNT_SYSTEM_PROGRAM: usize = 2;\nconst ACCOUNTS_LEN: usize = 3;"));
+        assert!(code.contains("let OpenAccounts { note, author, system_program } = OpenAccounts::parse(program_id, accounts)?;"));
+        assert!(code.contains("if *system_program.key() != pinocchio_system::ID {"));
+        // A keypair account signs the transaction itself, so the CPI is unsigned
+        assert!(code.contains("            owner: program_id,\n        }\n        .invoke()?;"));
+        assert!(!code.contains("invoke_signed"));
+        syn::parse_file(&code).unwrap();
+    }
+
+    #[test]
+    fn test_init_if_needed_skips_existing_accounts() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod counters {
+                use super::*;
+                pub fn bump(ctx: Context<Bump>) -> Result<()> {
+                    ctx.accounts.counter.count += 1;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Bump<'info> {
+                #[account(init_if_needed, payer = user, space = 8 + 8, seeds = [b"counter"], bump)]
+                pub counter: Account<'info, Counter>,
+                #[account(
+                    init_if_needed,
+                    payer = user,
+                    token::mint = mint,
+                    token::authority = user,
+                    seeds = [b"vault"],
+                    bump,
+                )]
+                pub vault: Account<'info, TokenAccount>,
+                pub mint: Account<'info, Mint>,
+                #[account(mut)]
+                pub user: Signer<'info>,
+                pub system_program: Program<'info, System>,
+                pub token_program: Program<'info, Token>,
+            }
+
+            #[account]
+            pub struct Counter {
+                pub count: u64,
+            }
+        "#;
+        let code = emit_instruction_source(source, "bump");
+
+        // Token account: the whole InitializeAccount2 path only runs when empty
+        let vault_check = code.find("if vault.data_is_empty() {").unwrap();
+        let vault_init = code.find("InitializeAccount2 {").unwrap();
+        let vault_owner = code
+            .find("} else if !vault.is_owned_by(&pinocchio_token::ID) {")
+            .unwrap();
+        assert!(vault_check < vault_init && vault_init < vault_owner);
+
+        // State account: created when empty, otherwise must already be ours
+        let counter_check = code.find("if counter.data_is_empty() {").unwrap();
+        let counter_create = code.find("to: counter,").unwrap();
+        let counter_owner = code.find("} else if !counter.is_owned_by(program_id) {").unwrap();
+        let logic = code.find("counter_state.count += 1").unwrap();
+        assert!(counter_check < counter_create && counter_create < counter_owner);
+        assert!(counter_owner < logic);
+        assert!(code.contains("let counter_seeds = pinocchio::seeds!(b\"counter\".as_ref(), &[_bump_counter]);"));
+        assert!(code.contains(".invoke_signed(&[pinocchio::instruction::Signer::from(&counter_seeds)])?;"));
+        // Both accounts are funded by a system CPI, never by editing the user's lamports
+        assert!(code.contains("from: user,"));
+        assert!(!code.contains("*user.try_borrow_mut_lamports()?"));
+        assert!(code.contains("Counter::from_account_info_mut(counter)?"));
+        syn::parse_file(&code).unwrap();
+    }
+    #[test]
+    fn test_borrowed_seeds_keep_their_sigil() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vaults {
+                use super::*;
+                pub fn touch(ctx: Context<Touch>, id: u64) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            #[instruction(id: u64)]
+            pub struct Touch<'info> {
+                #[account(
+                    seeds = [b"vault", &user.key().to_bytes(), &id.to_le_bytes(), &user.key().as_ref(), &[7u8]],
+                    bump,
+                )]
+                pub vault: Account<'info, Vault>,
+                pub user: Signer<'info>,
+            }
+
+            #[account]
+            pub struct Vault {
+                pub amount: u64,
+            }
+        "#;
+        let code = emit_instruction_source(source, "touch");
+
+        assert!(code.contains(
+            "&[b\"vault\".as_ref(), user.key().as_ref(), & id . to_le_bytes (), \
+             user . key () . as_ref (), & [7u8]],"
+        ), "{}", code);
+        assert!(!code.contains("to_bytes ()"));
+        syn::parse_file(&code).unwrap();
+    }
+    #[test]
+    fn test_delegate_and_freeze_cpis() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod escrow {
+                use super::*;
+                pub fn lock(ctx: Context<Lock>, amount: u64) -> Result<()> {
+                    token::approve(
+                        CpiContext::new(
+                            ctx.accounts.token_program.to_account_info(),
+                            Approve {
+                                to: ctx.accounts.vault.to_account_info(),
+                                delegate: ctx.accounts.operator.to_account_info(),
+                                authority: ctx.accounts.owner.to_account_info(),
+                            },
+                        ),
+                        amount,
+                    )?;
+                    token::revoke(CpiContext::new(
+                        ctx.accounts.token_program.to_account_info(),
+                        Revoke {
+                            source: ctx.accounts.vault.to_account_info(),
+                            authority: ctx.accounts.owner.to_account_info(),
+                        },
+                    ))?;
+                    token::freeze_account(CpiContext::new(
+                        ctx.accounts.token_program.to_account_info(),
+                        FreezeAccount {
+                            account: ctx.accounts.vault.to_account_info(),
+                            mint: ctx.accounts.mint.to_account_info(),
+                            authority: ctx.accounts.owner.to_account_info(),
+                        },
+                    ))?;
+                    token::thaw_account(CpiContext::new(
+                        ctx.accounts.token_program.to_account_info(),
+                        ThawAccount {
+                            account: ctx.accounts.vault.to_account_info(),
+                            mint: ctx.accounts.mint.to_account_info(),
+                            authority: ctx.accounts.owner.to_account_info(),
+                        },
+                    ))?;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Lock<'info> {
+                #[account(mut)]
+                pub vault: Account<'info, TokenAccount>,
+                pub mint: Account<'info, Mint>,
+                pub operator: SystemAccount<'info>,
+                pub owner: Signer<'info>,
+                pub token_program: Program<'info, Token>,
+            }
+        "#;
+        let code = emit_instruction_source(source, "lock");
+        let compact = code.replace(char::is_whitespace, "");
+
+        assert!(code.contains(
+            "use pinocchio_token::instructions::{Approve, Revoke, FreezeAccount, ThawAccount};"
+        ), "{}", code);
+        for anchor_cpi in ["approve", "revoke", "freeze_account", "thaw_account"] {
+            assert!(!code.contains(&format!("token::{}", anchor_cpi)));
+        }
+        assert!(compact.contains(
+            "Approve{source:vault,delegate:operator,authority:owner,amount:amount,}.invoke()?;"
+        ));
+        assert!(compact.contains("Revoke{source:vault,authority:owner,}.invoke()?;"));
+        assert!(compact.contains(
+            "FreezeAccount{account:vault,mint:mint,freeze_authority:owner,}.invoke()?;"
+        ));
+        assert!(compact.contains(
+            "ThawAccount{account:vault,mint:mint,freeze_authority:owner,}.invoke()?;"
+        ));
+        syn::parse_file(&code).unwrap();
+    }
+    #[test]
+    fn test_seed_key_stays_on_account_info() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod pools {
+                use super::*;
+                pub fn stake(ctx: Context<Stake>) -> Result<()> {
+                    ctx.accounts.position.amount += ctx.accounts.pool.rate;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Stake<'info> {
+                pub pool: Account<'info, Pool>,
+                #[account(
+                    mut,
+                    seeds = [b"position", pool.key().as_ref(), user.key().as_ref(), &[pool.tier]],
+                    bump = position.bump,
+                )]
+                pub position: Account<'info, Position>,
+                pub user: Signer<'info>,
+            }
+
+            #[account]
+            pub struct Pool {
+                pub rate: u64,
+                pub tier: u8,
+            }
+
+            #[account]
+            pub struct Position {
+                pub amount: u64,
+                pub bump: u8,
+            }
+        "#;
+        let code = emit_instruction_source(source, "stake");
+
+        // pool is deserialized for `tier`, but its key still comes from the AccountInfo
+        assert!(code.contains("let pool_state = Pool::from_account_info(pool)?;"));
+        assert!(code.contains(
+            "&[b\"position\".as_ref(), pool . key () . as_ref (), user . key () . as_ref (), & [pool_state.tier], &[position_state.bump]],"
+        ));
+        assert!(!code.contains("pool_state.key"));
+        assert!(!code.contains("pool_state . key"));
+        syn::parse_file(&code).unwrap();
+    }
+    #[test]
+    fn test_realloc_shrink_refunds_payer() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod notes {
+                use super::*;
+                pub fn trim(ctx: Context<Trim>, len: u32) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            #[instruction(len: u32)]
+            pub struct Trim<'info> {
+                #[account(mut, realloc = 8 + len as usize, realloc::payer = author, realloc::zero = false)]
+                pub note: Account<'info, Note>,
+                #[account(mut)]
+                pub author: Signer<'info>,
+                pub system_program: Program<'info, System>,
+            }
+
+            #[account]
+            pub struct Note {
+                pub len: u32,
+            }
+        "#;
+        let code = emit_instruction_source(source, "trim");
+
+        let shrink = code
+            .find("} else if note.lamports() > note_rent_required {")
+            .unwrap();
+        let from_account = code.find("*note.try_borrow_mut_lamports()? -= refund;").unwrap();
+        let to_payer = code.find("*author.try_borrow_mut_lamports()? += refund;").unwrap();
+        let resize = code.find("note.realloc(note_new_len, false)?;").unwrap();
+        assert!(shrink < from_account && from_account < to_payer && to_payer < resize);
+        syn::parse_file(&code).unwrap();
+    }
+    #[test]
+    fn test_account_named_data_is_renamed() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod store {
+                use super::*;
+                pub fn put(ctx: Context<Put>, value: u64) -> Result<()> {
+                    let owner = ctx.accounts.data.owner;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Put<'info> {
+                #[account(mut, constraint = data.owner == authority.key())]
+                pub data: Account<'info, Record>,
+                pub authority: Signer<'info>,
+            }
+
+            #[account]
+            pub struct Record {
+                pub owner: Pubkey,
+            }
+        "#;
+        let code = emit_instruction_source(source, "put");
+
+        assert!(code.contains("const ACCOUNT_DATA_ACC: usize = 0;"));
+        assert!(code.contains("data_acc: &accounts[ACCOUNT_DATA_ACC],"));
+        assert!(code.contains("if !data_acc.is_writable()"));
+        assert!(code.contains("data_acc_state.owner"));
+        assert!(!code.contains("let data = "));
+        // The instruction data parameter is untouched
+        assert!(code.contains("data.get(0..8)"));
+    }
+
+    #[test]
+    fn test_arg_structs_mode() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod events {
+                use super::*;
+                pub fn ping(ctx: Context<Ping>, nonce: u64, target: Pubkey) -> Result<()> {
+                    require!(nonce > 0, PingError::Zero);
+                    ctx.accounts.counter.pings = nonce;
+                    ctx.accounts.counter.last = Record { nonce, target: target };
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Ping<'info> {
+                #[account(mut)]
+                pub counter: Account<'info, Counter>,
+            }
+
+            #[account]
+            pub struct Counter {
+                pub pings: u64,
+            }
+        "#;
+        let config = Config {
+            arg_structs: true,
+            ..Default::default()
+        };
+        let program = transform_source(source, &config);
+        let generated = generate(&program);
+        let code = &generated.files[Path::new("src/instructions/ping.rs")];
+
+        assert!(code.contains("pub struct PingArgs {\n    pub nonce: u64,\n    pub target: [u8; 32],\n}"));
+        assert!(code.contains("impl TryFrom<&[u8]> for PingArgs {"));
+        assert!(code.contains("Ok(Self { nonce, target: *target })"));
+        assert!(code.contains("let args = PingArgs::try_from(data)?;"));
+        assert!(code.contains("if !(args.nonce> 0)"));
+        assert!(code.contains("counter_state.pings = args.nonce"));
+        assert!(code.contains("Record { nonce: args.nonce, target : args.target }"));
+        // No inline parsing in the handler itself
+        assert!(!code.contains("    // Parse instruction arguments"));
+        syn::parse_file(code).unwrap();
+    }
+    #[test]
+    fn test_has_one_compares_deserialized_field() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vault {
+                use super::*;
+                pub fn withdraw(ctx: Context<Withdraw>) -> Result<()> {
+                    Ok(())
+                }
+                pub fn close(ctx: Context<Close>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Withdraw<'info> {
+                #[account(mut, has_one = authority @ VaultError::Unauthorized)]
+                pub vault: Account<'info, Vault>,
+                pub authority: Signer<'info>,
+            }
+
+            #[derive(Accounts)]
+            pub struct Close<'info> {
+                #[account(mut, has_one = authority)]
+                pub vault: Account<'info, Vault>,
+                pub authority: Signer<'info>,
+            }
+
+            #[account]
+            pub struct Vault {
+                pub authority: Pubkey,
+            }
+
+            #[error_code]
+            pub enum VaultError {
+                Unauthorized,
+            }
+        "#;
+        let code = emit_instruction_source(source, "withdraw");
+
+        assert!(code.contains("use crate::state::Vault;"));
+        let deserialize = code.find("let vault_state = Vault::from_account_info(vault)?;").unwrap();
+        let check = code.find("if vault_state.authority != *authority.key() {").unwrap();
+        assert!(deserialize < check);
+        assert!(code.contains("return Err(Error::Unauthorized.into());"));
+        syn::parse_file(&code).unwrap();
+
+        // Without an explicit error, Anchor's ConstraintHasOne code is used
+        let code = emit_instruction_source(source, "close");
+        assert!(code.contains("if vault_state.authority != *authority.key() {\n        return Err(ProgramError::InvalidAccountData);"));
+    }
+    #[test]
+    fn test_error_code_enum_emitted_as_error() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vaults {
+                use super::*;
+                pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
+                    let vault = &mut ctx.accounts.vault;
+                    vault.total = vault.total.checked_sub(amount).ok_or(ErrorCode::Overflow)?;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Withdraw<'info> {
+                #[account(mut, has_one = authority @ ErrorCode::Unauthorized)]
+                pub vault: Account<'info, Vault>,
+                #[account(constraint = authority.key() != vault.key() @ crate::ErrorCode::Overflow)]
+                pub authority: Signer<'info>,
+            }
+
+            #[account]
+            pub struct Vault {
+                pub authority: Pubkey,
+                pub total: u64,
+            }
+
+            #[error_code]
+            pub enum ErrorCode {
+                Unauthorized,
+                Overflow,
+            }
+        "#;
+        let code = emit_instruction_source(source, "withdraw");
+
+        // Anchor's default enum name is whatever the parser found, not only *Error
+        assert!(code.contains("return Err(Error::Unauthorized.into());"), "{}", code);
+        assert!(code.contains("return Err(Error::Overflow.into());"));
+        assert!(code.contains("ok_or (Error::Overflow)"), "{}", code);
+        assert!(!code.contains("ErrorCode"));
+        syn::parse_file(&code).unwrap();
+    }
+    #[test]
+    fn test_cold_instructions_marked() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod pool {
+                use super::*;
+                pub fn deposit(ctx: Context<Update>, amount: u64) -> Result<()> {
+                    ctx.accounts.pool.total += amount;
+                    Ok(())
+                }
+                pub fn set_admin(ctx: Context<Update>, admin: Pubkey) -> Result<()> {
+                    ctx.accounts.pool.admin = admin;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Update<'info> {
+                #[account(mut)]
+                pub pool: Account<'info, Pool>,
+            }
+
+            #[account]
+            pub struct Pool {
+                pub admin: Pubkey,
+                pub total: u64,
+            }
+        "#;
+        let config = Config {
+            cold_instructions: vec!["set_admin".to_string()],
+            ..Default::default()
+        };
+        let program = transform_source(source, &config);
+        let generated = generate(&program);
+
+        let cold = &generated.files[Path::new("src/instructions/set_admin.rs")];
+        assert!(cold.contains("#[inline(never)]\n#[cold]\npub fn set_admin("));
+        syn::parse_file(cold).unwrap();
+
+        let hot = &generated.files[Path::new("src/instructions/deposit.rs")];
+        assert!(!hot.contains("#[cold]"));
+    }
+    #[test]
+    fn test_check_program_id_guards_dispatch() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            declare_id!("11111111111111111111111111111111");
+
+            #[program]
+            pub mod pinged {
+                use super::*;
+                pub fn ping(ctx: Context<Ping>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Ping<'info> {
+                pub user: Signer<'info>,
+            }
+        "#;
+        let anchor = parser::parse_anchor_source(source).unwrap();
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        let emit_lib = |check_program_id| {
+            let config = Config {
+                check_program_id,
+                ..Default::default()
+            };
+            let program = transformer::transform(&anchor, &analysis, &config).unwrap();
+            generate(&program).files.remove(Path::new("src/lib.rs")).unwrap()
+        };
+
+        let checked = emit_lib(true);
+        assert!(checked.contains(
+            ") -> ProgramResult {\n    if program_id != &ID {\n        return Err(ProgramError::IncorrectProgramId);\n    }"
+        ));
+        syn::parse_file(&checked).unwrap();
+
+        assert!(!emit_lib(false).contains("IncorrectProgramId"));
+    }
+    #[test]
+    fn test_lazy_entrypoint_reads_context() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod pinged {
+                use super::*;
+                pub fn ping(ctx: Context<Ping>) -> Result<()> {
+                    Ok(())
+                }
+                pub fn pong(ctx: Context<Pong>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Ping<'info> {
+                pub user: Signer<'info>,
+            }
+
+            #[derive(Accounts)]
+            pub struct Pong<'info> {
+                pub user: Signer<'info>,
+                pub other: Signer<'info>,
+            }
+        "#;
+        let anchor = parser::parse_anchor_source(source).unwrap();
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        let emit_lib = |lazy_entrypoint| {
+            let config = Config {
+                lazy_entrypoint,
+                ..Default::default()
+            };
+            let program = transformer::transform(&anchor, &analysis, &config).unwrap();
+            generate(&program).files.remove(Path::new("src/lib.rs")).unwrap()
+        };
+
+        let lazy = emit_lib(true);
+        assert!(lazy.contains("lazy_program_entrypoint!(process_instruction);"));
+        assert!(!lazy.contains("use pinocchio::entrypoint;"));
+        assert!(lazy.contains("const MAX_ACCOUNTS: usize = 2;"));
+        // Accounts past MAX_ACCOUNTS are consumed and skipped, not rejected
+        assert!(lazy.contains(
+            "while context.remaining() > 0 {\n        if count == MAX_ACCOUNTS {\n            context.next_account()?;\n            continue;\n        }"
+        ), "{}", lazy);
+        assert!(!lazy.contains("InvalidArgument"), "{}", lazy);
+        assert!(lazy.contains(
+            "pub fn process_instruction(\n    mut context: pinocchio::entrypoint::InstructionContext,\n) -> ProgramResult {"
+        ));
+        assert!(lazy.contains("dispatch(program_id, accounts, instruction_data)"));
+        assert!(lazy.contains("PING_DISC => instructions::ping(program_id, accounts, data),"));
+        syn::parse_file(&lazy).unwrap();
+
+        let eager = emit_lib(false);
+        assert!(eager.contains("\nentrypoint!(process_instruction);"));
+        assert!(!eager.contains("InstructionContext"));
+    }
+
+    #[test]
+    fn test_emitted_tests_for_lazy_accountless_program() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod beacon {
+                use super::*;
+                pub fn ping(ctx: Context<Ping>, nonce: u64) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Ping {}
+        "#;
+        let anchor = parser::parse_anchor_source(source).unwrap();
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        let config = Config {
+            lazy_entrypoint: true,
+            anchor_compat: false,
+            ..Default::default()
+        };
+        let program = transformer::transform(&anchor, &analysis, &config).unwrap();
+        let generated = emit_tests(&program);
+        let tests = &generated.files[Path::new("tests/instructions.rs")];
+
+        // No declare_id!, and the lazy entrypoint's dispatch takes the account slice
+        assert!(tests.contains("const PROGRAM_ID: Pubkey = [0u8; 32];"));
+        assert!(tests.contains("beacon::dispatch(&PROGRAM_ID, &[], instruction_data)"));
+        // Without accounts to check, dispatch must return the handler's own result
+        assert!(tests.contains(
+            "fn ping_is_dispatched() {\n    let mut data = vec![0x00];\n    data.resize(data.len() + 1024, 0);\n    let expected = beacon::instructions::ping(&PROGRAM_ID, &[], &data[1..]);\n    assert_eq!(call(&data), expected);\n}"
+        ), "{}", tests);
+        assert!(!tests.contains("assert_ne!"));
+        assert!(tests.contains("assert_eq!(call(&[0x01]), Err(ProgramError::InvalidInstructionData));"));
+        syn::parse_file(tests).unwrap();
+    }
+
+    #[test]
+    fn test_constraint_args_parsed_before_validation() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vaults {
+                use super::*;
+                pub fn deposit(ctx: Context<Deposit>, memo: u8, amount: u64, fee: u64) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            #[instruction(memo: u8, amount: u64, fee: u64)]
+            pub struct Deposit<'info> {
+                #[account(mut, constraint = amount > 0 @ VaultError::ZeroAmount)]
+                pub user: Signer<'info>,
+                #[account(constraint = config.fee < 100)]
+                pub config: Account<'info, Config>,
+            }
+
+            #[account]
+            pub struct Config {
+                pub fee: u64,
+            }
+
+            #[error_code]
+            pub enum VaultError {
+                #[msg("zero")]
+                ZeroAmount,
+            }
+        "#;
+        let code = emit_instruction_source(source, "deposit");
+
+        let parse = code
+            .find("let amount = u64::from_le_bytes(data.get(1..9)")
+            .unwrap();
+        let check = code.find("amount > 0").unwrap();
+        assert!(parse < check, "{}", code);
+        assert_eq!(code.matches("let amount =").count(), 1);
+        // Args the constraints don't use are still parsed after validation
+        let memo = code.find("let memo =").unwrap();
+        assert!(memo > check, "{}", code);
+        // A state field named like an arg doesn't pull the arg forward
+        let fee_check = code.find("config_state.fee < 100").expect(&code);
+        let fee = code.find("let fee =").unwrap();
+        assert!(fee > fee_check, "{}", code);
+    }
+
+    #[test]
+    fn test_accounts_struct_names_each_account() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod pinged {
+                use super::*;
+                pub fn ping_pong(ctx: Context<PingPong>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct PingPong<'info> {
+                #[account(mut)]
+                pub payer: Signer<'info>,
+                pub target: SystemAccount<'info>,
+                pub witness: Option<Signer<'info>>,
+            }
+        "#;
+        let code = emit_instruction_source(source, "ping_pong");
+        let file = syn::parse_file(&code).unwrap();
+
+        let accounts = file
+            .items
+            .iter()
+            .find_map(|item| match item {
+                syn::Item::Struct(s) if s.ident == "PingPongAccounts" => Some(s),
+                _ => None,
+            })
+            .unwrap();
+        let fields: Vec<String> = accounts
+            .fields
+            .iter()
+            .map(|f| f.ident.as_ref().unwrap().to_string())
+            .collect();
+        assert_eq!(fields, ["payer", "target", "witness"]);
+
+        assert!(code.contains("const ACCOUNTS_LEN: usize = 3;"));
+        assert!(code.contains("        if accounts.len() < ACCOUNTS_LEN {"));
+        assert!(code.contains(
+            "    let PingPongAccounts { payer, target, witness } = PingPongAccounts::parse(program_id, accounts)?;"
+        ));
+        // Indexing only happens inside parse
+        let handler = code.find("pub fn ping_pong(").unwrap();
+        assert!(!code[handler..].contains("accounts["));
+    }
+
+    #[test]
+    fn test_field_accessors_read_at_offsets() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vault {
+                use super::*;
+                pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
+                    let vault = &mut ctx.accounts.vault;
+                    vault.total += amount;
+                    vault.deposits = vault.deposits + 1;
+                    vault.active = true;
+                    vault.tags[1] = 7;
+                    vault.tags[2] |= 1;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Deposit<'info> {
+                #[account(mut, has_one = authority)]
+                pub vault: Account<'info, Vault>,
+                pub authority: Signer<'info>,
+            }
+
+            #[account]
+            pub struct Vault {
+                pub authority: Pubkey,
+                pub total: u64,
+                pub deposits: u32,
+                pub active: bool,
+                pub tags: [u8; 4],
+            }
+        "#;
+        let config = Config {
+            field_accessors: true,
+            ..Default::default()
+        };
+        let program = transform_source(source, &config);
+        let generated = generate(&program);
+
+        let state = &generated.files[Path::new("src/state.rs")];
+        syn::parse_file(state).unwrap();
+        assert!(!state.contains("#[repr(C)]"));
+        // Views keep the data borrowed, so a conflicting borrow fails
+        assert!(!state.contains("_unchecked"));
+        assert!(state.contains("let data = info.try_borrow_mut_data()?;"));
+        assert!(state.contains("Ok(VaultMut { data })"));
+        assert!(state.contains("pub struct VaultMut<'a> {\n    data: RefMut<'a, [u8]>,\n}"));
+        assert!(state.contains("pub fn set_tags_at(&mut self, index: usize, value: u8) {\n        self.data[53..57][index] = value;"), "{}", state);
+        assert!(!state.contains("as *const Self"));
+        assert!(state.contains("pub fn from_account_info(info: &AccountInfo) -> Result<VaultRef<'_>, ProgramError> {"));
+        assert!(state.contains("pub fn total(&self) -> u64 {\n        u64::from_le_bytes(self.data[40..48].try_into().unwrap())"));
+        assert!(state.contains("pub fn set_deposits(&mut self, value: u32) {\n        self.data[48..52].copy_from_slice(&value.to_le_bytes());"));
+        assert!(state.contains("pub fn active(&self) -> bool {\n        self.data[52] != 0"));
+        assert!(state.contains("pub fn set_authority(&mut self, value: [u8; 32]) {\n        self.data[8..40].copy_from_slice(&value);"));
+
+        let code = &generated.files[Path::new("src/instructions/deposit.rs")];
+        assert!(code.contains("if vault_state.authority() != *authority.key() {"));
+        assert!(code.contains("vault_state.set_total(vault_state.total() + amount);"));
+        assert!(code.contains("vault_state.set_deposits(vault_state.deposits() + 1);"));
+        assert!(code.contains("vault_state.set_active(true);"));
+        assert!(code.contains("vault_state.set_tags_at(1, 7);"), "{}", code);
+        assert!(code.contains("vault_state.set_tags_at(2, vault_state.tags()[2] | (1));"), "{}", code);
+    }
+
+    #[test]
+    fn test_field_accessors_release_validation_guards() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+            use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+
+            #[program]
+            pub mod fees {
+                use super::*;
+                pub fn set_fee(ctx: Context<SetFee>, fee: u64) -> Result<()> {
+                    let config = &mut ctx.accounts.config;
+                    config.fee = fee;
+                    Ok(())
+                }
+
+                pub fn pay(ctx: Context<Pay>, amount: u64) -> Result<()> {
+                    let config = &mut ctx.accounts.config;
+                    config.fee = amount;
+                    token::transfer(
+                        CpiContext::new(
+                            ctx.accounts.token_program.to_account_info(),
+                            Transfer {
+                                from: ctx.accounts.payer_tokens.to_account_info(),
+                                to: ctx.accounts.fee_vault.to_account_info(),
+                                authority: ctx.accounts.config.to_account_info(),
+                            },
+                        ),
+                        amount,
+                    )?;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct SetFee<'info> {
+                #[account(mut, has_one = authority)]
+                pub config: Account<'info, Config>,
+                pub authority: Signer<'info>,
+            }
+
+            #[derive(Accounts)]
+            pub struct Pay<'info> {
+                #[account(mut, has_one = authority)]
+                pub config: Account<'info, Config>,
+                pub authority: Signer<'info>,
+                #[account(mut)]
+                pub payer_tokens: Account<'info, TokenAccount>,
+                #[account(mut)]
+                pub fee_vault: Account<'info, TokenAccount>,
+                pub token_program: Program<'info, Token>,
+            }
+
+            #[account]
+            pub struct Config {
+                pub authority: Pubkey,
+                pub fee: u64,
+            }
+        "#;
+        let config = Config {
+            field_accessors: true,
+            ..Default::default()
+        };
+        let program = transform_source(source, &config);
+        let generated = generate(&program);
+
+        // The view taken for has_one holds the data borrow until it is dropped
+        let code = &generated.files[Path::new("src/instructions/set_fee.rs")];
+        syn::parse_file(code).unwrap();
+        let dropped = code.find("drop(config_state);").expect(code);
+        let borrowed = code.find("Config::from_account_info_mut(config)?").expect(code);
+        assert!(dropped < borrowed, "{}", code);
+        assert!(code.contains("config_state.set_fee(fee);"), "{}", code);
+
+        // The state written before a CPI is released before the CPI borrows the account
+        let code = &generated.files[Path::new("src/instructions/pay.rs")];
+        syn::parse_file(code).unwrap();
+        let written = code.find("config_state.set_fee(amount);").expect(code);
+        let dropped = written + code[written..].find("drop(config_state);").expect(code);
+        let invoked = code.find(".invoke()").expect(code);
+        assert!(dropped < invoked, "{}", code);
+    }
+
+    #[test]
+    fn test_field_accessors_after_variable_length_field() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod registry {
+                use super::*;
+                pub fn bump(ctx: Context<Bump>) -> Result<()> {
+                    ctx.accounts.registry.count += 1;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Bump<'info> {
+                #[account(mut)]
+                pub registry: Account<'info, Registry>,
+            }
+
+            #[account]
+            pub struct Registry {
+                pub authority: Pubkey,
+                pub entries: Vec<u64>,
+                pub count: u64,
+                pub label: Option<u32>,
+                pub active: bool,
+            }
+        "#;
+        let config = Config {
+            field_accessors: true,
+            ..Default::default()
+        };
+        let program = transform_source(source, &config);
+        let offsets: Vec<_> = program.state_structs[0].fields.iter().map(|f| f.offset).collect();
+        assert_eq!(offsets, [Some(8), Some(40), None, None, None]);
+
+        let generated = generate(&program);
+
+        let state = &generated.files[Path::new("src/state.rs")];
+        syn::parse_file(state).unwrap();
+        // Fields up to the vec keep their constant offsets
+        assert!(state.contains("pub fn set_authority(&mut self, value: [u8; 32]) {\n        self.data[8..40].copy_from_slice(&value);"));
+        // The field after it walks past the vec's length prefix at runtime
+        let vec_step = "cursor += 4 + 8 * u32::from_le_bytes(self.data[cursor..cursor + 4].try_into().unwrap()) as usize;";
+        assert!(state.contains(&format!(
+            "pub fn count(&self) -> u64 {{\n        let mut cursor = 40;\n        {}\n        u64::from_le_bytes(self.data[cursor..cursor + 8].try_into().unwrap())",
+            vec_step
+        )));
+        assert!(state.contains(&format!(
+            "pub fn active(&self) -> bool {{\n        let mut cursor = 40;\n        {}\n        cursor += 8;\n        cursor += if self.data[cursor] == 0 {{ 1 }} else {{ 5 }};\n        self.data[cursor] != 0",
+            vec_step
+        )));
+        assert!(!state.contains("self.data[48..56]"));
+    }
+
+    #[test]
+    fn test_field_accessors_after_bounded_string() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod profiles {
+                use super::*;
+                pub fn bump(ctx: Context<Bump>) -> Result<()> {
+                    ctx.accounts.profile.count += 1;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Bump<'info> {
+                #[account(mut)]
+                pub profile: Account<'info, Profile>,
+            }
+
+            #[account]
+            pub struct Profile {
+                #[max_len(16)]
+                pub name: String,
+                pub count: u64,
+            }
+        "#;
+        let config = Config {
+            field_accessors: true,
+            ..Default::default()
+        };
+        let program = transform_source(source, &config);
+        let offsets: Vec<_> = program.state_structs[0].fields.iter().map(|f| f.offset).collect();
+        assert_eq!(offsets, [Some(8), None]);
+
+        let generated = generate(&program);
+
+        // Borsh writes the name's 4-byte length and only its contents
+        let state = &generated.files[Path::new("src/state.rs")];
+        syn::parse_file(state).unwrap();
+        assert!(state.contains(
+            "pub fn count(&self) -> u64 {\n        let mut cursor = 8;\n        cursor += 4 + u32::from_le_bytes(self.data[cursor..cursor + 4].try_into().unwrap()) as usize;\n        u64::from_le_bytes(self.data[cursor..cursor + 8].try_into().unwrap())"
+        ), "{}", state);
+        assert!(!state.contains("pub fn name("));
+        assert!(!state.contains("self.data[24..32]"));
+    }
+
+    #[test]
+    fn test_vec_state_fields_become_bounded_arrays() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod registry {
+                use super::*;
+                pub fn add(ctx: Context<Add>, who: Pubkey) -> Result<()> {
+                    ctx.accounts.registry.members.push(who);
+                    ctx.accounts.registry.scores.push(5);
+                    Ok(())
+                }
+                pub fn reset(ctx: Context<Add>) -> Result<()> {
+                    ctx.accounts.registry.members.clear();
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Add<'info> {
+                #[account(mut)]
+                pub registry: Account<'info, Registry>,
+            }
+
+            #[account]
+            pub struct Registry {
+                #[max_len(10)]
+                pub members: Vec<Pubkey>,
+                pub scores: Vec<u64>,
+            }
+        "#;
+        let program = transform_source(source, &Config::default());
+        let vec_info = program.state_structs[0].fields[0].vec_info.as_ref().unwrap();
+        assert_eq!((vec_info.element_type.as_str(), vec_info.max_len), ("Pubkey", Some(10)));
+
+        let generated = generate(&program);
+
+        let state = &generated.files[Path::new("src/state.rs")];
+        syn::parse_file(state).unwrap();
+        assert!(state.contains("pub members: [[u8; 32];  10],\n    pub members_len: u8,"));
+        assert!(state.contains("pub scores: [u64;  32],\n    pub scores_len: u8,"));
+        assert!(state.contains("pub const SIZE: usize = core::mem::size_of::<Self>();"));
+        assert!(state.contains("pub fn push_members(&mut self, item: [u8; 32])"));
+        assert!(!state.contains("Vec<"));
+
+        let add = &generated.files[Path::new("src/instructions/add.rs")];
+        assert!(add.contains("registry_state.members[registry_state.members_len as usize] = *who; registry_state.members_len += 1;"));
+        assert!(add.contains("registry_state.scores[registry_state.scores_len as usize] = 5;"));
+        let reset = &generated.files[Path::new("src/instructions/reset.rs")];
+        assert!(reset.contains("registry_state.members_len = 0"), "{}", reset);
+    }
+
+    #[test]
+    fn test_unchecked_account_keeps_check_note() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod payouts {
+                use super::*;
+                pub fn pay(ctx: Context<Pay>) -> Result<()> {
+                    Ok(())
                 }
-                let acc = &inst.accounts[*account_idx];
-                content.push_str(&format!(
-                    "    if !{}.is_writable() {{\n        return Err(ProgramError::Immutable);\n    }}\n",
-                    acc.name
-                ));
             }
-            Validation::PdaCheck {
-                account_idx,
-                seeds,
-                bump,
-            } => {
-                if !has_validations {
-                    content.push_str("    // Validate accounts\n");
-                    has_validations = true;
+
+            #[derive(Accounts)]
+            pub struct Pay<'info> {
+                /// CHECK: only receives lamports,
+                /// so any system account will do
+                #[account(mut)]
+                pub recipient: UncheckedAccount<'info>,
+                pub payer: Signer<'info>,
+            }
+        "#;
+        let program = transform_source(source, &Config::default());
+        let generated = generate(&program);
+
+        let pay = &generated.files[Path::new("src/instructions/pay.rs")];
+        assert!(pay.contains(
+            "    // CHECK: only receives lamports, so any system account will do\n    pub recipient: &'a AccountInfo,\n    pub payer: &'a AccountInfo,"
+        ), "{}", pay);
+        let migration = &generated.files[Path::new("MIGRATION.md")];
+        assert!(migration.contains("- [ ] `pay` / `recipient`: CHECK: only receives lamports, so any system account will do\n"));
+
+        // Nothing to note, no file
+        let source = source.replace("/// CHECK: only receives lamports,\n                /// so any system account will do\n", "");
+        let program = transform_source(&source, &Config::default());
+        let generated = generate(&program);
+        assert!(!generated.files.contains_key(Path::new("MIGRATION.md")));
+    }
+
+    #[test]
+    fn test_borsh_skip_field_left_out_of_layout() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod cache {
+                use super::*;
+                pub fn touch(ctx: Context<Touch>) -> Result<()> {
+                    ctx.accounts.entry.b += 1;
+                    Ok(())
                 }
-                let acc = &inst.accounts[*account_idx];
-                // Generate actual PDA validation code
-                let mut seeds_code: Vec<String> = seeds
-                    .iter()
-                    .map(|s| {
-                        let mut seed = s.clone();
+            }
 
-                        // Transform state field references: "pool . bump" -> "pool_state.bump"
-                        // BUT: Don't transform "pool . key ()" since .key() is an AccountInfo method
-                        for (state_acc, _) in &state_accounts_to_deserialize {
-                            let pattern = format!("{} . ", state_acc);
-                            if seed.contains(&pattern) && !seed.contains(&format!("{} . key", state_acc)) {
-                                seed = seed.replace(&pattern, &format!("{}_state.", state_acc));
-                            }
-                        }
+            #[derive(Accounts)]
+            pub struct Touch<'info> {
+                #[account(mut)]
+                pub entry: Account<'info, Entry>,
+            }
 
-                        if seed.starts_with("b\"") {
-                            format!("{}.as_ref()", seed)
-                        } else if seed.contains(".key()") {
-                            let acc_name = seed
-                                .replace(".key()", "")
-                                .replace(".as_ref()", "")
-                                .replace(" ", "");
-                            format!("{}.key().as_ref()", acc_name)
-                        } else if seed.contains("as_ref") {
-                            seed
-                        } else {
-                            format!("{}.as_ref()", seed)
-                        }
-                    })
-                    .collect();
+            #[account]
+            pub struct Entry {
+                pub a: u64,
+                #[borsh(skip)]
+                pub cache: u64,
+                pub b: u64,
+            }
+        "#;
+        let anchor = parser::parse_anchor_source(source).unwrap();
+        assert!(anchor.state_structs[0].fields[1].borsh_skip);
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        let config = Config {
+            field_accessors: true,
+            ..Default::default()
+        };
+        let program = transformer::transform(&anchor, &analysis, &config).unwrap();
+        let offsets: Vec<_> = program.state_structs[0].fields.iter().map(|f| f.offset).collect();
+        assert_eq!(offsets, [Some(8), None, Some(16)]);
+
+        let generated = generate(&program);
+
+        let state = &generated.files[Path::new("src/state.rs")];
+        syn::parse_file(state).unwrap();
+        assert!(state.contains("u64::from_le_bytes(self.data[16..24].try_into().unwrap())"));
+        assert!(!state.contains("pub fn cache("));
+        assert!(!state.contains("pub fn set_cache("));
+        assert!(state.contains("// cache is #[borsh(skip)]: not stored in the account data"));
+
+        let idl = crate::idl::generate_idl(&program, None);
+        let fields: Vec<_> = idl.accounts[0].ty.fields.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(fields, ["a", "b"]);
+
+        // The cast layout leaves it out of the struct and its size as well
+        let program = transformer::transform(&anchor, &analysis, &Config::default()).unwrap();
+        let generated = generate(&program);
+        let state = &generated.files[Path::new("src/state.rs")];
+        assert!(state.contains("pub struct Entry {\n    pub a: u64,\n    pub b: u64,\n}"), "{}", state);
+        assert!(state.contains("pub const SIZE: usize = 24;"), "{}", state);
+    }
 
-                // If bump is explicitly provided, add it to seeds (with state field transformation)
-                if let Some(bump_var) = bump {
-                    let mut transformed_bump = bump_var.clone();
-                    // Transform state field references in bump
-                    for (state_acc, _) in &state_accounts_to_deserialize {
-                        let pattern = format!("{} . ", state_acc);
-                        if transformed_bump.contains(&pattern) {
-                            transformed_bump = transformed_bump
-                                .replace(&pattern, &format!("{}_state.", state_acc));
-                        }
-                    }
-                    seeds_code.push(format!("&[{}]", transformed_bump));
+    #[test]
+    fn test_account_loader_loads_zero_copy_state() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod pools {
+                use super::*;
+                pub fn add_liquidity(ctx: Context<AddLiquidity>, amount: u64) -> Result<()> {
+                    let mut pool = ctx.accounts.pool.load_mut()?;
+                    pool.liquidity += amount;
+                    let config = ctx.accounts.config.load()?;
+                    require!(config.enabled == 1, PoolError::Disabled);
+                    Ok(())
                 }
+            }
 
-                // Generate the PDA verification code
-                content.push_str(&format!("    // Verify PDA for {}\n", acc.name));
+            #[derive(Accounts)]
+            pub struct AddLiquidity<'info> {
+                #[account(mut)]
+                pub pool: AccountLoader<'info, PoolState>,
+                pub config: AccountLoader<'info, PoolConfig>,
+            }
 
-                // Check if this PDA references its own state fields (self-referential)
-                // Check in ORIGINAL seeds OR bump for the pattern "accountname . "
-                let is_self_referential = seeds
-                    .iter()
-                    .any(|s| s.contains(&format!("{} . ", acc.name)))
-                    || bump
-                        .as_ref()
-                        .is_some_and(|b| b.contains(&format!("{} . ", acc.name)));
+            #[account(zero_copy)]
+            pub struct PoolState {
+                pub liquidity: u64,
+            }
 
-                // If account is being initialized, self-referential, or bump not provided, use find_program_address
-                if acc.is_init || bump.is_none() || is_self_referential {
-                    // For find_program_address, don't include the bump in seeds (it's what we're finding)
-                    // Remove the last seed if it contains a bump reference
-                    let mut find_seeds = seeds_code.clone();
-                    if let Some(last) = find_seeds.last() {
-                        // Remove if it's a bump seed (contains .bump or is a byte array reference)
-                        if last.contains(".bump")
-                            || (last.starts_with("&[") && !last.contains("b\""))
-                        {
-                            find_seeds.pop();
-                        }
-                    }
+            #[account(zero_copy)]
+            pub struct PoolConfig {
+                pub enabled: u8,
+            }
 
-                    // Find the bump (needed for init, self-reference, or when bump not provided)
-                    content.push_str(&format!(
-                        "    let (expected_{}, _bump_{}) = pinocchio::pubkey::find_program_address(\n",
-                        acc.name, acc.name
-                    ));
-                    content.push_str(&format!("        &[{}],\n", find_seeds.join(", ")));
-                    content.push_str("        program_id,\n");
-                    content.push_str("    );\n");
-                } else {
-                    // If bump is provided from another account's field, use create_program_address
-                    content.push_str(&format!(
-                        "    let expected_{} = pinocchio::pubkey::create_program_address(\n",
-                        acc.name
-                    ));
-                    content.push_str(&format!("        &[{}],\n", seeds_code.join(", ")));
-                    content.push_str("        program_id,\n");
-                    content.push_str("    )?;\n");
+            #[error_code]
+            pub enum PoolError {
+                Disabled,
+            }
+        "#;
+        let program = transform_source(source, &Config::default());
+        let generated = generate(&program);
+
+        let code = &generated.files[Path::new("src/instructions/add_liquidity.rs")];
+        assert!(code.contains("let mut pool = unsafe { PoolState::load_mut(pool)? } ;"), "{}", code);
+        assert!(code.contains("pool.liquidity += amount ;"));
+        assert!(code.contains("let config = unsafe { PoolConfig::load(config)? } ;"));
+        assert!(!code.contains(".load"));
+        assert!(code.contains("use crate::state::PoolState;"));
+
+        let state = &generated.files[Path::new("src/state.rs")];
+        syn::parse_file(state).unwrap();
+        assert!(state.contains("/// # Safety\n    /// This function performs unsafe pointer casts."));
+        assert!(state.contains(
+            "pub unsafe fn load_mut(info: &AccountInfo) -> Result<RefMut<'_, Self>, ProgramError> {\n        Self::from_account_info_mut(info)"
+        ));
+        assert_eq!(state.matches("pub unsafe fn load(").count(), 2);
+    }
+
+    #[test]
+    fn test_bs58_decode_known_addresses() {
+        let hex = |s: &str| -> String {
+            bs58_decode(s).unwrap().iter().map(|b| format!("{:02x}", b)).collect()
+        };
+
+        assert_eq!(
+            hex("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA"),
+            "06ddf6e1d765a193d9cbe146ceeb79ac1cb485ed5f5b37913a8cf5857eff00a9"
+        );
+        assert_eq!(
+            hex("ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL"),
+            "8c97258f4e2489f1bb3d1029148e0d830b5a1399daff1084048e7bd8dbe9f859"
+        );
+        // Leading '1's are leading zero bytes
+        assert_eq!(
+            hex("1nc1nerator11111111111111111111111111111111"),
+            "003390728d34116079bdc911bfff00dbd44d2ecdccf79ca6e10038e100000000"
+        );
+        assert_eq!(hex("11111111111111111111111111111111"), "00".repeat(32));
+        assert_eq!(hex("11111111111111111111111111111112"), format!("{}01", "00".repeat(31)));
+
+        assert!(bs58_decode("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5D0").is_err());
+        assert!(bs58_decode("Tokenkeg").is_err());
+    }
+
+    #[test]
+    fn test_instructions_module_is_public() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod pinged {
+                use super::*;
+                pub fn ping(ctx: Context<Ping>) -> Result<()> {
+                    Ok(())
                 }
-                content.push_str(&format!(
-                    "    if {}.key() != &expected_{} {{\n",
-                    acc.name, acc.name
-                ));
-                content.push_str("        return Err(ProgramError::InvalidSeeds);\n");
-                content.push_str("    }\n");
             }
-            Validation::Custom { code } => {
-                if !has_validations {
-                    content.push_str("    // Validate accounts\n");
-                    has_validations = true;
+
+            #[derive(Accounts)]
+            pub struct Ping<'info> {
+                pub user: Signer<'info>,
+            }
+        "#;
+        let program = transform_source(source, &Config::default());
+        let generated = generate(&program);
+
+        let lib = &generated.files[Path::new("src/lib.rs")];
+        let lib = syn::parse_file(lib).unwrap();
+        let instructions_mod = lib.items.iter().find_map(|item| match item {
+            syn::Item::Mod(m) if m.ident == "instructions" => Some(m),
+            _ => None,
+        });
+        assert!(matches!(instructions_mod.unwrap().vis, syn::Visibility::Public(_)));
+
+        // The handler itself is re-exported, so `pinged::instructions::ping` resolves
+        let mod_rs = &generated.files[Path::new("src/instructions/mod.rs")];
+        assert!(mod_rs.contains("pub use ping::ping;"));
+    }
+
+    #[test]
+    fn test_emit_logs_event_data() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vaults {
+                use super::*;
+                pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
+                    emit!(Deposited {
+                        user: ctx.accounts.user.key(),
+                        amount,
+                        closed: false,
+                    });
+                    Ok(())
                 }
-                // Transform state field references in custom validation code
-                let mut transformed_code = code.clone();
-                for (state_acc, _) in &state_accounts_to_deserialize {
-                    let pattern = format!("{} . ", state_acc);
-                    transformed_code =
-                        transformed_code.replace(&pattern, &format!("{}_state.", state_acc));
+            }
+
+            #[derive(Accounts)]
+            pub struct Deposit<'info> {
+                pub user: Signer<'info>,
+            }
+
+            #[event]
+            pub struct Deposited {
+                pub user: Pubkey,
+                pub amount: u64,
+                pub closed: bool,
+            }
+
+        "#;
+        let program = transform_source(source, &Config::default());
+        let generated = generate(&program);
+
+        let lib = &generated.files[Path::new("src/lib.rs")];
+        assert!(lib.contains("pub mod events;"));
+        // sha256("event:Deposited")[0..8]
+        assert!(lib.contains(
+            "pub const DEPOSITED_EVENT_DISC: [u8; 8] = [0x6f, 0x8d, 0x1a, 0x2d, 0xa1, 0x23, 0x64, 0x39];"
+        ));
+
+        let events = &generated.files[Path::new("src/events.rs")];
+        for line in [
+            "let mut data = [0u8; 49];",
+            "data[..8].copy_from_slice(&crate::DEPOSITED_EVENT_DISC);",
+            "data[8..40].copy_from_slice(&self.user);",
+            "data[40..48].copy_from_slice(&self.amount.to_le_bytes());",
+            "data[48] = self.closed as u8;",
+            "pinocchio::log::sol_log_data(&[&data]);",
+        ] {
+            assert!(events.contains(line), "{}: {}", line, events);
+        }
+        syn::parse_file(events).unwrap();
+
+        let code = &generated.files[Path::new("src/instructions/deposit.rs")];
+        let compact = code.replace(char::is_whitespace, "");
+        assert!(
+            compact.contains("crate::events::Deposited{user:*user.key(),amount,closed:false}.emit();"),
+            "{}",
+            code
+        );
+        assert!(!code.contains("TODO: Emit event"));
+        syn::parse_file(code).unwrap();
+    }
+
+    #[test]
+    fn test_variable_size_event_fields_are_rejected() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod names {
+                use super::*;
+                pub fn rename(ctx: Context<Rename>) -> Result<()> {
+                    Ok(())
                 }
+            }
 
-                // Transform token account field access: account.mint -> get_token_mint(account)?
-                for acc in &inst.accounts {
-                    // Check if this looks like a token account (commonly named with token types)
-                    let is_token_account = acc.name.contains("user_")
-                        || acc.name.contains("_token")
-                        || acc.name == "position"
-                        || acc.token_mint.is_some();
+            #[derive(Accounts)]
+            pub struct Rename<'info> {
+                pub user: Signer<'info>,
+            }
 
-                    if is_token_account {
-                        transformed_code = transformed_code.replace(
-                            &format!("{} . mint", acc.name),
-                            &format!("get_token_mint({})?", acc.name),
-                        );
-                        transformed_code = transformed_code.replace(
-                            &format!("{} . owner", acc.name),
-                            &format!("get_token_owner({})?", acc.name),
-                        );
-                    }
+            #[event]
+            pub struct Renamed {
+                pub id: u64,
+                pub name: String,
+            }
+        "#;
+        let anchor = parser::parse_anchor_source(source).unwrap();
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        let program = transformer::transform(&anchor, &analysis, &Config::default()).unwrap();
+
+        // Logging just the discriminator would hand clients a truncated record
+        let err = emit_events_rs(&program).unwrap_err();
+        assert!(
+            err.to_string().contains("Event 'Renamed': field 'name' of type String has no fixed Borsh size"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_no_alloc_installs_no_allocator() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod pinged {
+                use super::*;
+                pub fn ping(ctx: Context<Ping>) -> Result<()> {
+                    Ok(())
                 }
+            }
 
-                // Fix Pubkey comparisons: add dereference for .key() in comparisons
-                // Replace " == X.key ()" patterns
-                for acc in &inst.accounts {
-                    // Pattern: == account.key () -> == *account.key()
-                    transformed_code = transformed_code.replace(
-                        &format!(" == {} . key ()", acc.name),
-                        &format!(" == *{}.key()", acc.name),
-                    );
-                    transformed_code = transformed_code.replace(
-                        &format!(" != {} . key ()", acc.name),
-                        &format!(" != *{}.key()", acc.name),
-                    );
-                    transformed_code = transformed_code.replace(
-                        &format!("{} . key () == ", acc.name),
-                        &format!("*{}.key() == ", acc.name),
-                    );
-                    transformed_code = transformed_code.replace(
-                        &format!("{} . key () != ", acc.name),
-                        &format!("*{}.key() != ", acc.name),
-                    );
+            #[derive(Accounts)]
+            pub struct Ping<'info> {
+                pub user: Signer<'info>,
+            }
+        "#;
+        let anchor = parser::parse_anchor_source(source).unwrap();
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        let emit_lib = |no_alloc, lazy_entrypoint| {
+            let config = Config {
+                no_alloc,
+                lazy_entrypoint,
+                ..Default::default()
+            };
+            let program = transformer::transform(&anchor, &analysis, &config).unwrap();
+            generate(&program).files.remove(Path::new("src/lib.rs")).unwrap()
+        };
+
+        let eager = emit_lib(true, false);
+        assert!(eager.contains("use pinocchio::{no_allocator, program_entrypoint};"));
+        assert!(eager.contains("program_entrypoint!(process_instruction);\n#[cfg(not(feature = \"no-entrypoint\"))]\nno_allocator!();"));
+        assert!(!eager.contains("\nentrypoint!"));
+        syn::parse_file(&eager).unwrap();
+
+        let lazy = emit_lib(true, true);
+        assert!(lazy.contains("lazy_program_entrypoint!(process_instruction);"));
+        assert!(lazy.contains("no_allocator!();"));
+
+        assert!(!emit_lib(false, false).contains("no_allocator!"));
+    }
+    #[test]
+    fn test_kept_region_survives_regeneration() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod counter {
+                use super::*;
+                pub fn bump(ctx: Context<Bump>, by: u64) -> Result<()> {
+                    ctx.accounts.counter.count += by;
+                    Ok(())
                 }
+            }
 
-                content.push_str(&format!("    {}\n", transformed_code));
+            #[derive(Accounts)]
+            pub struct Bump<'info> {
+                #[account(mut)]
+                pub counter: Account<'info, Counter>,
             }
-            _ => {}
+
+            #[account]
+            pub struct Counter {
+                pub count: u64,
+            }
+        "#;
+        let config = Config {
+            arg_structs: true,
+            ..Default::default()
+        };
+        let program = transform_source(source, &config);
+        let output_dir = TempDir::new().unwrap();
+        let handler = output_dir.path().join("src/instructions/bump.rs");
+
+        emit_with_extras(&program, Some(output_dir.path()), None).unwrap().write(output_dir.path()).unwrap();
+        let generated = fs::read_to_string(&handler).unwrap();
+        let logic = generated.find("    // Transformed instruction logic").unwrap();
+        let ok = generated.rfind("    Ok(())").unwrap();
+        let kept = "    // uncpi:keep\n    let counter_state = Counter::from_account_info_mut(counter)?;\n    \
+                    counter_state.count = counter_state.count.saturating_add(args.by);\n    // uncpi:end\n";
+        // Hand edits outside the markers are regenerated
+        let edited = format!("{}{}{}", &generated[..logic], kept, &generated[ok..])
+            .replace("NotEnoughAccountKeys", "Custom(1)");
+        fs::write(&handler, edited).unwrap();
+
+        emit_with_extras(&program, Some(output_dir.path()), None).unwrap().write(output_dir.path()).unwrap();
+        let regenerated = fs::read_to_string(&handler).unwrap();
+        assert!(regenerated.contains(kept));
+        assert!(!regenerated.contains("args.args"));
+        assert!(!regenerated.contains("Transformed instruction logic"));
+        assert!(regenerated.contains("NotEnoughAccountKeys"));
+        assert!(regenerated.ends_with("// uncpi:end\n\n    Ok(())\n}\n"));
+        syn::parse_file(&regenerated).unwrap();
+
+        // An unterminated region is an error rather than silently dropped
+        fs::write(&handler, regenerated.replace("    // uncpi:end\n", "")).unwrap();
+        let err = emit_with_extras(&program, Some(output_dir.path()), None).unwrap_err();
+        assert!(format!("{:#}", err).contains("without a matching `// uncpi:end`"));
+    }
+    #[test]
+    fn test_init_pda_with_provided_bump() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vaults {
+                use super::*;
+                pub fn open(ctx: Context<Open>, vault_bump: u8) -> Result<()> {
+                    ctx.accounts.vault.bump = vault_bump;
+                    Ok(())
+                }
+                pub fn open_canonical(ctx: Context<OpenCanonical>) -> Result<()> {
+                    ctx.accounts.vault.bump = ctx.bumps.vault;
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            #[instruction(vault_bump: u8)]
+            pub struct Open<'info> {
+                #[account(init, payer = user, space = 8 + 1, seeds = [b"vault", user.key().as_ref()], bump = vault_bump)]
+                pub vault: Account<'info, Vault>,
+                #[account(mut)]
+                pub user: Signer<'info>,
+                pub system_program: Program<'info, System>,
+            }
+
+            #[derive(Accounts)]
+            pub struct OpenCanonical<'info> {
+                #[account(init, payer = user, space = 8 + 1, seeds = [b"vault", user.key().as_ref()], bump)]
+                pub vault: Account<'info, Vault>,
+                #[account(mut)]
+                pub user: Signer<'info>,
+                pub system_program: Program<'info, System>,
+            }
+
+            #[account]
+            pub struct Vault {
+                pub bump: u8,
+            }
+        "#;
+        let provided = emit_instruction_source(source, "open");
+        let parse_bump = provided
+            .find("let vault_bump = data.get(0)")
+            .expect("bump arg parsed");
+        let verify = provided
+            .find("let expected_vault = pinocchio::pubkey::create_program_address(")
+            .expect("provided bump verified with create_program_address");
+        assert!(parse_bump < verify);
+        assert!(provided.contains("&[vault_bump]],\n        program_id,\n    )?;"));
+        assert!(provided.contains("let _bump_vault: u8 = vault_bump;"));
+        assert!(!provided.contains("find_program_address"));
+
+        let canonical = emit_instruction_source(source, "open_canonical");
+        assert!(canonical.contains(
+            "let (expected_vault, _bump_vault) = pinocchio::pubkey::find_program_address("
+        ));
+    }
+    #[test]
+    fn test_instructions_share_prelude() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+            use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+
+            #[program]
+            pub mod payments {
+                use super::*;
+                pub fn pay(ctx: Context<Pay>, amount: u64) -> Result<()> {
+                    token::transfer(
+                        CpiContext::new(
+                            ctx.accounts.token_program.to_account_info(),
+                            Transfer {
+                                from: ctx.accounts.from.to_account_info(),
+                                to: ctx.accounts.to.to_account_info(),
+                                authority: ctx.accounts.user.to_account_info(),
+                            },
+                        ),
+                        amount,
+                    )?;
+                    Ok(())
+                }
+                pub fn ping(ctx: Context<Ping>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Pay<'info> {
+                #[account(mut)]
+                pub from: Account<'info, TokenAccount>,
+                #[account(mut)]
+                pub to: Account<'info, TokenAccount>,
+                pub user: Signer<'info>,
+                pub token_program: Program<'info, Token>,
+            }
+
+            #[derive(Accounts)]
+            pub struct Ping<'info> {
+                pub user: Signer<'info>,
+            }
+        "#;
+        let program = transform_source(source, &Config::default());
+        let generated = generate(&program);
+
+        let prelude = &generated.files[Path::new("src/prelude.rs")];
+        assert!(prelude.contains("pub use pinocchio::{"));
+        syn::parse_file(prelude).unwrap();
+        let lib = &generated.files[Path::new("src/lib.rs")];
+        assert!(lib.contains("mod prelude;"));
+
+        let pay = &generated.files[Path::new("src/instructions/pay.rs")];
+        let ping = &generated.files[Path::new("src/instructions/ping.rs")];
+        for handler in [&pay, &ping] {
+            assert!(handler.contains("use crate::prelude::*;"));
+            assert!(!handler.contains("use pinocchio::{"));
+        }
+        // Token imports stay per handler, limited to the instructions it builds
+        assert!(pay.contains("use pinocchio_token::instructions::{Transfer};"), "{}", pay);
+        assert!(!ping.contains("pinocchio_token"));
+
+        // No blanket allow: unused imports are dropped and unused parameters marked
+        for handler in [&pay, &ping] {
+            assert!(!handler.contains("#![allow("));
+            assert!(!handler.contains("use crate::helpers::*;"));
         }
+        assert!(pay.contains("    program_id: &Pubkey,\n    accounts: &[AccountInfo],\n    data: &[u8],\n"));
+        assert!(ping.contains("    program_id: &Pubkey,\n    accounts: &[AccountInfo],\n    _data: &[u8],\n"), "{}", ping);
     }
+    #[test]
+    fn test_close_writes_closed_discriminator() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod escrow {
+                use super::*;
+                pub fn cancel(ctx: Context<Cancel>) -> Result<()> {
+                    ctx.accounts.escrow.amount = 0;
+                    Ok(())
+                }
+            }
 
-    if has_validations {
-        content.push('\n');
+            #[derive(Accounts)]
+            pub struct Cancel<'info> {
+                #[account(mut, close = payer)]
+                pub escrow: Account<'info, Escrow>,
+                #[account(mut)]
+                pub payer: Signer<'info>,
+            }
+
+            #[account]
+            pub struct Escrow {
+                pub amount: u64,
+            }
+        "#;
+        let code = emit_instruction_source(source, "cancel");
+
+        let logic = code.find("escrow_state.amount = 0").unwrap();
+        let drain = code
+            .find("*payer.try_borrow_mut_lamports()? += escrow_lamports;")
+            .unwrap();
+        let sentinel = code
+            .find("escrow_data[..8].copy_from_slice(&[255u8; 8]);")
+            .unwrap();
+        let clear = code.find("for byte in escrow_data.iter_mut()").unwrap();
+        assert!(logic < drain && drain < clear && clear < sentinel);
+        assert!(code.contains("*escrow.try_borrow_mut_lamports()? = 0;"));
+        assert!(code.contains("    close_accounts(escrow, payer)?;\n    Ok(())\n}\n"), "{}", code);
+        syn::parse_file(&code).unwrap();
+
+        // An early `return Ok(())` closes too, after releasing the state
+        let early = source.replace(
+            "ctx.accounts.escrow.amount = 0;",
+            "if ctx.accounts.escrow.amount == 0 {\n                        return Ok(());\n                    }\n                    ctx.accounts.escrow.amount = 0;",
+        );
+        let code = emit_instruction_source(&early, "cancel");
+        assert!(
+            code.contains("if escrow_state.amount == 0 { { drop(escrow_state); return close_accounts(escrow, payer) } ; }"),
+            "{}",
+            code
+        );
+        assert!(!code.contains("return Ok"));
+        assert_eq!(code.matches("// Close escrow").count(), 1);
+        syn::parse_file(&code).unwrap();
     }
+    #[test]
+    fn test_stored_bump_from_other_account_deserializes_it() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod pools {
+                use super::*;
+                pub fn sweep(ctx: Context<Sweep>) -> Result<()> {
+                    Ok(())
+                }
+            }
 
-    // Parse remaining instruction arguments (skip those already parsed for PDA seeds)
-    let remaining_args: Vec<_> = inst
-        .args
-        .iter()
-        .filter(|arg| !args_used_in_pda.contains(&arg.name))
-        .collect();
+            #[derive(Accounts)]
+            pub struct Sweep<'info> {
+                pub pool: Account<'info, Pool>,
+                #[account(seeds = [b"vault", pool.key().as_ref()], bump = pool.vault_bump)]
+                pub vault: SystemAccount<'info>,
+            }
 
-    if !remaining_args.is_empty() {
-        content.push_str("    // Parse instruction arguments\n");
+            #[account]
+            pub struct Pool {
+                pub vault_bump: u8,
+            }
+        "#;
+        let code = emit_instruction_source(source, "sweep");
+
+        let deserialize = code.find("let pool_state = Pool::from_account_info(pool)?;").unwrap();
+        let verify = code
+            .find("let expected_vault = pinocchio::pubkey::create_program_address(")
+            .unwrap();
+        assert!(deserialize < verify);
+        assert!(code.contains("&[pool_state.vault_bump]]"));
+        assert!(!code.contains("find_program_address"));
+    }
+    #[test]
+    fn test_own_stored_bump_skips_bump_search() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vaults {
+                use super::*;
+                pub fn touch(ctx: Context<Touch>) -> Result<()> {
+                    Ok(())
+                }
+            }
 
-        let mut offset = 0usize;
-        for arg in &inst.args {
-            let (size, parse_code) = get_arg_parse_code(&arg.ty, offset, &arg.name);
-            // Only emit if not already parsed for PDA seeds
-            if !args_used_in_pda.contains(&arg.name) {
-                content.push_str(&format!("    {}\n", parse_code));
+            #[derive(Accounts)]
+            pub struct Touch<'info> {
+                #[account(seeds = [b"vault", vault.authority.as_ref()], bump = vault.bump)]
+                pub vault: Account<'info, Vault>,
             }
-            offset += size;
-        }
-        content.push('\n');
+
+            #[account]
+            pub struct Vault {
+                pub authority: Pubkey,
+                pub bump: u8,
+            }
+        "#;
+        let code = emit_instruction_source(source, "touch");
+        syn::parse_file(&code).unwrap();
+
+        let deserialize = code.find("let vault_state = Vault::from_account_info(vault)?;").unwrap();
+        let verify = code
+            .find("let expected_vault = pinocchio::pubkey::create_program_address(")
+            .expect(&code);
+        assert!(deserialize < verify);
+        assert!(code.contains("&[vault_state.bump]],\n        program_id,\n    )?;"), "{}", code);
+        assert!(!code.contains("find_program_address"), "{}", code);
     }
+    #[test]
+    fn test_token_field_constraints_follow_account_type() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+            use anchor_spl::token_interface::{Mint, TokenAccount};
+
+            #[program]
+            pub mod profiles {
+                use super::*;
+                pub fn link(ctx: Context<Link>) -> Result<()> {
+                    Ok(())
+                }
+            }
 
-    // Generate token account initialization code if needed
-    for acc in &inst.accounts {
-        if acc.is_init && acc.token_mint.is_some() && acc.token_authority.is_some() {
-            content.push_str(&format!("    // Initialize token account: {}\n", acc.name));
-            let mint_name = acc.token_mint.as_ref().unwrap();
-            let authority_name = acc.token_authority.as_ref().unwrap();
-            let default_payer = "authority".to_string();
-            let payer_name = acc.init_payer.as_ref().unwrap_or(&default_payer);
+            #[derive(Accounts)]
+            pub struct Link<'info> {
+                /// CHECK: only its owner is checked
+                #[account(constraint = user_profile.owner == &crate::ID)]
+                pub user_profile: UncheckedAccount<'info>,
+                #[account(constraint = user_vault.mint == mint.key())]
+                pub user_vault: InterfaceAccount<'info, TokenAccount>,
+                pub mint: InterfaceAccount<'info, Mint>,
+                pub owner: Signer<'info>,
+            }
+        "#;
+        let code = emit_instruction_source(source, "link");
+        syn::parse_file(&code).unwrap();
 
-            // Verify rent sysvar address
-            content.push_str("    // Verify Rent sysvar\n");
-            content.push_str("    const RENT_SYSVAR_ID: [u8; 32] = [\n");
-            content.push_str(
-                "        6, 167, 213, 23, 24, 199, 116, 201, 40, 86, 99, 152, 105, 29,\n",
-            );
-            content
-                .push_str("        94, 182, 139, 94, 184, 163, 155, 75, 109, 92, 115, 85, 91,\n");
-            content.push_str("        33, 0, 0, 0, 0,\n");
-            content.push_str("    ];\n");
-            content.push_str("    if *rent_sysvar.key() != RENT_SYSVAR_ID {\n");
-            content.push_str("        return Err(ProgramError::InvalidArgument);\n");
-            content.push_str("    }\n\n");
+        // A `user_` name doesn't make the profile a token account
+        assert!(!code.contains("get_token_owner(user_profile)"), "{}", code);
+        assert!(code.contains("get_token_mint(user_vault)? == *mint.key()"), "{}", code);
+    }
 
-            // Add create_account CPI if this is a PDA (needs to be created)
-            if acc.is_pda && acc.pda_seeds.is_some() {
-                content.push_str("    // Create PDA account for token account\n");
-                content.push_str(
-                    "    const TOKEN_ACCOUNT_SIZE: usize = 165; // SPL Token Account size\n",
-                );
-                content.push_str("    let rent = pinocchio::sysvars::rent::Rent::get()?;\n");
-                content.push_str(
-                    "    let rent_lamports = rent.minimum_balance(TOKEN_ACCOUNT_SIZE);\n\n",
-                );
+    #[test]
+    fn test_owner_constraint_checks() {
+        let source = r#"
+            use anchor_lang::prelude::*;
 
-                content.push_str("    // Transfer lamports from payer to new account\n");
-                content.push_str(&format!(
-                    "    *{}.try_borrow_mut_lamports()? -= rent_lamports;\n",
-                    payer_name
-                ));
-                content.push_str(&format!(
-                    "    *{}.try_borrow_mut_lamports()? += rent_lamports;\n\n",
-                    acc.name
-                ));
+            #[program]
+            pub mod owned {
+                use super::*;
+                pub fn check(ctx: Context<Check>) -> Result<()> {
+                    Ok(())
+                }
+            }
 
-                content.push_str("    // Allocate space and assign owner\n");
-                content.push_str(&format!("    unsafe {{ {}.assign(&pinocchio_token::ID); }}\n", acc.name));
-                content.push_str(&format!(
-                    "    {}.realloc(TOKEN_ACCOUNT_SIZE, false)?;\n\n",
-                    acc.name
-                ));
+            #[derive(Accounts)]
+            pub struct Check<'info> {
+                /// CHECK: owner verified by constraint
+                #[account(owner = token_program.key())]
+                pub token_data: UncheckedAccount<'info>,
+                /// CHECK: owner verified by constraint
+                #[account(mut, owner = crate::ID @ ErrorCode::WrongOwner, constraint = config.owner == token_program.key())]
+                pub config: UncheckedAccount<'info>,
+                pub token_program: Program<'info, Token>,
             }
 
-            content.push_str(&format!(
-                "    pinocchio_token::instructions::InitializeAccount2 {{\n        account: {},\n        mint: {},\n        owner: {},\n        rent_sysvar: rent_sysvar,\n    }}.invoke()?;\n\n",
-                acc.name, mint_name, authority_name
-            ));
-        }
+            #[error_code]
+            pub enum ErrorCode {
+                WrongOwner,
+            }
+        "#;
+        let code = emit_instruction_source(source, "check");
+
+        assert!(code.contains(
+            "if !token_data.is_owned_by(token_program.key()) {\n        return Err(ProgramError::Custom(2004));"
+        ));
+        // `@ ErrorCode::X` replaces ConstraintOwner
+        assert!(code.contains("if !config.is_owned_by(&crate::ID) {\n        return Err(Error::WrongOwner.into());"), "{}", code);
+        // `owner` inside a constraint expression is not an owner constraint
+        assert_eq!(code.matches("is_owned_by").count(), 2);
+        syn::parse_file(&code).unwrap();
     }
+    #[test]
+    fn test_address_constraint_checks_key() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            pub const ADMIN: Pubkey = pubkey!("11111111111111111111111111111112");
+
+            #[program]
+            pub mod registry {
+                use super::*;
+                pub fn update(ctx: Context<Update>) -> Result<()> {
+                    Ok(())
+                }
+            }
 
-    // Add transformed body or placeholder
-    let body_ends_with_ok =
-        inst.body.trim().ends_with("Ok (())") || inst.body.trim().ends_with("Ok(())");
+            #[derive(Accounts)]
+            pub struct Update<'info> {
+                #[account(address = ADMIN @ ErrorCode::NotAdmin)]
+                pub admin: Signer<'info>,
+                /// CHECK: pinned to the incinerator
+                #[account(address = pubkey!("1nc1nerator11111111111111111111111111111111"))]
+                pub incinerator: UncheckedAccount<'info>,
+                pub config: Account<'info, Config>,
+                /// CHECK: must be the stored treasury
+                #[account(address = config.treasury)]
+                pub treasury: UncheckedAccount<'info>,
+            }
 
-    if !inst.body.is_empty() && inst.body != "{}" {
-        content.push_str("    // Transformed instruction logic\n");
-        // Add the transformed body (will have some TODO markers)
-        // First, fix any multi-line msg! macros
-        let mut fixed_body = fix_msg_macros(&inst.body);
-        // Fix .key() assignments that need dereferencing
-        fixed_body = fix_key_dereferencing(&fixed_body);
-        // Remove redundant self-assignments like "let escrow = & mut escrow ;"
-        fixed_body = remove_redundant_assignments(&fixed_body);
-        // Replace custom error enum names with Error::
-        fixed_body = fix_error_enum_names(&fixed_body, program);
-        for line in fixed_body.lines() {
-            let trimmed = line.trim();
-            if !trimmed.is_empty() {
-                // Skip duplicate Ok(()) if body already has it
-                if body_ends_with_ok && (trimmed == "Ok (())" || trimmed == "Ok(())") {
-                    continue;
-                }
-                content.push_str(&format!("    {}\n", trimmed));
+            #[account]
+            pub struct Config {
+                pub treasury: Pubkey,
             }
-        }
-    } else {
-        content.push_str("    // TODO: Implement instruction logic\n");
+
+            #[error_code]
+            pub enum ErrorCode {
+                NotAdmin,
+            }
+        "#;
+        let code = emit_instruction_source(source, "update");
+
+        // The account's index constant doesn't shadow the program's ADMIN
+        assert!(code.contains("const ACCOUNT_ADMIN: usize = 0;"));
+        assert!(!code.contains("const ADMIN"));
+        assert!(code.contains("if admin.key() != &ADMIN {\n        return Err(Error::NotAdmin.into());"), "{}", code);
+        // Literal pubkeys are decoded, since Pinocchio has no pubkey! here
+        assert!(code.contains(
+            "if incinerator.key() != &[0x00, 0x33, 0x90, 0x72, 0x8d, 0x34, 0x11, 0x60, 0x79, 0xbd, 0xc9, 0x11, \
+             0xbf, 0xff, 0x00, 0xdb, 0xd4, 0x4d, 0x2e, 0xcd, 0xcc, 0xf7, 0x9c, 0xa6, 0xe1, 0x00, 0x38, 0xe1, \
+             0x00, 0x00, 0x00, 0x00] {\n        return Err(ProgramError::Custom(2012));"
+        ), "{}", code);
+        assert!(code.contains("if treasury.key() != &config_state.treasury {"));
+        syn::parse_file(&code).unwrap();
+
+        // ...and ADMIN itself is written out as bytes
+        let extras = parser::parse_extras_source(source).unwrap();
+        let anchor = parser::parse_anchor_source(source).unwrap();
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        let program = transformer::transform(&anchor, &analysis, &Config::default()).unwrap();
+        let helpers = emit_helpers_rs(&extras, &program);
+        assert!(helpers.contains(&format!("pub const ADMIN: Pubkey = [{}0x01];", "0x00, ".repeat(31))), "{}", helpers);
     }
+    #[test]
+    fn test_pinned_token_mint_checked() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+            use anchor_spl::token::{Mint, Token, TokenAccount};
+
+            pub const USDC_MINT: Pubkey = pubkey!("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v");
+
+            #[program]
+            pub mod vaults {
+                use super::*;
+                pub fn open(ctx: Context<Open>) -> Result<()> {
+                    Ok(())
+                }
+            }
 
-    // Only add Ok(()) if body doesn't already have it
-    if !body_ends_with_ok {
-        content.push_str("\n    Ok(())\n");
-    } else {
-        content.push_str("    Ok(())\n");
+            #[derive(Accounts)]
+            pub struct Open<'info> {
+                #[account(init, payer = payer, token::mint = USDC_MINT, token::authority = payer)]
+                pub vault: Account<'info, TokenAccount>,
+                pub usdc: Account<'info, Mint>,
+                #[account(mut)]
+                pub payer: Signer<'info>,
+                pub token_program: Program<'info, Token>,
+                pub system_program: Program<'info, System>,
+            }
+        "#;
+        let code = emit_instruction_source(source, "open");
+
+        let check = code
+            .find("if *usdc.key() != USDC_MINT {\n        return Err(ProgramError::Custom(2014));")
+            .unwrap();
+        let init = code.find("InitializeAccount2 {\n        account: vault,\n        mint: usdc,").unwrap();
+        assert!(check < init);
+        syn::parse_file(&code).unwrap();
     }
-    content.push_str("}\n");
+    #[test]
+    fn test_nested_cpi_releases_guards() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+            use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+
+            #[program]
+            pub mod pools {
+                use super::*;
+                pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
+                    if amount > 0 {
+                    ctx.accounts.pool.total -= amount;
+                    token::transfer(
+                        CpiContext::new(
+                            ctx.accounts.token_program.to_account_info(),
+                            Transfer {
+                                from: ctx.accounts.vault.to_account_info(),
+                                to: ctx.accounts.user_token.to_account_info(),
+                                authority: ctx.accounts.pool.to_account_info(),
+                            },
+                        ),
+                        amount,
+                    )?;
+                    }
+                    ctx.accounts.pool.count += 1;
+                    Ok(())
+                }
+            }
 
-    fs::write(inst_dir.join(format!("{}.rs", inst.name)), content)?;
-    Ok(())
-}
+            #[account]
+            pub struct Pool {
+                pub authority: Pubkey,
+                pub total: u64,
+                pub count: u64,
+            }
 
-fn to_screaming_snake(s: &str) -> String {
-    let mut result = String::new();
-    for (i, c) in s.chars().enumerate() {
-        if c.is_uppercase() && i > 0 {
-            result.push('_');
-        }
-        result.push(c.to_uppercase().next().unwrap());
+            #[derive(Accounts)]
+            pub struct Withdraw<'info> {
+                #[account(mut, has_one = authority, close = authority)]
+                pub pool: Account<'info, Pool>,
+                #[account(mut)]
+                pub vault: Account<'info, TokenAccount>,
+                #[account(mut)]
+                pub user_token: Account<'info, TokenAccount>,
+                #[account(mut)]
+                pub authority: Signer<'info>,
+                pub token_program: Program<'info, Token>,
+            }
+        "#;
+        let code = emit_instruction_source(source, "withdraw");
+
+        // Released before the block, and the write inside borrows only for its own statement
+        let block = code.find("if amount> 0 {").unwrap();
+        assert!(code[..block].ends_with("drop(pool_state);\n    "), "{}", code);
+        assert!(code.contains("if amount> 0 { (Pool::from_account_info_mut(pool)?).total -= amount ;"));
+        let retake = code.find("let mut pool_state = Pool::from_account_info_mut(pool)?;\n    pool_state.count += 1").unwrap();
+        assert!(block < retake);
+        syn::parse_file(&code).unwrap();
+
+        // A copy taken ahead of the block can't see what the block writes
+        let anchor = parser::parse_anchor_source(&source.replace("\n                        amount,\n", "\n                        ctx.accounts.pool.total,\n")).unwrap();
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        let program = transformer::transform(&anchor, &analysis, &Config::default()).unwrap();
+        let err = emit_with_extras(&program, None, None).unwrap_err();
+        assert!(err.to_string().contains("reads pool_state.total, which the enclosing block also changes"), "{}", err);
     }
-    result
-}
+    #[test]
+    fn test_state_guards_released_around_cpis() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+            use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+
+            #[program]
+            pub mod pools {
+                use super::*;
+                pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
+                    ctx.accounts.pool.total -= amount;
+                    token::transfer(
+                        CpiContext::new(
+                            ctx.accounts.token_program.to_account_info(),
+                            Transfer {
+                                from: ctx.accounts.vault.to_account_info(),
+                                to: ctx.accounts.user_token.to_account_info(),
+                                authority: ctx.accounts.pool.to_account_info(),
+                            },
+                        ),
+                        amount,
+                    )?;
+                    ctx.accounts.pool.count += 1;
+                    Ok(())
+                }
+            }
 
-/// Returns (size, parse_code) for a given type
-fn get_arg_parse_code(ty: &str, offset: usize, name: &str) -> (usize, String) {
-    let ty_clean = ty.replace(" ", "").to_lowercase();
+            #[account]
+            pub struct Pool {
+                pub authority: Pubkey,
+                pub total: u64,
+                pub count: u64,
+            }
 
-    match ty_clean.as_str() {
-        "u8" => (1, format!(
-            "let {} = data.get({}).copied().ok_or(ProgramError::InvalidInstructionData)?;",
-            name, offset
-        )),
-        "i8" => (1, format!(
-            "let {} = data.get({}).map(|&b| b as i8).ok_or(ProgramError::InvalidInstructionData)?;",
-            name, offset
-        )),
-        "u16" => (2, format!(
-            "let {} = u16::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
-            name, offset, offset + 2
-        )),
-        "i16" => (2, format!(
-            "let {} = i16::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
-            name, offset, offset + 2
-        )),
-        "u32" => (4, format!(
-            "let {} = u32::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
-            name, offset, offset + 4
-        )),
-        "i32" => (4, format!(
-            "let {} = i32::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
-            name, offset, offset + 4
-        )),
-        "u64" => (8, format!(
-            "let {} = u64::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
-            name, offset, offset + 8
-        )),
-        "i64" => (8, format!(
-            "let {} = i64::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
-            name, offset, offset + 8
-        )),
-        "u128" => (16, format!(
-            "let {} = u128::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
-            name, offset, offset + 16
-        )),
-        "i128" => (16, format!(
-            "let {} = i128::from_le_bytes(data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap());",
-            name, offset, offset + 16
-        )),
-        "bool" => (1, format!(
-            "let {} = data.get({}).copied().ok_or(ProgramError::InvalidInstructionData)? != 0;",
-            name, offset
-        )),
-        "pubkey" => (32, format!(
-            "let {}: &[u8; 32] = data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap();",
-            name, offset, offset + 32
-        )),
-        // Fixed-size byte arrays
-        "[u8;32]" => (32, format!(
-            "let {}: [u8; 32] = data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap();",
-            name, offset, offset + 32
-        )),
-        "[u8;64]" => (64, format!(
-            "let {}: [u8; 64] = data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap();",
-            name, offset, offset + 64
-        )),
-        _ => {
-            // Check for generic [u8; N] pattern
-            if ty_clean.starts_with("[u8;") && ty_clean.ends_with("]") {
-                if let Some(n_str) = ty_clean.strip_prefix("[u8;").and_then(|s| s.strip_suffix("]")) {
-                    if let Ok(n) = n_str.parse::<usize>() {
-                        return (n, format!(
-                            "let {}: [u8; {}] = data.get({}..{}).ok_or(ProgramError::InvalidInstructionData)?.try_into().unwrap();",
-                            name, n, offset, offset + n
-                        ));
-                    }
+            #[derive(Accounts)]
+            pub struct Withdraw<'info> {
+                #[account(mut, has_one = authority, close = authority)]
+                pub pool: Account<'info, Pool>,
+                #[account(mut)]
+                pub vault: Account<'info, TokenAccount>,
+                #[account(mut)]
+                pub user_token: Account<'info, TokenAccount>,
+                #[account(mut)]
+                pub authority: Signer<'info>,
+                pub token_program: Program<'info, Token>,
+            }
+        "#;
+        let program = transform_source(source, &Config::default());
+        let generated = generate(&program);
+
+        // The guard returned by from_account_info keeps the data borrowed
+        let state = &generated.files[Path::new("src/state.rs")];
+        assert!(state.contains("pub fn from_account_info(info: &AccountInfo) -> Result<Ref<'_, Self>, ProgramError> {"));
+        assert!(state.contains("Ok(RefMut::map(data, |data| unsafe { &mut *(data[8..].as_mut_ptr() as *mut Self) }))"));
+
+        let code = &generated.files[Path::new("src/instructions/withdraw.rs")];
+        let validate = code.find("let pool_state = Pool::from_account_info(pool)?;").unwrap();
+        let take = code.find("let mut pool_state = Pool::from_account_info_mut(pool)?;").unwrap();
+        let release = code[validate..take].find("drop(pool_state);");
+        assert!(release.is_some(), "validation guard not released:\n{}", code);
+
+        // Dropped before the transfer that passes pool, taken again for the update after it
+        let cpi = code.find("authority: pool,").unwrap();
+        let before_cpi = code[take..cpi].rfind("drop(pool_state);").unwrap() + take;
+        let retake = code[cpi..].find("let mut pool_state = Pool::from_account_info_mut(pool)?;").unwrap() + cpi;
+        let update = code.find("pool_state.count += 1").unwrap();
+        assert!(take < before_cpi && retake < update);
+
+        // Released before the close zeroes the data
+        let close = code.find("// Close pool").unwrap();
+        assert!(code[update..close].contains("drop(pool_state);"));
+        syn::parse_file(code).unwrap();
+    }
+    #[test]
+    fn test_associated_token_init_and_checks() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+            use anchor_spl::associated_token::AssociatedToken;
+            use anchor_spl::token::{Mint, Token, TokenAccount};
+
+            #[program]
+            pub mod wallets {
+                use super::*;
+                pub fn open(ctx: Context<Open>) -> Result<()> {
+                    Ok(())
+                }
+                pub fn check(ctx: Context<Check>) -> Result<()> {
+                    Ok(())
                 }
             }
-            // Default: assume it's a custom struct or unknown type
-            (0, format!("// TODO: Parse {} of type {} at offset {}", name, ty, offset))
-        }
+
+            #[derive(Accounts)]
+            pub struct Open<'info> {
+                #[account(init_if_needed, payer = payer, associated_token::mint = mint, associated_token::authority = owner)]
+                pub wallet: Account<'info, TokenAccount>,
+                pub mint: Account<'info, Mint>,
+                pub owner: SystemAccount<'info>,
+                #[account(mut)]
+                pub payer: Signer<'info>,
+                pub token_program: Program<'info, Token>,
+                pub associated_token_program: Program<'info, AssociatedToken>,
+                pub system_program: Program<'info, System>,
+            }
+
+            #[derive(Accounts)]
+            pub struct Check<'info> {
+                #[account(associated_token::mint = mint, associated_token::authority = owner)]
+                pub wallet: Account<'info, TokenAccount>,
+                pub mint: Account<'info, Mint>,
+                pub owner: Signer<'info>,
+            }
+        "#;
+        let program = transform_source(source, &Config::default());
+        let generated = generate(&program);
+        let read = |path: &str| &generated.files[Path::new(path)];
+
+        let open = read("src/instructions/open.rs");
+        assert!(open.contains(
+            "pinocchio_associated_token_account::instructions::CreateIdempotent {\n        funding_account: payer,\n        account: wallet,\n        wallet: owner,\n        mint: mint,"
+        ));
+        // The ATA program takes no rent sysvar and does its own initialization
+        assert!(!open.contains("rent_sysvar"));
+        assert!(!open.contains("InitializeAccount2 {"));
+        syn::parse_file(open).unwrap();
+
+        let check = read("src/instructions/check.rs");
+        assert!(check.contains("&[owner.key().as_ref(), pinocchio_token::ID.as_ref(), mint.key().as_ref()],"));
+        assert!(check.contains("if *wallet.key() != expected_wallet {"));
+        assert!(check.contains("if get_token_owner(wallet)? != *owner.key() {"));
+        syn::parse_file(check).unwrap();
+
+        assert!(read("Cargo.toml").contains("pinocchio-associated-token-account = \"0.1\""));
     }
-}
+    #[test]
+    fn test_associated_token_uses_declared_programs() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+            use anchor_spl::associated_token::AssociatedToken;
+            use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+            #[program]
+            pub mod wallets {
+                use super::*;
+                pub fn open(ctx: Context<Open>) -> Result<()> {
+                    Ok(())
+                }
+                pub fn check(ctx: Context<Check>) -> Result<()> {
+                    Ok(())
+                }
+            }
 
-/// Fix .key() calls that need dereferencing in assignments
-fn fix_key_dereferencing(body: &str) -> String {
-    let mut result = String::new();
-    
-    for line in body.lines() {
-        let mut new_line = line.to_string();
-        
-        // Pattern: something = account.key () ; (with spaces)
-        // Pattern: something = account.key() ; (no spaces)
-        // Also handle: account . key () (space before dot)
-        if line.contains(" = ") && line.contains("key ()")  {
-            // Check if not already dereferenced
-            if !line.contains(" = *") && !line.contains(" =*") {
-                // More precise: Only add * right before the identifier that has .key()
-                // Find the position of " = " and add * after it
-                if let Some(eq_pos) = new_line.find(" = ") {
-                    let before = &new_line[..eq_pos + 3]; // Include " = "
-                    let after = &new_line[eq_pos + 3..];
-                    new_line = format!("{}*{}", before, after);
+            #[derive(Accounts)]
+            pub struct Open<'info> {
+                #[account(init, payer = payer, associated_token::mint = mint, associated_token::authority = owner)]
+                pub wallet: InterfaceAccount<'info, TokenAccount>,
+                pub mint: InterfaceAccount<'info, Mint>,
+                pub owner: SystemAccount<'info>,
+                #[account(mut)]
+                pub payer: Signer<'info>,
+                pub token_2022: Interface<'info, TokenInterface>,
+                pub associated_token_program: Program<'info, AssociatedToken>,
+                pub sys: Program<'info, System>,
+            }
+
+            #[derive(Accounts)]
+            pub struct Check<'info> {
+                #[account(associated_token::mint = mint, associated_token::authority = owner, associated_token::token_program = token_program)]
+                pub wallet: InterfaceAccount<'info, TokenAccount>,
+                pub mint: InterfaceAccount<'info, Mint>,
+                pub owner: Signer<'info>,
+                /// CHECK: Token or Token-2022
+                pub token_program: UncheckedAccount<'info>,
+            }
+        "#;
+        let program = transform_source(source, &Config::default());
+        let generated = generate(&program);
+
+        // The struct's own program accounts go to the ATA program, with no system_program added
+        let open = &generated.files[Path::new("src/instructions/open.rs")];
+        assert!(open.contains("        system_program: sys,\n        token_program: token_2022,\n    }.invoke()?;"), "{}", open);
+        assert!(open.contains("if *sys.key() != pinocchio_system::ID {"));
+        assert!(!open.contains("system_program }"));
+        syn::parse_file(open).unwrap();
+
+        // associated_token::token_program derives with that program's key
+        let check = &generated.files[Path::new("src/instructions/check.rs")];
+        assert!(check.contains("&[owner.key().as_ref(), token_program.key().as_ref(), mint.key().as_ref()],"), "{}", check);
+
+        // Creating an ATA without a token program account can't be done
+        let anchor = parser::parse_anchor_source(&source.replace("pub token_2022: Interface<'info, TokenInterface>,", "")).unwrap();
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        let err = transformer::transform(&anchor, &analysis, &Config::default()).unwrap_err();
+        assert!(err.to_string().contains("associated token account 'wallet' is created through the ATA program"), "{}", err);
+    }
+    #[test]
+    fn test_custom_struct_arg_parsed_field_by_field() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod swapper {
+                use super::*;
+                pub fn swap(ctx: Context<Swap>, params: SwapParams, memo: u8) -> Result<()> {
+                    ctx.accounts.pool.last_amount = params.amount_in;
+                    Ok(())
                 }
             }
-        }
-        
-        result.push_str(&new_line);
-        result.push('\n');
+
+            #[derive(Accounts)]
+            pub struct Swap<'info> {
+                #[account(mut)]
+                pub pool: Account<'info, Pool>,
+            }
+
+            #[account]
+            pub struct Pool {
+                pub last_amount: u64,
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub struct SwapParams {
+                pub amount_in: u64,
+                pub min_out: u32,
+            }
+        "#;
+        let code = emit_instruction_source(source, "swap");
+
+        assert!(code.contains("use crate::state::SwapParams;"));
+        assert!(code.contains("let params_amount_in = u64::from_le_bytes(data.get(0..8)"));
+        assert!(code.contains("let params_min_out = u32::from_le_bytes(data.get(8..12)"));
+        assert!(code.contains("let params = SwapParams { amount_in: params_amount_in, min_out: params_min_out };"));
+        // The cursor advances past the struct for the following arg
+        assert!(code.contains("let memo = data.get(12)"));
+        syn::parse_file(&code).unwrap();
     }
-    
-    // Clean up potential double dereferences
-    result = result.replace(" = **", " = *");
-    
-    result
-}
 
-/// Remove redundant self-assignments like "let x = &mut x;"
-fn remove_redundant_assignments(body: &str) -> String {
-    let mut result = String::new();
-    
-    for line in body.lines() {
-        let trimmed = line.trim();
-        
-        // Pattern: let varname = &mut varname ; or let varname = *& mut varname ;
-        // These are self-assignments that should be removed
-        let mut skip_line = false;
-        
-        if trimmed.starts_with("let ") && trimmed.contains(" = ") {
-            // Extract variable name
-            if let Some(eq_pos) = trimmed.find(" = ") {
-                let before_eq = &trimmed[4..eq_pos].trim(); // After "let "
-                let after_eq = &trimmed[eq_pos + 3..].trim();
-                
-                // Check if it's a self-assignment
-                // Pattern: "let x = & mut x ;" or "let x = *& mut x ;"
-                let self_ref_pattern1 = format!("& mut {} ;", before_eq);
-                let self_ref_pattern2 = format!("*& mut {} ;", before_eq);
-                let self_ref_pattern3 = format!("&mut {} ;", before_eq);
-                let self_ref_pattern4 = format!("*&mut {} ;", before_eq);
-                
-                if after_eq.contains(&self_ref_pattern1) || 
-                   after_eq.contains(&self_ref_pattern2) ||
-                   after_eq.contains(&self_ref_pattern3) ||
-                   after_eq.contains(&self_ref_pattern4) {
-                    skip_line = true;
+    #[test]
+    fn test_heap_backed_arg_types_are_not_copy() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod notes {
+                use super::*;
+                pub fn post(ctx: Context<Post>, note: Note) -> Result<()> {
+                    Ok(())
                 }
             }
-        }
-        
-        if !skip_line {
-            result.push_str(line);
-            result.push('\n');
-        }
+
+            #[derive(Accounts)]
+            pub struct Post<'info> {
+                pub user: Signer<'info>,
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub struct Note {
+                pub id: u64,
+                pub text: String,
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub struct Limits {
+                pub caps: [u64; 4],
+                pub owner: Pubkey,
+            }
+        "#;
+        let anchor = parser::parse_anchor_source(source).unwrap();
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        let program = transformer::transform(&anchor, &analysis, &Config::default()).unwrap();
+
+        let state = emit_state_rs(&program);
+        assert!(state.contains("#[derive(Clone)]\npub struct Note {"), "{}", state);
+        assert!(state.contains("#[derive(Clone, Copy)]\npub struct Limits {"), "{}", state);
+
+        // A length-prefixed field leaves no fixed offset to read the arg from
+        let err = emit_with_extras(&program, None, None).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("Instruction 'post'"), "{}", message);
+        assert!(message.contains("Arg 'note_text' has type String"), "{}", message);
     }
-    
-    result
-}
+    #[test]
+    fn test_fieldless_enum_arg_parsed_from_tag() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod actions {
+                use super::*;
+                pub fn act(ctx: Context<Act>, action: Action, amount: u64) -> Result<()> {
+                    Ok(())
+                }
+            }
 
-/// Replace custom error enum names (like VotingError, StakingError) with Error
-fn fix_error_enum_names(body: &str, program: &PinocchioProgram) -> String {
-    let mut result = body.to_string();
+            #[derive(Accounts)]
+            pub struct Act<'info> {
+                pub user: Signer<'info>,
+            }
 
-    // If there are no errors, nothing to fix
-    if program.errors.is_empty() {
-        return result;
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+            pub enum Action {
+                Deposit,
+                Withdraw,
+            }
+        "#;
+        let code = emit_instruction_source(source, "act");
+
+        assert!(code.contains("use crate::state::Action;"));
+        assert!(code.contains(
+            "let action = match data.get(0).copied().ok_or(ProgramError::InvalidInstructionData)? {\n        \
+             0 => Action::Deposit,\n        1 => Action::Withdraw,\n        \
+             _ => return Err(ProgramError::InvalidInstructionData),\n    };"
+        ));
+        // The tag is one byte
+        assert!(code.contains("let amount = u64::from_le_bytes(data.get(1..9)"));
+        syn::parse_file(&code).unwrap();
     }
 
-    // Dynamically find all patterns like "SomeError::" and replace with "Error::"
-    // This uses a regex-like approach to find any word ending with "Error::"
-    let mut words_to_replace = Vec::new();
-    let chars: Vec<char> = result.chars().collect();
-    let mut i = 0;
+    #[test]
+    fn test_enum_args_need_copy_fields_and_equal_payloads() {
+        let source = r#"
+            use anchor_lang::prelude::*;
 
-    while i < chars.len() {
-        // Look for pattern: AlphanumericError::
-        if i + 7 < chars.len() && chars[i].is_alphabetic() {
-            let mut j = i;
-            // Collect the word
-            while j < chars.len() && (chars[j].is_alphanumeric() || chars[j] == '_') {
-                j += 1;
-            }
-
-            // Check if it ends with "Error::"
-            if j + 2 <= chars.len() {
-                let word: String = chars[i..j].iter().collect();
-                if word.ends_with("Error") && j + 1 < chars.len() && chars[j] == ':' && chars[j + 1] == ':' {
-                    let full_pattern = format!("{}::", word);
-                    if !words_to_replace.contains(&full_pattern) {
-                        words_to_replace.push(full_pattern);
-                    }
+            #[program]
+            pub mod orders {
+                use super::*;
+                pub fn place(ctx: Context<Place>, side: Side) -> Result<()> {
+                    Ok(())
                 }
             }
-        }
-        i += 1;
-    }
 
-    // Replace all found error patterns with "Error::"
-    for pattern in words_to_replace {
-        result = result.replace(&pattern, "Error::");
-    }
+            #[derive(Accounts)]
+            pub struct Place<'info> {
+                pub user: Signer<'info>,
+            }
 
-    result
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub enum Side {
+                Bid { price: u64 },
+                Ask(u32),
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub enum Tag {
+                Named(String),
+                Empty,
+            }
+        "#;
+        let anchor = parser::parse_anchor_source(source).unwrap();
+        let analysis = analyzer::analyze(&anchor).unwrap();
+        let program = transformer::transform(&anchor, &analysis, &Config::default()).unwrap();
+
+        let state = emit_state_rs(&program);
+        assert!(state.contains("#[derive(Clone, Copy, PartialEq, Eq)]\npub enum Side {"), "{}", state);
+        assert!(state.contains("#[derive(Clone, PartialEq, Eq)]\npub enum Tag {"), "{}", state);
+
+        // Bid carries 8 bytes and Ask 4, so nothing after the arg has a fixed offset
+        let err = emit_with_extras(&program, None, None).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("Arg 'side' of type Side: variant payloads differ in size"), "{}", message);
+    }
 }
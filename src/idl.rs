@@ -1,18 +1,35 @@
 //! IDL Generator for Pinocchio programs
-//! Generates Anchor-compatible IDL JSON from the transpiled program
+//! Generates Anchor-compatible IDL JSON from the transpiled program, or a Codama root node
 
 use crate::ir::{
-    PinocchioError, PinocchioField, PinocchioInstruction, PinocchioProgram, PinocchioState,
+    EnumVariantDef, PinocchioError, PinocchioEventDef, PinocchioField, PinocchioInstruction,
+    PinocchioProgram, PinocchioState,
 };
+use crate::transformer::to_snake_case;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
 
+/// Layout of the generated idl.json
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum IdlFormat {
+    /// Anchor IDL (instructions / accounts / errors)
+    #[default]
+    Anchor,
+    /// Codama node tree rooted at a `rootNode`
+    Codama,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Idl {
     pub version: String,
     pub name: String,
     pub instructions: Vec<IdlInstruction>,
     pub accounts: Vec<IdlAccount>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub types: Vec<IdlTypeDef>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<IdlEvent>,
     pub errors: Vec<IdlError>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<IdlMetadata>,
@@ -86,6 +103,45 @@ pub struct IdlField {
     pub docs: Option<Vec<String>>,
 }
 
+/// A custom struct or enum referenced as `{ "defined": name }`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdlTypeDef {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: IdlTypeDefTy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdlTypeDefTy {
+    pub kind: String, // "struct" or "enum"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fields: Option<Vec<IdlField>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variants: Option<Vec<IdlEnumVariant>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdlEnumVariant {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fields: Option<IdlEnumFields>,
+}
+
+/// Named variant fields, or the bare types of a tuple variant
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IdlEnumFields {
+    Named(Vec<IdlField>),
+    Tuple(Vec<IdlType>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdlEvent {
+    pub name: String,
+    pub fields: Vec<IdlField>,
+    pub discriminator: Vec<u8>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IdlError {
     pub code: u32,
@@ -108,12 +164,11 @@ pub fn generate_idl(program: &PinocchioProgram, program_id: Option<&str>) -> Idl
         .map(state_to_idl_account)
         .collect();
 
-    let errors: Vec<IdlError> = program
-        .errors
-        .iter()
-        .enumerate()
-        .map(|(i, err)| error_to_idl(err, 6000 + i as u32))
-        .collect();
+    let types = defined_types_to_idl(program);
+
+    let events: Vec<IdlEvent> = program.events.iter().map(event_to_idl).collect();
+
+    let errors: Vec<IdlError> = program.errors.iter().map(error_to_idl).collect();
 
     let metadata = program_id.map(|addr| IdlMetadata {
         address: addr.to_string(),
@@ -125,23 +180,29 @@ pub fn generate_idl(program: &PinocchioProgram, program_id: Option<&str>) -> Idl
         name: to_snake_case(&program.name),
         instructions,
         accounts,
+        types,
+        events,
         errors,
         metadata,
     }
 }
 
 fn instruction_to_idl(inst: &PinocchioInstruction) -> IdlInstruction {
-    // Calculate discriminator
-    let disc = calculate_discriminator("global", &to_snake_case(&inst.name));
+    // Whatever the dispatcher matches on: Anchor's 8 bytes, or a one-byte selector
+    let disc = &inst.discriminator;
 
     let accounts: Vec<IdlAccountItem> = inst
         .accounts
         .iter()
         .map(|acc| IdlAccountItem {
-            name: to_camel_case(&acc.name),
+            name: to_camel_case(acc.original_name.as_ref().unwrap_or(&acc.name)),
             is_mut: acc.is_writable,
             is_signer: acc.is_signer,
-            docs: None,
+            docs: if acc.docs.is_empty() {
+                None
+            } else {
+                Some(acc.docs.clone())
+            },
         })
         .collect();
 
@@ -159,14 +220,142 @@ fn instruction_to_idl(inst: &PinocchioInstruction) -> IdlInstruction {
         docs: None,
         accounts,
         args,
-        discriminator: Some(disc.to_vec()),
+        discriminator: Some(disc.clone()),
+    }
+}
+
+/// Custom structs and enums reachable from instruction args, state fields and events,
+/// in the order they are first referenced
+fn defined_types_to_idl(program: &PinocchioProgram) -> Vec<IdlTypeDef> {
+    let mut pending: Vec<String> = Vec::new();
+    let type_refs = program
+        .instructions
+        .iter()
+        .flat_map(|inst| inst.args.iter().map(|a| &a.ty))
+        .chain(program.state_structs.iter().flat_map(|s| {
+            s.fields.iter().filter(|f| !f.borsh_skip).map(|f| &f.ty)
+        }))
+        .chain(program.events.iter().flat_map(|e| e.fields.iter().map(|f| &f.ty)));
+    for ty in type_refs {
+        defined_names(&rust_type_to_idl_type(&ty.replace(' ', "")), &mut pending);
+    }
+
+    let mut seen: Vec<String> = Vec::new();
+    let mut types = Vec::new();
+    let mut next = 0;
+    while next < pending.len() {
+        let name = pending[next].clone();
+        next += 1;
+        if seen.contains(&name) {
+            continue;
+        }
+        seen.push(name.clone());
+
+        if let Some(def) = program.types.iter().find(|t| t.name == name) {
+            let fields: Vec<IdlField> = def
+                .fields
+                .iter()
+                .map(|f| {
+                    let ty = rust_type_to_idl_type(&f.ty.replace(' ', ""));
+                    defined_names(&ty, &mut pending);
+                    IdlField {
+                        name: to_camel_case(&f.name),
+                        ty,
+                        docs: None,
+                    }
+                })
+                .collect();
+            types.push(IdlTypeDef {
+                name,
+                ty: IdlTypeDefTy {
+                    kind: "struct".to_string(),
+                    fields: Some(fields),
+                    variants: None,
+                },
+            });
+        } else if let Some(def) = program.enums.iter().find(|e| e.name == name) {
+            let variants = def
+                .variants
+                .iter()
+                .map(|v| enum_variant_to_idl(v, &mut pending))
+                .collect();
+            types.push(IdlTypeDef {
+                name,
+                ty: IdlTypeDefTy {
+                    kind: "enum".to_string(),
+                    fields: None,
+                    variants: Some(variants),
+                },
+            });
+        }
+    }
+
+    types
+}
+
+fn enum_variant_to_idl(variant: &EnumVariantDef, pending: &mut Vec<String>) -> IdlEnumVariant {
+    let types = variant.fields.iter().map(|f| {
+        let ty = rust_type_to_idl_type(&f.ty.replace(' ', ""));
+        defined_names(&ty, pending);
+        (f, ty)
+    });
+    let fields = if variant.fields.is_empty() {
+        None
+    } else if variant.is_tuple {
+        Some(IdlEnumFields::Tuple(types.map(|(_, ty)| ty).collect()))
+    } else {
+        Some(IdlEnumFields::Named(
+            types
+                .map(|(f, ty)| IdlField {
+                    name: to_camel_case(&f.name),
+                    ty,
+                    docs: None,
+                })
+                .collect(),
+        ))
+    };
+
+    IdlEnumVariant {
+        name: variant.name.clone(),
+        fields,
+    }
+}
+
+/// Names of the `defined` types inside `ty`
+fn defined_names(ty: &IdlType, names: &mut Vec<String>) {
+    match ty {
+        IdlType::Defined { defined } => names.push(defined.clone()),
+        IdlType::Array { array: (item, _) } => defined_names(item, names),
+        IdlType::Option { option } => defined_names(option, names),
+        IdlType::Vec { vec } => defined_names(vec, names),
+        IdlType::Simple(_) => {}
+    }
+}
+
+fn event_to_idl(event: &PinocchioEventDef) -> IdlEvent {
+    let fields: Vec<IdlField> = event
+        .fields
+        .iter()
+        .map(|f| IdlField {
+            name: to_camel_case(&f.name),
+            ty: rust_type_to_idl_type(&f.ty.replace(' ', "")),
+            docs: None,
+        })
+        .collect();
+
+    IdlEvent {
+        name: event.name.clone(),
+        fields,
+        discriminator: event.discriminator.clone(),
     }
 }
 
 fn state_to_idl_account(state: &PinocchioState) -> IdlAccount {
+    // Clients only see the serialized layout
     let fields: Vec<IdlField> = state
         .fields
         .iter()
+        .filter(|f| !f.borsh_skip)
         .map(|f: &PinocchioField| IdlField {
             name: to_camel_case(&f.name),
             ty: rust_type_to_idl_type(&f.ty),
@@ -183,14 +372,251 @@ fn state_to_idl_account(state: &PinocchioState) -> IdlAccount {
     }
 }
 
-fn error_to_idl(err: &PinocchioError, code: u32) -> IdlError {
+fn error_to_idl(err: &PinocchioError) -> IdlError {
     IdlError {
-        code,
+        code: err.code,
         name: err.name.clone(),
         msg: Some(err.msg.clone()),
     }
 }
 
+/// Generate a Codama root node from a PinocchioProgram. Instruction discriminators
+/// become a leading `discriminator` argument, as Codama's Anchor importer does.
+pub fn generate_codama_idl(
+    program: &PinocchioProgram,
+    name: &str,
+    program_id: Option<&str>,
+) -> Value {
+    let instructions: Vec<Value> = program
+        .instructions
+        .iter()
+        .map(instruction_to_codama)
+        .collect();
+
+    let accounts: Vec<Value> = program
+        .state_structs
+        .iter()
+        .map(state_to_codama_account)
+        .collect();
+
+    // The same structs and enums the Anchor IDL lists under `types`
+    let defined_types: Vec<Value> = defined_types_to_idl(program)
+        .iter()
+        .map(type_def_to_codama)
+        .collect();
+
+    let errors: Vec<Value> = program
+        .errors
+        .iter()
+        .map(|err| {
+            json!({
+                "kind": "errorNode",
+                "name": to_camel_case(&to_snake_case(&err.name)),
+                "code": err.code,
+                "message": err.msg,
+                "docs": [],
+            })
+        })
+        .collect();
+
+    json!({
+        "kind": "rootNode",
+        "standard": "codama",
+        "version": "1.0.0",
+        "program": {
+            "kind": "programNode",
+            "name": to_camel_case(name),
+            "publicKey": program_id.unwrap_or_default(),
+            "version": "0.1.0",
+            "origin": "anchor",
+            "docs": [],
+            "accounts": accounts,
+            "instructions": instructions,
+            "definedTypes": defined_types,
+            "pdas": [],
+            "errors": errors,
+        },
+        "additionalPrograms": [],
+    })
+}
+
+fn instruction_to_codama(inst: &PinocchioInstruction) -> Value {
+    let disc = &inst.discriminator;
+
+    let accounts: Vec<Value> = inst
+        .accounts
+        .iter()
+        .map(|acc| {
+            json!({
+                "kind": "instructionAccountNode",
+                "name": to_camel_case(acc.original_name.as_ref().unwrap_or(&acc.name)),
+                "isWritable": acc.is_writable,
+                "isSigner": acc.is_signer,
+                "isOptional": acc.is_optional,
+                "docs": acc.docs,
+            })
+        })
+        .collect();
+
+    let mut arguments = vec![discriminator_field("instructionArgumentNode", disc)];
+    arguments.extend(inst.args.iter().map(|arg| {
+        json!({
+            "kind": "instructionArgumentNode",
+            "name": to_camel_case(&arg.name),
+            "type": idl_type_to_codama(&rust_type_to_idl_type(&arg.ty)),
+            "docs": [],
+        })
+    }));
+
+    json!({
+        "kind": "instructionNode",
+        "name": to_camel_case(&inst.name),
+        "docs": [],
+        "optionalAccountStrategy": "programId",
+        "accounts": accounts,
+        "arguments": arguments,
+        "discriminators": [discriminator_node()],
+    })
+}
+
+fn state_to_codama_account(state: &PinocchioState) -> Value {
+    let mut fields = vec![discriminator_field("structFieldTypeNode", &state.discriminator)];
+    fields.extend(state.fields.iter().map(|f| {
+        json!({
+            "kind": "structFieldTypeNode",
+            "name": to_camel_case(&f.name),
+            "type": idl_type_to_codama(&rust_type_to_idl_type(&f.ty)),
+            "docs": [],
+        })
+    }));
+
+    json!({
+        "kind": "accountNode",
+        "name": to_camel_case(&to_snake_case(&state.name)),
+        "docs": [],
+        "data": {
+            "kind": "structTypeNode",
+            "fields": fields,
+        },
+        "discriminators": [discriminator_node()],
+    })
+}
+
+fn type_def_to_codama(def: &IdlTypeDef) -> Value {
+    let ty = match &def.ty.variants {
+        Some(variants) => {
+            let variants: Vec<Value> = variants
+                .iter()
+                .map(|v| {
+                    let name = to_camel_case(&to_snake_case(&v.name));
+                    match &v.fields {
+                        None => json!({ "kind": "enumEmptyVariantTypeNode", "name": name }),
+                        Some(IdlEnumFields::Named(fields)) => json!({
+                            "kind": "enumStructVariantTypeNode",
+                            "name": name,
+                            "struct": struct_type_to_codama(fields),
+                        }),
+                        Some(IdlEnumFields::Tuple(items)) => json!({
+                            "kind": "enumTupleVariantTypeNode",
+                            "name": name,
+                            "tuple": {
+                                "kind": "tupleTypeNode",
+                                "items": items.iter().map(idl_type_to_codama).collect::<Vec<_>>(),
+                            },
+                        }),
+                    }
+                })
+                .collect();
+            json!({ "kind": "enumTypeNode", "variants": variants, "size": number_node("u8") })
+        }
+        None => struct_type_to_codama(def.ty.fields.as_deref().unwrap_or_default()),
+    };
+
+    json!({
+        "kind": "definedTypeNode",
+        "name": to_camel_case(&to_snake_case(&def.name)),
+        "docs": [],
+        "type": ty,
+    })
+}
+
+fn struct_type_to_codama(fields: &[IdlField]) -> Value {
+    let fields: Vec<Value> = fields
+        .iter()
+        .map(|f| {
+            json!({
+                "kind": "structFieldTypeNode",
+                "name": f.name,
+                "type": idl_type_to_codama(&f.ty),
+                "docs": [],
+            })
+        })
+        .collect();
+    json!({ "kind": "structTypeNode", "fields": fields })
+}
+
+/// The discriminator as a leading `discriminator` argument or field (`kind`)
+/// whose value is filled in by clients
+fn discriminator_field(kind: &str, disc: &[u8]) -> Value {
+    let hex: String = disc.iter().map(|b| format!("{:02x}", b)).collect();
+    json!({
+        "kind": kind,
+        "name": "discriminator",
+        "type": {
+            "kind": "fixedSizeTypeNode",
+            "size": disc.len(),
+            "type": { "kind": "bytesTypeNode" },
+        },
+        "defaultValue": { "kind": "bytesValueNode", "data": hex, "encoding": "base16" },
+        "defaultValueStrategy": "omitted",
+        "docs": [],
+    })
+}
+
+fn discriminator_node() -> Value {
+    json!({ "kind": "fieldDiscriminatorNode", "name": "discriminator", "offset": 0 })
+}
+
+fn number_node(format: &str) -> Value {
+    json!({ "kind": "numberTypeNode", "format": format, "endian": "le" })
+}
+
+/// Map an Anchor IDL type onto the equivalent Codama type node (Borsh layout)
+fn idl_type_to_codama(ty: &IdlType) -> Value {
+    match ty {
+        IdlType::Simple(s) => match s.as_str() {
+            "bool" => json!({ "kind": "booleanTypeNode", "size": number_node("u8") }),
+            "publicKey" => json!({ "kind": "publicKeyTypeNode" }),
+            "string" => json!({
+                "kind": "sizePrefixTypeNode",
+                "type": { "kind": "stringTypeNode", "encoding": "utf8" },
+                "prefix": number_node("u32"),
+            }),
+            format => number_node(format),
+        },
+        IdlType::Array { array: (item, n) } => json!({
+            "kind": "arrayTypeNode",
+            "item": idl_type_to_codama(item),
+            "count": { "kind": "fixedCountNode", "value": n },
+        }),
+        IdlType::Option { option } => json!({
+            "kind": "optionTypeNode",
+            "fixed": false,
+            "item": idl_type_to_codama(option),
+            "prefix": number_node("u8"),
+        }),
+        IdlType::Vec { vec } => json!({
+            "kind": "arrayTypeNode",
+            "item": idl_type_to_codama(vec),
+            "count": { "kind": "prefixedCountNode", "prefix": number_node("u32") },
+        }),
+        IdlType::Defined { defined } => json!({
+            "kind": "definedTypeLinkNode",
+            "name": to_camel_case(&to_snake_case(defined)),
+        }),
+    }
+}
+
 fn rust_type_to_idl_type(ty: &str) -> IdlType {
     let ty = ty.trim();
 
@@ -268,21 +694,6 @@ fn calculate_discriminator(namespace: &str, name: &str) -> [u8; 8] {
     disc
 }
 
-fn to_snake_case(s: &str) -> String {
-    let mut result = String::new();
-    for (i, c) in s.chars().enumerate() {
-        if c.is_uppercase() {
-            if i > 0 {
-                result.push('_');
-            }
-            result.push(c.to_lowercase().next().unwrap());
-        } else {
-            result.push(c);
-        }
-    }
-    result
-}
-
 fn to_camel_case(s: &str) -> String {
     let mut result = String::new();
     let mut capitalize_next = false;
@@ -321,6 +732,14 @@ pub fn verify_idl(
 ) -> anyhow::Result<IdlVerification> {
     let original_content = std::fs::read_to_string(original_path)?;
     let original: serde_json::Value = serde_json::from_str(&original_content)?;
+    // Anchor 0.30+ IDLs carry `address` and `metadata.spec`, spell account flags
+    // `writable` / `signer` and keep account layouts in `types`
+    let is_new_format = original.get("address").is_some() || original.pointer("/metadata/spec").is_some();
+    let (mut_flag, signer_flag) = if is_new_format {
+        ("writable", "signer")
+    } else {
+        ("isMut", "isSigner")
+    };
 
     let mut verification = IdlVerification {
         is_compatible: true,
@@ -353,7 +772,8 @@ pub fn verify_idl(
             if let Some(gen_inst) = generated.instructions.get(i) {
                 let mut matches = true;
 
-                if gen_inst.name != orig_name {
+                // 0.30+ names instructions in snake_case
+                if gen_inst.name != to_camel_case(orig_name) {
                     verification.issues.push(format!(
                         "Instruction {}: name mismatch '{}' vs '{}'",
                         i, gen_inst.name, orig_name
@@ -381,6 +801,53 @@ pub fn verify_idl(
                     matches = false;
                 }
 
+                // Matching counts can still hide reordered or retyped args
+                let orig_arg_list = orig_inst.get("args").and_then(|v| v.as_array());
+                for (gen_arg, orig_arg) in gen_inst.args.iter().zip(orig_arg_list.into_iter().flatten()) {
+                    let orig_arg_name = orig_arg.get("name").and_then(|v| v.as_str()).unwrap_or("");
+                    let gen_ty = normalize_idl_type(&serde_json::to_value(&gen_arg.ty)?);
+                    let orig_ty = normalize_idl_type(orig_arg.get("type").unwrap_or(&Value::Null));
+                    if gen_arg.name != to_camel_case(orig_arg_name) || gen_ty != orig_ty {
+                        verification.issues.push(format!(
+                            "Instruction '{}': arg mismatch '{}: {}' vs '{}: {}'",
+                            orig_name, gen_arg.name, gen_ty, orig_arg_name, orig_ty
+                        ));
+                        matches = false;
+                    }
+                }
+
+                let orig_account_list = orig_inst.get("accounts").and_then(|v| v.as_array());
+                for (gen_acc, orig_acc) in gen_inst.accounts.iter().zip(orig_account_list.into_iter().flatten()) {
+                    // Composite account groups carry no flags of their own
+                    if orig_acc.get("accounts").is_some() {
+                        continue;
+                    }
+                    let orig_acc_name = orig_acc.get("name").and_then(|v| v.as_str()).unwrap_or("");
+                    // 0.30+ omits false flags
+                    let flag = |name: &str| orig_acc.get(name).and_then(|v| v.as_bool()).unwrap_or(false);
+                    let (orig_mut, orig_signer) = (flag(mut_flag), flag(signer_flag));
+                    if gen_acc.is_mut != orig_mut || gen_acc.is_signer != orig_signer {
+                        verification.issues.push(format!(
+                            "Instruction '{}': account '{}' is mut {} / signer {} vs mut {} / signer {}",
+                            orig_name, orig_acc_name, gen_acc.is_mut, gen_acc.is_signer, orig_mut, orig_signer
+                        ));
+                        matches = false;
+                    }
+                }
+
+                let orig_discriminator: Option<Vec<u8>> = orig_inst
+                    .get("discriminator")
+                    .and_then(|v| serde_json::from_value(v.clone()).ok());
+                if let (Some(gen_disc), Some(orig_disc)) = (&gen_inst.discriminator, &orig_discriminator) {
+                    if gen_disc != orig_disc {
+                        verification.issues.push(format!(
+                            "Instruction '{}': discriminator mismatch {:?} vs {:?}",
+                            orig_name, gen_disc, orig_disc
+                        ));
+                        matches = false;
+                    }
+                }
+
                 if matches {
                     verification.matching_instructions += 1;
                 } else {
@@ -402,7 +869,13 @@ pub fn verify_idl(
 
         for (i, orig_acc) in orig_accounts.iter().enumerate() {
             let orig_name = orig_acc.get("name").and_then(|v| v.as_str()).unwrap_or("");
-            let orig_fields = orig_acc
+            let orig_layout = original
+                .get("types")
+                .and_then(|v| v.as_array())
+                .and_then(|types| types.iter().find(|t| t.get("name") == orig_acc.get("name")))
+                .filter(|_| is_new_format && orig_acc.get("type").is_none())
+                .unwrap_or(orig_acc);
+            let orig_fields = orig_layout
                 .get("type")
                 .and_then(|t| t.get("fields"))
                 .and_then(|f| f.as_array())
@@ -430,6 +903,18 @@ pub fn verify_idl(
                     matches = false;
                 }
 
+                let orig_discriminator: Option<Vec<u8>> = orig_acc
+                    .get("discriminator")
+                    .and_then(|v| serde_json::from_value(v.clone()).ok());
+                let gen_discriminator = calculate_discriminator("account", &gen_acc.name);
+                if orig_discriminator.is_some_and(|disc| disc != gen_discriminator) {
+                    verification.issues.push(format!(
+                        "Account '{}': discriminator mismatch {:?}",
+                        orig_name, gen_discriminator
+                    ));
+                    matches = false;
+                }
+
                 if matches {
                     verification.matching_accounts += 1;
                 } else {
@@ -464,6 +949,25 @@ pub fn verify_idl(
     Ok(verification)
 }
 
+/// An IDL type in one spelling across Anchor IDL versions: `pubkey` as `publicKey`,
+/// `{ "defined": { "name": X } }` as `{ "defined": X }`
+fn normalize_idl_type(ty: &Value) -> Value {
+    match ty {
+        Value::String(s) if s == "pubkey" => json!("publicKey"),
+        Value::Object(map) => {
+            if let Some(name) = map.get("defined").and_then(|d| d.get("name")) {
+                return json!({ "defined": name });
+            }
+            map.iter()
+                .map(|(key, value)| (key.clone(), normalize_idl_type(value)))
+                .collect::<serde_json::Map<_, _>>()
+                .into()
+        }
+        Value::Array(items) => Value::Array(items.iter().map(normalize_idl_type).collect()),
+        _ => ty.clone(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -472,7 +976,9 @@ mod tests {
     fn test_to_snake_case() {
         assert_eq!(to_snake_case("CreatePool"), "create_pool");
         assert_eq!(to_snake_case("addLiquidity"), "add_liquidity");
-        assert_eq!(to_snake_case("IDL"), "i_d_l");
+        assert_eq!(to_snake_case("IDL"), "idl");
+        assert_eq!(to_snake_case("SwapAMM"), "swap_amm");
+        assert_eq!(to_snake_case("CreatePoolV2"), "create_pool_v2");
     }
 
     #[test]
@@ -519,4 +1025,531 @@ mod tests {
         let disc2 = calculate_discriminator("global", "add_liquidity");
         assert_ne!(disc, disc2);
     }
+
+    #[test]
+    fn test_idl_discriminators_match_anchor_reference() {
+        // Instruction names mapped to discriminators precomputed with Anchor's algorithm,
+        // checked here through parsing and transforming a handler of each name
+        let fixture = include_str!("../tests/fixtures/anchor_discriminators.json");
+        let reference: std::collections::BTreeMap<String, Vec<u8>> =
+            serde_json::from_str(fixture).unwrap();
+        let handlers: String = reference
+            .keys()
+            .map(|name| format!("pub fn {}(ctx: Context<Noop>) -> Result<()> {{ Ok(()) }}\n", name))
+            .collect();
+        let source = format!(
+            "use anchor_lang::prelude::*;\n\
+             #[program]\npub mod reference {{\nuse super::*;\n{}}}\n\
+             #[derive(Accounts)]\npub struct Noop<'info> {{\npub payer: Signer<'info>,\n}}\n",
+            handlers
+        );
+        let anchor = crate::parser::parse_anchor_source(&source).unwrap();
+        let analysis = crate::analyzer::analyze(&anchor).unwrap();
+        let program =
+            crate::transformer::transform(&anchor, &analysis, &Default::default()).unwrap();
+        let idl = generate_idl(&program, None);
+
+        assert_eq!(idl.instructions.len(), reference.len());
+        for ((name, expected), inst) in reference.iter().zip(&idl.instructions) {
+            assert_eq!(inst.discriminator.as_ref(), Some(expected), "discriminator for {}", name);
+        }
+    }
+
+    #[test]
+    fn test_account_docs_from_doc_comments() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vault {
+                use super::*;
+                pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Deposit<'info> {
+                /// The vault owner
+                /// Must sign the deposit
+                #[account(mut)]
+                pub owner: Signer<'info>,
+                pub system_program: Program<'info, System>,
+            }
+        "#;
+        let anchor = crate::parser::parse_anchor_source(source).unwrap();
+        let analysis = crate::analyzer::analyze(&anchor).unwrap();
+        let program =
+            crate::transformer::transform(&anchor, &analysis, &Default::default()).unwrap();
+        let idl = generate_idl(&program, None);
+
+        let accounts = &idl.instructions[0].accounts;
+        assert_eq!(
+            accounts[0].docs,
+            Some(vec![
+                "The vault owner".to_string(),
+                "Must sign the deposit".to_string()
+            ])
+        );
+        assert_eq!(accounts[1].docs, None);
+    }
+
+    #[test]
+    fn test_types_section_for_defined_args() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod swapper {
+                use super::*;
+                pub fn swap(ctx: Context<Swap>, params: SwapParams) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Swap<'info> {
+                pub user: Signer<'info>,
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub struct SwapParams {
+                pub amount_in: u64,
+                pub side: Side,
+                pub route: Option<Route>,
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub struct Route {
+                pub hops: u8,
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub enum Side {
+                Buy,
+                Limit(u64),
+                Stop { trigger: u64 },
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub struct Unused {
+                pub flag: bool,
+            }
+        "#;
+        let anchor = crate::parser::parse_anchor_source(source).unwrap();
+        let analysis = crate::analyzer::analyze(&anchor).unwrap();
+        let program =
+            crate::transformer::transform(&anchor, &analysis, &Default::default()).unwrap();
+        let idl = serde_json::to_value(generate_idl(&program, None)).unwrap();
+
+        assert_eq!(
+            idl["types"],
+            json!([
+                {
+                    "name": "SwapParams",
+                    "type": {
+                        "kind": "struct",
+                        "fields": [
+                            { "name": "amountIn", "type": "u64" },
+                            { "name": "side", "type": { "defined": "Side" } },
+                            { "name": "route", "type": { "option": { "defined": "Route" } } },
+                        ],
+                    },
+                },
+                {
+                    "name": "Side",
+                    "type": {
+                        "kind": "enum",
+                        "variants": [
+                            { "name": "Buy" },
+                            { "name": "Limit", "fields": ["u64"] },
+                            { "name": "Stop", "fields": [{ "name": "trigger", "type": "u64" }] },
+                        ],
+                    },
+                },
+                {
+                    "name": "Route",
+                    "type": { "kind": "struct", "fields": [{ "name": "hops", "type": "u8" }] },
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_events_section() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vault {
+                use super::*;
+                pub fn deposit(ctx: Context<Deposit>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Deposit<'info> {
+                pub owner: Signer<'info>,
+            }
+
+            #[event]
+            pub struct Deposited {
+                pub owner: Pubkey,
+                pub total_amount: u64,
+                pub memo: Option<String>,
+            }
+        "#;
+        let anchor = crate::parser::parse_anchor_source(source).unwrap();
+        let analysis = crate::analyzer::analyze(&anchor).unwrap();
+        let program =
+            crate::transformer::transform(&anchor, &analysis, &Default::default()).unwrap();
+        let idl = serde_json::to_value(generate_idl(&program, None)).unwrap();
+
+        assert_eq!(
+            idl["events"],
+            json!([{
+                "name": "Deposited",
+                "fields": [
+                    { "name": "owner", "type": "publicKey" },
+                    { "name": "totalAmount", "type": "u64" },
+                    { "name": "memo", "type": { "option": "string" } },
+                ],
+                "discriminator": calculate_discriminator("event", "Deposited"),
+            }])
+        );
+
+        // Programs without events keep the section out of the IDL
+        let program = crate::transformer::transform(
+            &crate::parser::parse_anchor_source(&source.replace("#[event]", "")).unwrap(),
+            &analysis,
+            &Default::default(),
+        )
+        .unwrap();
+        let idl = serde_json::to_value(generate_idl(&program, None)).unwrap();
+        assert!(idl.get("events").is_none());
+    }
+
+    #[test]
+    fn test_codama_root_node() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vault {
+                use super::*;
+                pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
+                    Ok(())
+                }
+                pub fn deposit(ctx: Context<Deposit>, amount: u64, memo: Option<String>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Initialize<'info> {
+                #[account(init, payer = owner, space = 8 + 8)]
+                pub vault_state: Account<'info, VaultState>,
+                #[account(mut)]
+                pub owner: Signer<'info>,
+                pub system_program: Program<'info, System>,
+            }
+
+            #[derive(Accounts)]
+            pub struct Deposit<'info> {
+                #[account(mut)]
+                pub vault_state: Account<'info, VaultState>,
+                pub owner: Signer<'info>,
+            }
+
+            #[account]
+            pub struct VaultState {
+                pub balance: u64,
+            }
+        "#;
+        let anchor = crate::parser::parse_anchor_source(source).unwrap();
+        let analysis = crate::analyzer::analyze(&anchor).unwrap();
+        let program =
+            crate::transformer::transform(&anchor, &analysis, &Default::default()).unwrap();
+        let root = generate_codama_idl(&program, "vault", Some("Vault111"));
+
+        assert_eq!(root["kind"], "rootNode");
+        assert_eq!(root["program"]["kind"], "programNode");
+        assert_eq!(root["program"]["publicKey"], "Vault111");
+
+        let instructions = root["program"]["instructions"].as_array().unwrap();
+        let names: Vec<_> = instructions.iter().map(|i| i["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["initialize", "deposit"]);
+        assert!(instructions.iter().all(|i| i["kind"] == "instructionNode"));
+
+        // initialize = [175, 175, 109, 31, 13, 152, 155, 237]
+        let disc = &instructions[0]["arguments"][0];
+        assert_eq!(disc["name"], "discriminator");
+        assert_eq!(disc["defaultValue"]["data"], "afaf6d1f0d989bed");
+
+        let accounts = &instructions[0]["accounts"];
+        assert_eq!(accounts[0]["name"], "vaultState");
+        assert_eq!(accounts[1]["isSigner"], true);
+
+        let args = &instructions[1]["arguments"];
+        assert_eq!(args[1]["name"], "amount");
+        assert_eq!(args[1]["type"]["format"], "u64");
+        assert_eq!(args[2]["type"]["kind"], "optionTypeNode");
+        assert_eq!(args[2]["type"]["item"]["type"]["kind"], "stringTypeNode");
+
+        let account = &root["program"]["accounts"][0];
+        assert_eq!(account["kind"], "accountNode");
+        assert_eq!(account["name"], "vaultState");
+        assert_eq!(account["data"]["fields"][1]["name"], "balance");
+        assert_eq!(root["program"]["definedTypes"], json!([]));
+    }
+
+    #[test]
+    fn test_codama_defined_types() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod market {
+                use super::*;
+                pub fn order(ctx: Context<Order>, params: OrderParams) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Order<'info> {
+                pub user: Signer<'info>,
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub struct OrderParams {
+                pub price_limit: u64,
+                pub side: Side,
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub enum Side {
+                Bid,
+                Ask { size: u32 },
+                Cross(u8, u8),
+            }
+        "#;
+        let anchor = crate::parser::parse_anchor_source(source).unwrap();
+        let analysis = crate::analyzer::analyze(&anchor).unwrap();
+        let program =
+            crate::transformer::transform(&anchor, &analysis, &Default::default()).unwrap();
+        let root = generate_codama_idl(&program, "market", None);
+
+        let types = root["program"]["definedTypes"].as_array().unwrap();
+        let names: Vec<_> = types.iter().map(|t| t["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["orderParams", "side"]);
+        assert!(types.iter().all(|t| t["kind"] == "definedTypeNode"));
+
+        let params = &types[0]["type"];
+        assert_eq!(params["kind"], "structTypeNode");
+        assert_eq!(params["fields"][0]["name"], "priceLimit");
+        assert_eq!(params["fields"][0]["type"]["format"], "u64");
+        assert_eq!(params["fields"][1]["type"], json!({ "kind": "definedTypeLinkNode", "name": "side" }));
+
+        let side = &types[1]["type"];
+        assert_eq!(side["kind"], "enumTypeNode");
+        assert_eq!(side["variants"][0], json!({ "kind": "enumEmptyVariantTypeNode", "name": "bid" }));
+        assert_eq!(side["variants"][1]["struct"]["fields"][0]["name"], "size");
+        assert_eq!(side["variants"][2]["tuple"]["items"][1]["format"], "u8");
+    }
+    #[test]
+    fn test_error_discriminants_match_idl_codes() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vault {
+                use super::*;
+                pub fn deposit(ctx: Context<Deposit>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Deposit<'info> {
+                pub owner: Signer<'info>,
+            }
+
+            #[error_code]
+            pub enum VaultError {
+                #[msg("Too small")]
+                TooSmall,
+                #[msg("Too large")]
+                TooLarge = 10,
+                #[msg("Closed")]
+                Closed,
+            }
+        "#;
+        let anchor = crate::parser::parse_anchor_source(source).unwrap();
+        let analysis = crate::analyzer::analyze(&anchor).unwrap();
+        let program =
+            crate::transformer::transform(&anchor, &analysis, &Default::default()).unwrap();
+        let idl = generate_idl(&program, None);
+
+        let codes: Vec<(&str, u32)> = idl.errors.iter().map(|e| (e.name.as_str(), e.code)).collect();
+        assert_eq!(codes, [("TooSmall", 6000), ("TooLarge", 6010), ("Closed", 6011)]);
+
+        // `Error::X as u32` is what the generated From<Error> for ProgramError returns
+        let generated = crate::emitter::emit_with_extras(&program, None, None).unwrap();
+        let error_rs = &generated.files[std::path::Path::new("src/error.rs")];
+        assert!(error_rs.contains("ProgramError::Custom(e as u32)"));
+        for (name, code) in codes {
+            assert!(error_rs.contains(&format!("    {} = {},\n", name, code)));
+        }
+    }
+
+    #[test]
+    fn test_verify_idl_compares_arg_types_and_flags() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vault {
+                use super::*;
+                pub fn deposit(ctx: Context<Deposit>, amount: u64, owner: Pubkey) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Deposit<'info> {
+                #[account(mut)]
+                pub vault: Account<'info, Vault>,
+                pub payer: Signer<'info>,
+            }
+
+            #[account]
+            pub struct Vault {
+                pub balance: u64,
+            }
+        "#;
+        let anchor = crate::parser::parse_anchor_source(source).unwrap();
+        let analysis = crate::analyzer::analyze(&anchor).unwrap();
+        let program =
+            crate::transformer::transform(&anchor, &analysis, &Default::default()).unwrap();
+        let idl = generate_idl(&program, None);
+        let dir = tempfile::TempDir::new().unwrap();
+        let verify = |original: &Value| {
+            let path = dir.path().join("original.json");
+            std::fs::write(&path, original.to_string()).unwrap();
+            verify_idl(&idl, &path).unwrap()
+        };
+
+        // Anchor 0.30 spelling of the same interface
+        let mut original = serde_json::to_value(&idl).unwrap();
+        original["metadata"] = json!({ "spec": "0.1.0" });
+        original["instructions"][0]["args"][1]["type"] = json!("pubkey");
+        original["instructions"][0]["accounts"] = json!([
+            { "name": "vault", "writable": true },
+            { "name": "payer", "signer": true },
+        ]);
+        let verification = verify(&original);
+        assert!(verification.is_compatible, "{:?}", verification.issues);
+
+        // Same counts, but the args are swapped
+        let mut swapped = original.clone();
+        swapped["instructions"][0]["args"] = json!([
+            { "name": "owner", "type": "publicKey" },
+            { "name": "amount", "type": "u64" },
+        ]);
+        let verification = verify(&swapped);
+        assert!(!verification.is_compatible);
+        assert_eq!(verification.issues.len(), 2);
+        assert!(verification.issues[0].contains("arg mismatch 'amount: \"u64\"' vs 'owner: \"publicKey\"'"));
+
+        let mut read_only = original.clone();
+        read_only["instructions"][0]["accounts"][0]["writable"] = json!(false);
+        let verification = verify(&read_only);
+        assert!(!verification.is_compatible);
+        assert!(verification.issues[0].contains("account 'vault' is mut true / signer false vs mut false / signer false"));
+
+        let mut rediscriminated = original.clone();
+        rediscriminated["instructions"][0]["discriminator"] = json!([0, 0, 0, 0, 0, 0, 0, 1]);
+        let verification = verify(&rediscriminated);
+        assert!(!verification.is_compatible);
+        assert!(verification.issues[0].contains("discriminator mismatch"));
+        assert_eq!(verification.matching_instructions, 0);
+    }
+
+    #[test]
+    fn test_verify_idl_reads_anchor_030_layout() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vault {
+                use super::*;
+                pub fn set_limit(ctx: Context<SetLimit>, new_limit: u64) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct SetLimit<'info> {
+                #[account(mut)]
+                pub vault: Account<'info, Vault>,
+                pub authority: Signer<'info>,
+            }
+
+            #[account]
+            pub struct Vault {
+                pub authority: Pubkey,
+                pub limit: u64,
+            }
+        "#;
+        let anchor = crate::parser::parse_anchor_source(source).unwrap();
+        let analysis = crate::analyzer::analyze(&anchor).unwrap();
+        let program =
+            crate::transformer::transform(&anchor, &analysis, &Default::default()).unwrap();
+        let idl = generate_idl(&program, None);
+
+        let original = json!({
+            "address": "Vault11111111111111111111111111111111111111",
+            "metadata": { "name": "vault", "version": "0.1.0", "spec": "0.1.0" },
+            "instructions": [{
+                "name": "set_limit",
+                "discriminator": calculate_discriminator("global", "set_limit"),
+                "accounts": [
+                    { "name": "vault", "writable": true },
+                    { "name": "authority", "signer": true },
+                ],
+                "args": [{ "name": "new_limit", "type": "u64" }],
+            }],
+            "accounts": [{ "name": "Vault", "discriminator": calculate_discriminator("account", "Vault") }],
+            "types": [{
+                "name": "Vault",
+                "type": {
+                    "kind": "struct",
+                    "fields": [
+                        { "name": "authority", "type": "pubkey" },
+                        { "name": "limit", "type": "u64" },
+                    ],
+                },
+            }],
+            "errors": [],
+        });
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("original.json");
+        std::fs::write(&path, original.to_string()).unwrap();
+
+        let verification = verify_idl(&idl, &path).unwrap();
+        assert!(verification.is_compatible, "{:?}", verification.issues);
+        assert_eq!(verification.matching_instructions, 1);
+        assert_eq!(verification.matching_accounts, 1);
+
+        // Without the version markers the same file reads as a legacy IDL with no flags set
+        let mut legacy = original.clone();
+        legacy.as_object_mut().unwrap().remove("address");
+        legacy["metadata"].as_object_mut().unwrap().remove("spec");
+        std::fs::write(&path, legacy.to_string()).unwrap();
+        let verification = verify_idl(&idl, &path).unwrap();
+        assert!(!verification.is_compatible);
+        assert!(verification.issues.iter().any(|i| i.contains("Account 'Vault': field count mismatch 2 vs 0")));
+    }
 }
@@ -13,7 +13,15 @@ pub struct AnchorProgram {
     pub instructions: Vec<AnchorInstruction>,
     pub account_structs: Vec<AnchorAccountStruct>,
     pub state_structs: Vec<AnchorStateStruct>,
+    #[serde(default)]
+    pub types: Vec<AnchorStructDef>, // Plain structs, e.g. instruction params
+    #[serde(default)]
+    pub enums: Vec<AnchorEnumDef>, // Plain (non-error) enums, e.g. instruction actions
+    #[serde(default)]
+    pub events: Vec<AnchorStructDef>, // #[event] structs logged with emit!
     pub errors: Vec<AnchorError>,
+    #[serde(default)]
+    pub error_enum: Option<String>, // Name of the #[error_code] enum, e.g. ErrorCode
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,6 +30,17 @@ pub struct AnchorInstruction {
     pub accounts_struct: String,
     pub args: Vec<InstructionArg>,
     pub body: String, // Raw function body
+    #[serde(default)]
+    pub access_control: Vec<AccessControl>, // From #[access_control(...)], run before the body
+}
+
+/// One check from `#[access_control(check(&ctx, ..))]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessControl {
+    pub name: String,
+    pub ctx_param: Option<String>, // The check's own name for the Context parameter
+    pub bindings: Vec<(String, String)>, // (parameter, argument) for the other arguments
+    pub body: Option<String>, // None when the check isn't defined in the parsed source
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,6 +54,8 @@ pub struct AnchorAccountStruct {
     pub name: String,
     pub instruction_args: Vec<InstructionArg>, // From #[instruction(...)]
     pub accounts: Vec<AnchorAccount>,
+    #[serde(default)]
+    pub composed: Vec<String>, // Fields holding nested Accounts structs, whose accounts are inlined
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,6 +63,8 @@ pub struct AnchorAccount {
     pub name: String,
     pub ty: AccountType,
     pub constraints: Vec<AccountConstraint>,
+    #[serde(default)]
+    pub docs: Vec<String>, // From `///` comments on the field
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -54,7 +77,9 @@ pub enum AccountType {
     Sysvar { inner: String },        // Sysvar<'info, T>
     TokenAccount,                    // anchor_spl::token::TokenAccount
     Mint,                            // anchor_spl::token::Mint
+    AccountLoader { inner: String }, // AccountLoader<'info, T> over a zero-copy T
     Box { inner: Box<AccountType> }, // Box<Account<...>>
+    Optional { inner: Box<AccountType> }, // Option<Account<...>>
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -72,6 +97,11 @@ pub enum AccountConstraint {
     Bump(Option<String>), // None = canonical bump, Some(x) = x.bump
     TokenMint(String),
     TokenAuthority(String),
+    AssociatedToken {
+        mint: String,
+        authority: String,
+        token_program: Option<String>, // associated_token::token_program, for Token-2022
+    },
     MintDecimals(u8),
     MintAuthority(String),
     Constraint {
@@ -82,8 +112,41 @@ pub enum AccountConstraint {
         field: String,
         error: Option<String>,
     },
-    Address(String),
+    Address {
+        address: String,
+        error: Option<String>,
+    },
+    Owner {
+        owner: String, // <program id path or account . key ()>
+        error: Option<String>,
+    },
     Close(String),
+    Realloc {
+        space: String,
+        payer: String,
+        zero: bool,
+    },
+}
+
+/// A plain `#[derive(AnchorSerialize, AnchorDeserialize)]` struct, e.g. instruction params
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnchorStructDef {
+    pub name: String,
+    pub fields: Vec<InstructionArg>,
+}
+
+/// A plain Borsh enum, encoded as a one-byte variant tag followed by the variant's fields
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnchorEnumDef {
+    pub name: String,
+    pub variants: Vec<EnumVariantDef>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnumVariantDef {
+    pub name: String,
+    pub fields: Vec<InstructionArg>, // Tuple fields are named "0", "1", ...
+    pub is_tuple: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -105,13 +168,17 @@ pub struct AnchorStateStruct {
 pub struct StateField {
     pub name: String,
     pub ty: String,
-    pub max_len: Option<usize>, // For String fields with #[max_len(N)]
+    pub max_len: Option<usize>, // For String and Vec fields with #[max_len(N)]
+    #[serde(default)]
+    pub inner_max_lens: Vec<usize>, // Remaining #[max_len(4, 32)] bounds, for nested collections
 
     // NEW: Track if this is a Vec
     #[serde(default)]
     pub is_vec: bool,
     #[serde(default)]
     pub vec_info: Option<VecField>,
+    #[serde(default)]
+    pub borsh_skip: bool, // #[borsh(skip)]: kept in memory, left out of the serialized layout
 }
 
 /// Represents a Vec field that needs transformation
@@ -184,7 +251,15 @@ pub struct PinocchioProgram {
     pub config: PinocchioConfig,
     pub instructions: Vec<PinocchioInstruction>,
     pub state_structs: Vec<PinocchioState>,
+    #[serde(default)]
+    pub types: Vec<PinocchioStructDef>,
+    #[serde(default)]
+    pub enums: Vec<PinocchioEnumDef>,
+    #[serde(default)]
+    pub events: Vec<PinocchioEventDef>,
     pub errors: Vec<PinocchioError>,
+    #[serde(default)]
+    pub error_enum: Option<String>, // The source's error enum name, emitted as Error
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -192,6 +267,24 @@ pub struct PinocchioConfig {
     pub no_alloc: bool,
     pub lazy_entrypoint: bool,
     pub anchor_compat: bool, // Use 8-byte discriminators like Anchor
+    #[serde(default)]
+    pub arg_structs: bool, // Parse args into a per-instruction struct instead of inline
+    #[serde(default)]
+    pub cold_instructions: Vec<String>, // Handlers marked #[inline(never)] and #[cold]
+    #[serde(default)]
+    pub check_program_id: bool, // Reject calls whose program_id is not ID
+    #[serde(default)]
+    pub field_accessors: bool, // State fields read / written at their offsets, no struct cast
+    #[serde(default)]
+    pub pinocchio_log: bool, // Formatted logs go through pinocchio_log::log!
+    #[serde(default)]
+    pub pack_fields: bool, // State fields reordered by alignment: not the original byte layout
+    #[serde(default = "default_disc_len")]
+    pub disc_len: usize, // Discriminator bytes of instructions and accounts (8 like Anchor, 4, 1)
+}
+
+fn default_disc_len() -> usize {
+    8
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -204,7 +297,7 @@ pub struct PinocchioInstruction {
     pub body: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct PinocchioAccount {
     pub name: String,
     pub index: usize,
@@ -217,6 +310,44 @@ pub struct PinocchioAccount {
     pub token_authority: Option<String>, // For init token accounts
     pub init_payer: Option<String>,      // Who pays for initialization
     pub state_type: Option<String>,      // The state struct type for this account (e.g., "Pool", "Escrow")
+    #[serde(default)]
+    pub docs: Vec<String>, // Doc comments carried through to the IDL
+    #[serde(default)]
+    pub is_optional: bool, // Option<...> account, absent when the program id is passed
+    #[serde(default)]
+    pub is_init_if_needed: bool,
+    #[serde(default)]
+    pub init_space: Option<String>, // Space expression from init / init_if_needed
+    #[serde(default)]
+    pub realloc: Option<ReallocInfo>,
+    #[serde(default)]
+    pub original_name: Option<String>, // Anchor name when renamed to avoid a collision
+    #[serde(default)]
+    pub sysvar: Option<String>, // Sysvar<'info, T> inner type (e.g., "Clock")
+    #[serde(default)]
+    pub close: Option<String>, // Account receiving the lamports from `close = target`
+    #[serde(default)]
+    pub is_loader: bool, // AccountLoader: loaded in place with State::load / load_mut
+    #[serde(default)]
+    pub check_doc: Option<String>, // `/// CHECK:` note: validation left to the handler
+    #[serde(default)]
+    pub is_associated_token: bool, // associated_token::mint / authority: token_mint / token_authority name them
+    #[serde(default)]
+    pub token_program: Option<String>, // Token program account an associated token account is derived and created with
+    #[serde(default)]
+    pub system_program: Option<String>, // System program account passed when creating an associated token account
+    #[serde(default)]
+    pub is_token_account: bool, // SPL TokenAccount: .amount / .mint / .owner read from its data
+    #[serde(default)]
+    pub is_mint: bool, // SPL Mint: .supply read from its data
+}
+
+/// Resize info from `realloc = ..., realloc::payer = ..., realloc::zero = ...`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReallocInfo {
+    pub space: String,
+    pub payer: String,
+    pub zero: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -235,6 +366,7 @@ pub enum Validation {
     OwnerCheck {
         account_idx: usize,
         owner: String,
+        error: String, // ProgramError expression returned on mismatch
     },
     KeyEquals {
         account_idx: usize,
@@ -245,11 +377,40 @@ pub enum Validation {
     },
 }
 
+/// Custom instruction arg type, deserialized field by field in Borsh order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinocchioStructDef {
+    pub name: String,
+    pub fields: Vec<InstructionArg>,
+}
+
+/// Custom instruction arg enum, matched on its tag byte
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinocchioEnumDef {
+    pub name: String,
+    pub variants: Vec<EnumVariantDef>,
+}
+
+/// `#[event]` struct, logged with sol_log_data as its discriminator followed by the
+/// Borsh-encoded fields
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinocchioEventDef {
+    pub name: String,
+    pub discriminator: Vec<u8>, // sha256("event:{name}")[0..8]
+    pub fields: Vec<InstructionArg>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PinocchioState {
     pub name: String,
     pub size: usize,
     pub fields: Vec<PinocchioField>,
+    #[serde(default)]
+    pub discriminator: Vec<u8>, // Anchor's sha256("account:Name")[0..8], written on init
+    #[serde(default)]
+    pub is_zero_copy: bool, // Gets unsafe load / load_mut for AccountLoader accounts
+    #[serde(default)]
+    pub init_space: Option<usize>, // Anchor's INIT_SPACE, for #[derive(InitSpace)] structs
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -257,12 +418,14 @@ pub struct PinocchioField {
     pub name: String,
     pub ty: String,
     pub size: usize,
-    pub offset: usize,
+    pub offset: Option<usize>, // None after a variable-length field (found at runtime) or if skipped
     pub max_len: Option<usize>, // For String fields with #[max_len(N)]
     #[serde(default)]
     pub is_vec: bool,
     #[serde(default)]
     pub vec_info: Option<VecField>,
+    #[serde(default)]
+    pub borsh_skip: bool, // #[borsh(skip)]: kept in memory, left out of the serialized layout
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1,6 +1,7 @@
 use anyhow::Result;
 use clap::Parser as ClapParser;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 mod analyzer;
 mod collections;
@@ -30,7 +31,8 @@ struct Args {
     #[arg(long)]
     no_alloc: bool,
 
-    /// Use lazy_program_entrypoint! for on-demand parsing
+    /// Use lazy_program_entrypoint! for on-demand parsing. Accounts past the most any
+    /// instruction declares are accepted but not passed to the handler
     #[arg(long)]
     lazy_entrypoint: bool,
 
@@ -38,10 +40,16 @@ struct Args {
     #[arg(long)]
     inline_cpi: bool,
 
-    /// Generate IDL-compatible discriminators (8-byte Anchor style) - enabled by default
-    #[arg(long, default_value = "true")]
+    /// Generate IDL-compatible discriminators (8-byte Anchor style) - enabled by default.
+    /// `--anchor-compat=false` routes on a single leading selector byte instead
+    #[arg(long, default_value_t = true, num_args = 0..=1, default_missing_value = "true", action = clap::ArgAction::Set)]
     anchor_compat: bool,
 
+    /// Discriminator length in bytes: 8 (Anchor), or 4 / 1 to save space in every
+    /// instruction and account. Shorter ones no longer match Anchor clients
+    #[arg(long, value_name = "8|4|1", default_value_t = 8, value_parser = parse_disc_len)]
+    disc_len: usize,
+
     /// Verbose output
     #[arg(short, long)]
     verbose: bool,
@@ -50,35 +58,106 @@ struct Args {
     #[arg(long)]
     idl: bool,
 
+    /// IDL layout: anchor, or a codama root node
+    #[arg(long, value_enum, default_value_t = idl::IdlFormat::Anchor)]
+    idl_format: idl::IdlFormat,
+
     /// Program ID for IDL metadata
     #[arg(long)]
     program_id: Option<String>,
 
-    /// Strip msg!() calls for smaller binary size
+    /// IDL name (defaults to the name of the crate holding the input, else the
+    /// #[program] module name)
+    #[arg(long)]
+    idl_name: Option<String>,
+
+    /// Strip msg!() calls for smaller binary size (same as --log-mode strip)
     #[arg(long)]
     no_logs: bool,
 
+    /// How to carry msg!() calls over: keep, compact (literal text only), strip or
+    /// pinocchio-log (formatted logs through pinocchio_log::log!)
+    #[arg(long, alias = "logger", value_enum, default_value_t = transformer::LogMode::Keep)]
+    log_mode: transformer::LogMode,
+
     /// Use unchecked math operations for smaller binary (unsafe but faster)
     #[arg(long)]
     unsafe_math: bool,
 
+    /// Parse instruction args into a per-instruction struct instead of inline locals
+    #[arg(long)]
+    arg_structs: bool,
+
+    /// Comma-separated instructions to mark #[inline(never)] and #[cold]
+    #[arg(long, value_delimiter = ',')]
+    cold_instructions: Vec<String>,
+
+    /// Reject calls whose program_id is not the declared program ID
+    #[arg(long)]
+    check_program_id: bool,
+
+    /// Read and write state fields at their byte offsets instead of casting account data
+    #[arg(long)]
+    field_accessors: bool,
+
+    /// Reorder state struct fields largest alignment first to minimize #[repr(C)] padding.
+    /// Changes the byte layout: only for new programs, not existing accounts
+    #[arg(long)]
+    pack_fields: bool,
+
+    /// Rename an instruction (and so its discriminator) as old=new; repeatable
+    #[arg(long = "rename-instruction", value_name = "OLD=NEW", value_parser = parse_rename)]
+    rename_instructions: Vec<(String, String)>,
+
     /// Verify generated IDL against original Anchor IDL
     #[arg(long)]
     verify_idl: Option<PathBuf>,
+
+    /// Generate a cargo-fuzz target for the instruction dispatcher in fuzz/
+    #[arg(long)]
+    emit_fuzz: bool,
+
+    /// Generate smoke tests in tests/ checking discriminator dispatch and account counts
+    #[arg(long)]
+    emit_tests: bool,
+
+    /// Write the parsed, analyzed and transformed IR as JSON (anchor_ir.json, analysis.json,
+    /// pinocchio_ir.json) into the output directory
+    #[arg(long)]
+    emit_ir: bool,
+
+    /// Run the full pipeline but print each generated file to stdout instead of writing it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Skip writing the security.json metadata file
+    #[arg(long)]
+    no_security_json: bool,
+
+    /// Print wall-clock time spent in each phase
+    #[arg(long)]
+    timings: bool,
+
+    /// Worker threads for parallel transforms (0 = 75% of available cores)
+    #[arg(long, default_value_t = 0)]
+    jobs: usize,
 }
 
 fn main() -> Result<()> {
-    // Configure rayon thread pool to use 75% of available cores globally
-    let num_cores = num_cpus::get();
-    let target_threads = (num_cores as f32 * 0.75).ceil() as usize;
+    let args = Args::parse();
+
+    // Configure rayon thread pool to use --jobs threads, or 75% of available cores
+    let target_threads = if args.jobs > 0 {
+        args.jobs
+    } else {
+        (num_cpus::get() as f32 * 0.75).ceil() as usize
+    };
 
     rayon::ThreadPoolBuilder::new()
         .num_threads(target_threads)
         .build_global()
         .ok(); // Ignore if already initialized
 
-    let args = Args::parse();
-
     // Resolve input path - if it's a directory, look for src/lib.rs
     let input_file = if args.input.is_dir() {
         let lib_path = args.input.join("src").join("lib.rs");
@@ -107,24 +186,28 @@ fn main() -> Result<()> {
     };
 
     if args.verbose {
-        println!("uncpi v{}", env!("CARGO_PKG_VERSION"));
-        println!("Input:  {:?}", input_file);
-        println!("Output: {:?}", output_dir);
+        eprintln!("uncpi v{}", env!("CARGO_PKG_VERSION"));
+        eprintln!("Input:  {:?}", input_file);
+        eprintln!("Output: {:?}", output_dir);
+        eprintln!("Threads: {}", rayon::current_num_threads());
     }
 
     // Phase 1: Parse Anchor source
     if args.verbose {
-        println!("\n[1/4] Parsing Anchor program...");
+        eprintln!("\n[1/4] Parsing Anchor program...");
     }
-    let anchor_program = parser::parse_anchor_file(&input_file)?;
+    let start = Instant::now();
+    let mut anchor_program = parser::parse_anchor_file(&input_file)?;
+    parser::rename_instructions(&mut anchor_program, &args.rename_instructions)?;
+    let mut parse_time = start.elapsed();
 
     if args.verbose {
-        println!("  Found {} instructions", anchor_program.instructions.len());
-        println!(
+        eprintln!("  Found {} instructions", anchor_program.instructions.len());
+        eprintln!(
             "  Found {} account structs",
             anchor_program.account_structs.len()
         );
-        println!(
+        eprintln!(
             "  Found {} state structs",
             anchor_program.state_structs.len()
         );
@@ -132,88 +215,184 @@ fn main() -> Result<()> {
 
     // Phase 2: Analyze
     if args.verbose {
-        println!("\n[2/4] Analyzing program...");
+        eprintln!("\n[2/4] Analyzing program...");
     }
+    let start = Instant::now();
     let analysis = analyzer::analyze(&anchor_program)?;
+    let analyze_time = start.elapsed();
 
     if args.verbose {
-        println!("  PDAs: {}", analysis.pdas.len());
-        println!("  CPIs: {}", analysis.cpi_calls.len());
+        eprintln!("  PDAs: {}", analysis.pdas.len());
+        eprintln!("  CPIs: {}", analysis.cpi_calls.len());
     }
 
     // Phase 3: Transform to Pinocchio IR
     if args.verbose {
-        println!("\n[3/4] Transforming to Pinocchio IR...");
+        eprintln!("\n[3/4] Transforming to Pinocchio IR...");
     }
     let config = transformer::Config {
         no_alloc: args.no_alloc,
         lazy_entrypoint: args.lazy_entrypoint,
         inline_cpi: args.inline_cpi,
         anchor_compat: args.anchor_compat,
-        no_logs: args.no_logs,
+        log_mode: if args.no_logs {
+            transformer::LogMode::Strip
+        } else {
+            args.log_mode
+        },
         unsafe_math: args.unsafe_math,
+        arg_structs: args.arg_structs,
+        cold_instructions: args.cold_instructions.clone(),
+        check_program_id: args.check_program_id,
+        field_accessors: args.field_accessors,
+        pack_fields: args.pack_fields,
+        disc_len: args.disc_len,
     };
-    let pinocchio_ir = transformer::transform(&anchor_program, &analysis, &config)?;
+    if args.pack_fields {
+        eprintln!(
+            "warning: --pack-fields reorders state fields, so accounts written by the original \
+             program will not deserialize; use it only for new deployments"
+        );
+    }
+    let start = Instant::now();
+    let (pinocchio_ir, transform_timings) =
+        transformer::transform_with_timings(&anchor_program, &analysis, &config)?;
+    let transform_time = start.elapsed();
 
     // Phase 3.5: Extract constants and helpers
     if args.verbose {
-        println!("\n[3.5/4] Extracting constants and helpers...");
+        eprintln!("\n[3.5/4] Extracting constants and helpers...");
     }
+    let start = Instant::now();
     let extras = parser::parse_extras(&input_file)?;
+    parse_time += start.elapsed();
     if args.verbose {
-        println!("  Constants: {}", extras.constants.len());
-        println!("  Helper functions: {}", extras.helper_functions.len());
+        eprintln!("  Constants: {}", extras.constants.len());
+        eprintln!("  Helper functions: {}", extras.helper_functions.len());
     }
 
     // Phase 4: Emit Pinocchio code
     if args.verbose {
-        println!("\n[4/4] Emitting Pinocchio code...");
+        eprintln!("\n[4/4] Emitting Pinocchio code...");
+    }
+    let start = Instant::now();
+    // Handlers from a previous run keep their hand-written regions
+    let mut generated = emitter::emit_with_extras(&pinocchio_ir, Some(&output_dir), Some(&extras))?;
+    if !args.no_security_json {
+        generated.extend(emitter::emit_security_json(&pinocchio_ir)?);
     }
-    emitter::emit_with_extras(&pinocchio_ir, &output_dir, Some(&extras))?;
+    if args.emit_fuzz {
+        generated.extend(emitter::emit_fuzz_target(&pinocchio_ir));
+    }
+    if args.emit_tests {
+        generated.extend(emitter::emit_tests(&pinocchio_ir));
+    }
+    if args.emit_ir {
+        generated.extend(emitter::emit_ir_json(&anchor_program, &analysis, &pinocchio_ir)?);
+    }
+    let emit_time = start.elapsed();
 
     // Phase 5: Generate IDL if requested
+    let mut idl_time = None;
+    let mut generated_idl = None;
     if args.idl || args.verify_idl.is_some() {
         if args.verbose {
-            println!("\n[5/5] Generating IDL...");
+            eprintln!("\n[5/5] Generating IDL...");
         }
-        let idl = idl::generate_idl(&pinocchio_ir, args.program_id.as_deref());
-        let idl_path = output_dir.join("idl.json");
-        let idl_json = serde_json::to_string_pretty(&idl)?;
-        std::fs::write(&idl_path, &idl_json)?;
-        if args.verbose {
-            println!("  IDL written to {:?}", idl_path);
+        let start = Instant::now();
+        let mut idl = idl::generate_idl(&pinocchio_ir, args.program_id.as_deref());
+        // Anchor names the IDL after the crate, which may differ from the module
+        let crate_name = input_file
+            .ancestors()
+            .skip(1)
+            .find_map(|dir| crate_name(&dir.join("Cargo.toml")));
+        if let Some(name) = args.idl_name.clone().or(crate_name) {
+            idl.name = name.replace('-', "_");
         }
+        // The Anchor IDL is still what --verify-idl compares against
+        let idl_json = match args.idl_format {
+            idl::IdlFormat::Anchor => serde_json::to_string_pretty(&idl)?,
+            idl::IdlFormat::Codama => serde_json::to_string_pretty(&idl::generate_codama_idl(
+                &pinocchio_ir,
+                &idl.name,
+                args.program_id.as_deref(),
+            ))?,
+        };
+        generated.files.insert(PathBuf::from("idl.json"), idl_json);
+        generated_idl = Some(idl);
+        idl_time = Some(start.elapsed());
+    }
 
-        // Verify against original IDL if provided
-        if let Some(original_idl_path) = &args.verify_idl {
-            if args.verbose {
-                println!("\n[6/6] Verifying IDL compatibility...");
+    let start = Instant::now();
+    if args.dry_run {
+        generated.print();
+    } else {
+        generated.write(&output_dir)?;
+        if args.verbose {
+            if args.emit_fuzz {
+                eprintln!("  Fuzz target written to {:?}", output_dir.join("fuzz"));
+            }
+            if args.emit_tests {
+                eprintln!("  Tests written to {:?}", output_dir.join("tests"));
+            }
+            if args.emit_ir {
+                eprintln!("  IR written to {:?}", output_dir);
             }
-            let verification = idl::verify_idl(&idl, original_idl_path)?;
-            if verification.is_compatible {
-                println!("\n✅ IDL VERIFICATION PASSED");
-                println!(
-                    "  Instructions: {}/{} match",
-                    verification.matching_instructions, verification.total_instructions
-                );
-                println!(
-                    "  Accounts: {}/{} match",
-                    verification.matching_accounts, verification.total_accounts
-                );
-                println!(
-                    "  Errors: {}/{} match",
-                    verification.matching_errors, verification.total_errors
-                );
-            } else {
-                println!("\n❌ IDL VERIFICATION FAILED");
-                for issue in &verification.issues {
-                    println!("  - {}", issue);
-                }
-                std::process::exit(1);
+            if generated_idl.is_some() {
+                eprintln!("  IDL written to {:?}", output_dir.join("idl.json"));
+            }
+        }
+    }
+    let emit_time = emit_time + start.elapsed();
+
+    // Verify against original IDL if provided
+    if let (Some(idl), Some(original_idl_path)) = (&generated_idl, &args.verify_idl) {
+        if args.verbose {
+            eprintln!("\n[6/6] Verifying IDL compatibility...");
+        }
+        let verification = idl::verify_idl(idl, original_idl_path)?;
+        if verification.is_compatible {
+            println!("\n✅ IDL VERIFICATION PASSED");
+            println!(
+                "  Instructions: {}/{} match",
+                verification.matching_instructions, verification.total_instructions
+            );
+            println!(
+                "  Accounts: {}/{} match",
+                verification.matching_accounts, verification.total_accounts
+            );
+            println!(
+                "  Errors: {}/{} match",
+                verification.matching_errors, verification.total_errors
+            );
+        } else {
+            println!("\n❌ IDL VERIFICATION FAILED");
+            for issue in &verification.issues {
+                println!("  - {}", issue);
             }
+            std::process::exit(1);
         }
     }
 
+    if args.timings {
+        println!("\nTimings:");
+        print_timing("parse", parse_time);
+        print_timing("analyze", analyze_time);
+        print_timing("transform", transform_time);
+        print_timing("  accounts", transform_timings.accounts);
+        print_timing("  validations", transform_timings.validations);
+        print_timing("  body", transform_timings.body);
+        print_timing("  state", transform_timings.state);
+        print_timing("emit", emit_time);
+        if let Some(idl_time) = idl_time {
+            print_timing("idl", idl_time);
+        }
+    }
+
+    if args.dry_run {
+        return Ok(());
+    }
+
     println!("\nSuccess! Pinocchio program written to {:?}", output_dir);
     println!("\nNext steps:");
     println!("  1. cd {:?}", output_dir);
@@ -222,3 +401,40 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// `[package] name` from a program's Cargo.toml; None for a missing manifest or a
+/// workspace root, so the search goes on to the next directory up
+fn crate_name(manifest: &std::path::Path) -> Option<String> {
+    let manifest = std::fs::read_to_string(manifest).ok()?;
+    let mut in_package = false;
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_package = line == "[package]";
+        } else if let (true, Some(value)) = (in_package, line.strip_prefix("name")) {
+            let value = value.trim_start().strip_prefix('=')?;
+            return Some(value.trim().trim_matches('"').to_string());
+        }
+    }
+    None
+}
+
+/// `--rename-instruction old=new`
+fn parse_rename(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((old, new)) if !old.trim().is_empty() && !new.trim().is_empty() => {
+            Ok((old.trim().to_string(), new.trim().to_string()))
+        }
+        _ => Err(format!("expected OLD=NEW, got '{}'", value)),
+    }
+}
+
+fn parse_disc_len(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(len @ (1 | 4 | 8)) => Ok(len),
+        _ => Err(format!("expected 8, 4 or 1, got '{}'", value)),
+    }
+}
+
+fn print_timing(phase: &str, elapsed: Duration) {
+    println!("  {:<16} {:>10.3}ms", phase, elapsed.as_secs_f64() * 1000.0);
+}
@@ -2,6 +2,7 @@
 
 use anyhow::{Context, Result};
 use quote::ToTokens;
+use std::collections::HashSet;
 use std::path::Path;
 use syn::{
     parse_file, Attribute, Field, GenericArgument, Item, ItemMod, ItemStruct,
@@ -107,8 +108,12 @@ pub fn parse_extras(path: &Path) -> Result<SourceExtras> {
 
     // Expand modules to get full source
     let expanded_content = expand_modules(&content, path)?;
+    parse_extras_source(&expanded_content)
+}
 
-    let file = parse_file(&expanded_content).with_context(|| "Failed to parse Rust source")?;
+/// Extract constants and helper functions from already expanded source
+pub fn parse_extras_source(source: &str) -> Result<SourceExtras> {
+    let file = parse_file(source).with_context(|| "Failed to parse Rust source")?;
 
     let mut extras = SourceExtras::default();
 
@@ -121,15 +126,13 @@ pub fn parse_extras(path: &Path) -> Result<SourceExtras> {
                     value: tokens_to_string(&c.expr),
                 });
             }
-            Item::Fn(f) => {
-                // Only include non-instruction helper functions
-                if !matches!(f.vis, syn::Visibility::Public(_)) {
-                    extras.helper_functions.push(HelperFunction {
-                        name: f.sig.ident.to_string(),
-                        signature: tokens_to_string(&f.sig),
-                        body: tokens_to_string(&f.block),
-                    });
-                }
+            // Only include non-instruction helper functions
+            Item::Fn(f) if !matches!(f.vis, syn::Visibility::Public(_)) => {
+                extras.helper_functions.push(HelperFunction {
+                    name: f.sig.ident.to_string(),
+                    signature: tokens_to_string(&f.sig),
+                    body: tokens_to_string(&f.block),
+                });
             }
             _ => {}
         }
@@ -147,7 +150,11 @@ pub fn parse_anchor_source(source: &str) -> Result<AnchorProgram> {
         instructions: Vec::new(),
         account_structs: Vec::new(),
         state_structs: Vec::new(),
+        types: Vec::new(),
+        enums: Vec::new(),
+        events: Vec::new(),
         errors: Vec::new(),
+        error_enum: None,
     };
 
     // Find declare_id!
@@ -165,27 +172,48 @@ pub fn parse_anchor_source(source: &str) -> Result<AnchorProgram> {
         if let Item::Mod(module) = item {
             if has_attribute(&module.attrs, "program") {
                 program.name = module.ident.to_string();
-                parse_program_module(module, &mut program)?;
+                parse_program_module(module, &file.items, &mut program)?;
             }
         }
     }
 
     // Find account structs with #[derive(Accounts)]
+    let accounts_structs: Vec<&ItemStruct> = file
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Struct(s) if has_derive(&s.attrs, "Accounts") => Some(s),
+            _ => None,
+        })
+        .collect();
+    let arg_types = arg_type_names(&file.items, &program);
     for item in &file.items {
         if let Item::Struct(s) = item {
             if has_derive(&s.attrs, "Accounts") {
-                program.account_structs.push(parse_account_struct(s)?);
+                program.account_structs.push(parse_account_struct(s, &accounts_structs)?);
             } else if has_attribute(&s.attrs, "account") {
                 program.state_structs.push(parse_state_struct(s)?);
+            } else if has_attribute(&s.attrs, "event") {
+                program.events.push(parse_struct_def(s));
+            } else if is_plain_type(&s.ident, &s.attrs, &arg_types) {
+                program.types.push(parse_struct_def(s));
             }
         }
     }
 
-    // Find #[error_code] enums
+    // Large accounts take the zero-copy path even without #[account(zero_copy)]
+    for state in &mut program.state_structs {
+        state.is_zero_copy = crate::zero_copy::should_use_zero_copy(state, &program.types);
+    }
+
+    // Find #[error_code] enums and plain data enums
     for item in &file.items {
         if let Item::Enum(e) = item {
             if has_attribute(&e.attrs, "error_code") {
                 program.errors = parse_error_enum(e)?;
+                program.error_enum = Some(e.ident.to_string());
+            } else if is_plain_type(&e.ident, &e.attrs, &arg_types) {
+                program.enums.push(parse_enum_def(e));
             }
         }
     }
@@ -193,12 +221,41 @@ pub fn parse_anchor_source(source: &str) -> Result<AnchorProgram> {
     Ok(program)
 }
 
-fn parse_program_module(module: &ItemMod, program: &mut AnchorProgram) -> Result<()> {
+/// Apply `old=new` instruction renames to a parsed program. Renames happen together, so
+/// two instructions can swap names, and the resulting names must stay unique.
+pub fn rename_instructions(program: &mut AnchorProgram, renames: &[(String, String)]) -> Result<()> {
+    for (old, new) in renames {
+        if !program.instructions.iter().any(|inst| &inst.name == old) {
+            anyhow::bail!("Renamed instruction '{}' is not an instruction of this program", old);
+        }
+        if syn::parse_str::<syn::Ident>(new).is_err() {
+            anyhow::bail!("New name '{}' for instruction '{}' is not a valid identifier", new, old);
+        }
+    }
+
+    for inst in &mut program.instructions {
+        if let Some((_, new)) = renames.iter().find(|(old, _)| *old == inst.name) {
+            inst.name = new.clone();
+        }
+    }
+
+    for (i, inst) in program.instructions.iter().enumerate() {
+        if program.instructions[..i].iter().any(|other| other.name == inst.name) {
+            anyhow::bail!("Instruction name '{}' is used more than once after renaming", inst.name);
+        }
+    }
+    Ok(())
+}
+
+fn parse_program_module(module: &ItemMod, file_items: &[Item], program: &mut AnchorProgram) -> Result<()> {
     if let Some((_, items)) = &module.content {
+        // Access control checks may live in the module or anywhere in the file
+        let scope: Vec<&Item> = items.iter().chain(file_items).collect();
         for item in items {
             if let Item::Fn(func) = item {
                 if matches!(func.vis, syn::Visibility::Public(_)) {
-                    let instruction = parse_instruction(func)?;
+                    let mut instruction = parse_instruction(func)?;
+                    instruction.access_control = parse_access_control(&func.attrs, &scope)?;
                     program.instructions.push(instruction);
                 }
             }
@@ -207,6 +264,85 @@ fn parse_program_module(module: &ItemMod, program: &mut AnchorProgram) -> Result
     Ok(())
 }
 
+/// Parse `#[access_control(a(&ctx), b(&ctx, amount))]` into its checks, each with the body
+/// of the function it calls (a free function or one in an impl block)
+fn parse_access_control(attrs: &[Attribute], scope: &[&Item]) -> Result<Vec<AccessControl>> {
+    let mut checks = Vec::new();
+
+    for attr in attrs.iter().filter(|a| a.path().is_ident("access_control")) {
+        let calls = attr
+            .parse_args_with(
+                syn::punctuated::Punctuated::<syn::Expr, syn::Token![,]>::parse_terminated,
+            )
+            .with_context(|| "Failed to parse #[access_control(...)]")?;
+
+        for call in calls {
+            let syn::Expr::Call(call) = call else {
+                anyhow::bail!(
+                    "Unsupported access control check: {}",
+                    tokens_to_string(&call)
+                );
+            };
+            let syn::Expr::Path(path) = &*call.func else {
+                anyhow::bail!(
+                    "Unsupported access control check: {}",
+                    tokens_to_string(&call)
+                );
+            };
+            let name = path
+                .path
+                .segments
+                .last()
+                .map(|s| s.ident.to_string())
+                .unwrap_or_default();
+
+            let sig_and_block = scope.iter().find_map(|item| match item {
+                Item::Fn(f) if f.sig.ident == name => Some((&f.sig, &*f.block)),
+                Item::Impl(imp) => imp.items.iter().find_map(|impl_item| match impl_item {
+                    syn::ImplItem::Fn(f) if f.sig.ident == name => Some((&f.sig, &f.block)),
+                    _ => None,
+                }),
+                _ => None,
+            });
+            let params: Vec<String> = sig_and_block
+                .map(|(sig, _)| {
+                    sig.inputs
+                        .iter()
+                        .filter_map(|input| match input {
+                            syn::FnArg::Typed(pat_type) => match &*pat_type.pat {
+                                syn::Pat::Ident(ident) => Some(ident.ident.to_string()),
+                                _ => None,
+                            },
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            let mut ctx_param = None;
+            let mut bindings = Vec::new();
+            for (i, arg) in call.args.iter().enumerate() {
+                let arg = tokens_to_string(arg);
+                let param = params.get(i).cloned();
+                if arg.replace(' ', "").trim_start_matches('&') == "ctx" {
+                    ctx_param = param;
+                } else if let Some(param) = param {
+                    bindings.push((param, arg));
+                }
+            }
+
+            checks.push(AccessControl {
+                name,
+                ctx_param,
+                bindings,
+                body: sig_and_block.map(|(_, block)| tokens_to_string(block)),
+            });
+        }
+    }
+
+    Ok(checks)
+}
+
 fn parse_instruction(func: &syn::ItemFn) -> Result<AnchorInstruction> {
     let name = func.sig.ident.to_string();
 
@@ -258,18 +394,40 @@ fn parse_instruction(func: &syn::ItemFn) -> Result<AnchorInstruction> {
         accounts_struct,
         args,
         body,
+        access_control: Vec::new(),
     })
 }
 
-fn parse_account_struct(s: &ItemStruct) -> Result<AnchorAccountStruct> {
+/// Parse a `#[derive(Accounts)]` struct. Fields whose type is another Accounts struct
+/// (account composition) are replaced by that struct's accounts, in place.
+fn parse_account_struct(s: &ItemStruct, accounts_structs: &[&ItemStruct]) -> Result<AnchorAccountStruct> {
     let name = s.ident.to_string();
-    let instruction_args = Vec::new(); // TODO: parse #[instruction(...)]
+    let instruction_args = parse_instruction_attr(&s.attrs)?;
 
     let mut accounts = Vec::new();
+    let mut composed = Vec::new();
 
     if let syn::Fields::Named(fields) = &s.fields {
         for field in &fields.named {
-            accounts.push(parse_anchor_account(field)?);
+            let nested = match &field.ty {
+                Type::Path(type_path) => type_path.path.segments.last().and_then(|seg| {
+                    accounts_structs
+                        .iter()
+                        .find(|s| s.ident == seg.ident && s.ident != name)
+                }),
+                _ => None,
+            };
+            match nested {
+                Some(nested) => {
+                    let nested = parse_account_struct(nested, accounts_structs)?;
+                    composed.push(
+                        field.ident.as_ref().map(|i| i.to_string()).unwrap_or_default(),
+                    );
+                    composed.extend(nested.composed);
+                    accounts.extend(nested.accounts);
+                }
+                None => accounts.push(parse_anchor_account(field)?),
+            }
         }
     }
 
@@ -277,9 +435,36 @@ fn parse_account_struct(s: &ItemStruct) -> Result<AnchorAccountStruct> {
         name,
         instruction_args,
         accounts,
+        composed,
     })
 }
 
+/// Args declared by `#[instruction(a: u64, b: Pubkey)]`, in instruction data order
+fn parse_instruction_attr(attrs: &[Attribute]) -> Result<Vec<InstructionArg>> {
+    let Some(attr) = attrs.iter().find(|a| a.path().is_ident("instruction")) else {
+        return Ok(Vec::new());
+    };
+    let tokens = &attr.meta.require_list()?.tokens;
+    // Same grammar as a parameter list, so borrow the fn signature parser
+    let sig: syn::Signature = syn::parse2(quote::quote!(fn instruction(#tokens)))
+        .context("Failed to parse #[instruction(...)]")?;
+
+    Ok(sig
+        .inputs
+        .iter()
+        .filter_map(|input| match input {
+            syn::FnArg::Typed(pat_type) => match &*pat_type.pat {
+                syn::Pat::Ident(ident) => Some(InstructionArg {
+                    name: ident.ident.to_string(),
+                    ty: type_to_string(&pat_type.ty).replace(" ", ""),
+                }),
+                _ => None,
+            },
+            syn::FnArg::Receiver(_) => None,
+        })
+        .collect())
+}
+
 fn parse_anchor_account(field: &Field) -> Result<AnchorAccount> {
     let name = field
         .ident
@@ -289,39 +474,52 @@ fn parse_anchor_account(field: &Field) -> Result<AnchorAccount> {
 
     let ty = parse_account_type(&field.ty);
     let constraints = parse_account_constraints(&field.attrs);
+    let docs = extract_doc_comments(&field.attrs);
 
     Ok(AnchorAccount {
         name,
         ty,
         constraints,
+        docs,
     })
 }
 
 fn parse_account_type(ty: &Type) -> AccountType {
+    // Optional accounts (Anchor 0.29+) wrap any other account type
+    if let Some(inner) = option_inner_type(ty) {
+        return AccountType::Optional {
+            inner: Box::new(parse_account_type(inner)),
+        };
+    }
+
     let ty_str = type_to_string(ty).replace(" ", "");
 
-    if ty_str.contains("Signer") {
+    if names_type(&ty_str, "Signer") {
         AccountType::Signer
-    } else if ty_str.contains("SystemAccount") {
+    } else if names_type(&ty_str, "SystemAccount") {
         AccountType::SystemAccount
-    } else if ty_str.contains("UncheckedAccount") {
+    } else if names_type(&ty_str, "UncheckedAccount") {
         AccountType::UncheckedAccount
-    } else if ty_str.contains("Program") {
+    } else if names_type(&ty_str, "Program") {
         let inner = extract_generic(&ty_str, "Program");
         AccountType::Program { inner }
-    } else if ty_str.contains("Sysvar") {
+    } else if names_type(&ty_str, "Sysvar") {
         let inner = extract_generic(&ty_str, "Sysvar");
         AccountType::Sysvar { inner }
-    } else if ty_str.contains("TokenAccount") {
+    } else if names_type(&ty_str, "TokenAccount") {
         AccountType::TokenAccount
-    } else if ty_str.contains("Mint") {
+    } else if names_type(&ty_str, "Mint") {
         AccountType::Mint
     } else if ty_str.starts_with("Box<") {
-        let inner_str = ty_str.trim_start_matches("Box<").trim_end_matches('>');
+        // Only the Box's own `>`; the inner type keeps its generics
+        let inner_str = &ty_str["Box<".len()..ty_str.len() - 1];
         let inner = parse_account_type_str(inner_str);
         AccountType::Box {
             inner: Box::new(inner),
         }
+    } else if ty_str.contains("AccountLoader<") {
+        let inner = extract_generic(&ty_str, "AccountLoader");
+        AccountType::AccountLoader { inner }
     } else if ty_str.contains("Account") {
         let inner = extract_generic(&ty_str, "Account");
         AccountType::Account { inner }
@@ -330,14 +528,40 @@ fn parse_account_type(ty: &Type) -> AccountType {
     }
 }
 
+/// Return `T` for an `Option<T>` type
+fn option_inner_type(ty: &Type) -> Option<&Type> {
+    let Type::Path(type_path) = ty else {
+        return None;
+    };
+    let segment = type_path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+            syn::GenericArgument::Type(inner) => Some(inner),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+/// Whether `name` appears in the type as a whole path segment, so `Mint` matches
+/// `anchor_spl::token::Mint` but not a user type like `MintConfig`
+fn names_type(ty_str: &str, name: &str) -> bool {
+    ty_str
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .any(|segment| segment == name)
+}
+
 fn parse_account_type_str(s: &str) -> AccountType {
-    if s.contains("Account") {
+    if names_type(s, "TokenAccount") {
+        AccountType::TokenAccount
+    } else if names_type(s, "Mint") {
+        AccountType::Mint
+    } else if s.contains("Account") {
         let inner = extract_generic(s, "Account");
         AccountType::Account { inner }
-    } else if s.contains("Mint") {
-        AccountType::Mint
-    } else if s.contains("TokenAccount") {
-        AccountType::TokenAccount
     } else {
         AccountType::Account {
             inner: s.to_string(),
@@ -372,66 +596,159 @@ fn parse_account_constraints(attrs: &[Attribute]) -> Vec<AccountConstraint> {
             continue;
         }
 
-        let tokens = attr_to_string(attr);
-
-        if tokens.contains("mut") {
-            constraints.push(AccountConstraint::Mut);
-        }
-
-        if tokens.contains("init") {
-            let payer = extract_value(&tokens, "payer");
-            let space = extract_value(&tokens, "space");
-            if tokens.contains("init_if_needed") {
-                constraints.push(AccountConstraint::InitIfNeeded { payer, space });
-            } else {
-                // Only add Init if it's not init_if_needed
-                constraints.push(AccountConstraint::Init { payer, space });
+        let clauses = account_clauses(&attr_to_string(attr));
+        let value_of = |key: &str| {
+            clauses
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default()
+        };
+
+        for (key, value) in &clauses {
+            match key.as_str() {
+                "mut" => constraints.push(AccountConstraint::Mut),
+                "init" => constraints.push(AccountConstraint::Init {
+                    payer: value_of("payer"),
+                    space: value_of("space"),
+                }),
+                "init_if_needed" => constraints.push(AccountConstraint::InitIfNeeded {
+                    payer: value_of("payer"),
+                    space: value_of("space"),
+                }),
+                "seeds" => {
+                    // Only the list's own brackets: a last seed like `& [bump]` keeps its `]`
+                    let value = value.trim();
+                    let inner = value
+                        .strip_prefix('[')
+                        .and_then(|v| v.strip_suffix(']'))
+                        .unwrap_or(value);
+                    let seeds = split_top_level(inner, ',')
+                        .into_iter()
+                        .filter(|s| !s.is_empty())
+                        .collect();
+                    constraints.push(AccountConstraint::Seeds(seeds));
+                }
+                "bump" => {
+                    let bump = Some(value.clone()).filter(|v| !v.is_empty());
+                    constraints.push(AccountConstraint::Bump(bump));
+                }
+                "associated_token :: mint" => constraints.push(AccountConstraint::AssociatedToken {
+                    mint: value.clone(),
+                    authority: value_of("associated_token :: authority"),
+                    token_program: Some(value_of("associated_token :: token_program"))
+                        .filter(|v| !v.is_empty()),
+                }),
+                "associated_token :: authority" | "associated_token :: token_program" => {}
+                k if k.ends_with("token :: mint") && !value.is_empty() => {
+                    constraints.push(AccountConstraint::TokenMint(value.clone()));
+                }
+                k if k.ends_with("token :: authority") && !value.is_empty() => {
+                    constraints.push(AccountConstraint::TokenAuthority(value.clone()));
+                }
+                "constraint" => {
+                    let (expr, error) = split_error(value);
+                    constraints.push(AccountConstraint::Constraint { expr, error });
+                }
+                "has_one" => {
+                    let (field, error) = split_error(value);
+                    constraints.push(AccountConstraint::HasOne { field, error });
+                }
+                "address" => {
+                    let (address, error) = split_error(value);
+                    constraints.push(AccountConstraint::Address { address, error });
+                }
+                "owner" => {
+                    let (owner, error) = split_error(value);
+                    constraints.push(AccountConstraint::Owner { owner, error });
+                }
+                "close" => constraints.push(AccountConstraint::Close(value.clone())),
+                "realloc" => constraints.push(AccountConstraint::Realloc {
+                    space: value.clone(),
+                    payer: value_of("realloc :: payer"),
+                    zero: value_of("realloc :: zero") == "true",
+                }),
+                _ => {}
             }
         }
+    }
 
-        if tokens.contains("seeds") {
-            let seeds = extract_seeds(&tokens);
-            constraints.push(AccountConstraint::Seeds(seeds));
-        }
-
-        if tokens.contains("bump") {
-            let bump = extract_value_optional(&tokens, "bump");
-            constraints.push(AccountConstraint::Bump(bump));
-        }
+    constraints
+}
 
-        // Handle "token :: mint" (with spaces from tokenization)
-        if tokens.contains("token :: mint") {
-            let mint = extract_value(&tokens, "token :: mint");
-            if !mint.is_empty() {
-                constraints.push(AccountConstraint::TokenMint(mint));
+/// Split `#[account(...)]` into its top-level `(key, value)` clauses. Bare flags like
+/// `mut` have an empty value. Commas inside brackets or string literals never end a
+/// clause, so `constraint = x . kind == "constraint , y"` stays one clause.
+fn account_clauses(tokens: &str) -> Vec<(String, String)> {
+    let Some(open) = tokens.find('(') else {
+        return Vec::new();
+    };
+    let inner = tokens[open + 1..].trim_end().trim_end_matches(']').trim_end();
+    let inner = inner.strip_suffix(')').unwrap_or(inner);
+
+    split_top_level(inner, ',')
+        .into_iter()
+        .filter(|clause| !clause.is_empty())
+        .map(|clause| {
+            // Keys are plain paths, so the first `=` is the key's unless it is `==` / `=>`
+            match clause.find('=') {
+                Some(eq)
+                    if !clause[eq + 1..].starts_with(['=', '>'])
+                        && !clause[..eq].ends_with(['!', '<', '>']) =>
+                {
+                    (
+                        clause[..eq].trim().to_string(),
+                        clause[eq + 1..].trim().to_string(),
+                    )
+                }
+                _ => (clause, String::new()),
             }
-        }
+        })
+        .collect()
+}
 
-        // Handle "token :: authority" (with spaces from tokenization)
-        if tokens.contains("token :: authority") {
-            let auth = extract_value(&tokens, "token :: authority");
-            if !auth.is_empty() {
-                constraints.push(AccountConstraint::TokenAuthority(auth));
+/// Split on `sep` outside brackets and string literals, trimming each part
+fn split_top_level(s: &str, sep: char) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut depth = 0i32;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, ch) in s.char_indices() {
+        if in_string {
+            match ch {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
             }
+            continue;
         }
-
-        if tokens.contains("constraint") {
-            let (expr, error) = extract_constraint(&tokens);
-            constraints.push(AccountConstraint::Constraint { expr, error });
-        }
-
-        if tokens.contains("has_one") {
-            let (field, error) = extract_has_one(&tokens);
-            constraints.push(AccountConstraint::HasOne { field, error });
-        }
-
-        if tokens.contains("close") {
-            let target = extract_value(&tokens, "close");
-            constraints.push(AccountConstraint::Close(target));
+        match ch {
+            '"' => in_string = true,
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            c if c == sep && depth == 0 => {
+                parts.push(s[start..i].trim().to_string());
+                start = i + c.len_utf8();
+            }
+            _ => {}
         }
     }
+    parts.push(s[start..].trim().to_string());
+    parts
+}
 
-    constraints
+/// Split `value @ SomeError::Variant` into the value and the error path
+fn split_error(value: &str) -> (String, Option<String>) {
+    let mut parts = split_top_level(value, '@').into_iter();
+    let value = parts.next().unwrap_or_default();
+    let error = parts
+        .next()
+        .map(|e| e.replace(' ', ""))
+        .filter(|e| !e.is_empty());
+    (value, error)
 }
 
 /// Detect if a type is Vec<T> and extract the element type
@@ -450,23 +767,104 @@ fn is_vec_type(ty: &Type) -> Option<String> {
     None
 }
 
-/// Extract #[max_len(N)] attribute for Vec fields
-fn extract_max_len_for_vec(attrs: &[Attribute]) -> Option<usize> {
-    for attr in attrs {
-        if attr.path().is_ident("max_len") {
-            let tokens = attr_to_string(attr);
-            // Parse "max_len(200)" or "max_len = 200"
-            if let Some(start) = tokens.find('(') {
-                if let Some(end) = tokens.find(')') {
-                    let num_str = tokens[start + 1..end].trim();
-                    if let Ok(num) = num_str.parse::<usize>() {
-                        return Some(num);
-                    }
+/// Bounds from #[max_len(N)], or #[max_len(4, 32)] for nested collections (outermost first)
+fn extract_max_lens(attrs: &[Attribute]) -> Vec<usize> {
+    attrs
+        .iter()
+        .find(|attr| attr.path().is_ident("max_len"))
+        .and_then(|attr| {
+            attr.parse_args_with(
+                syn::punctuated::Punctuated::<syn::LitInt, syn::Token![,]>::parse_terminated,
+            )
+            .ok()
+        })
+        .map(|lens| lens.iter().filter_map(|n| n.base10_parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Plain data types: Borsh-serializable or taken as an instruction arg
+fn is_plain_type(ident: &syn::Ident, attrs: &[Attribute], arg_types: &HashSet<String>) -> bool {
+    has_derive(attrs, "AnchorDeserialize")
+        || has_derive(attrs, "BorshDeserialize")
+        || arg_types.contains(&ident.to_string())
+}
+
+/// Names of the types instruction args are built from, including ones nested in
+/// their fields, resolved by name so declaration order doesn't matter
+fn arg_type_names(items: &[Item], program: &AnchorProgram) -> HashSet<String> {
+    let field_types = |name: &str| -> Vec<String> {
+        items
+            .iter()
+            .find_map(|item| match item {
+                Item::Struct(s) if s.ident == name => Some(parse_fields(&s.fields)),
+                Item::Enum(e) if e.ident == name => {
+                    Some(e.variants.iter().flat_map(|v| parse_fields(&v.fields)).collect())
                 }
+                _ => None,
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .map(|field| field.ty)
+            .collect()
+    };
+
+    let mut names = HashSet::new();
+    let mut pending: Vec<String> =
+        program.instructions.iter().flat_map(|inst| &inst.args).map(|arg| arg.ty.clone()).collect();
+    while let Some(ty) = pending.pop() {
+        // `Vec<Route>`, `Option<crate::Route>` and `[Route; 4]` all name `Route`
+        for name in ty.split(|c: char| !c.is_alphanumeric() && c != '_').filter(|s| !s.is_empty()) {
+            if names.insert(name.to_string()) {
+                pending.extend(field_types(name));
             }
         }
     }
-    None
+    names
+}
+
+fn parse_struct_def(s: &ItemStruct) -> AnchorStructDef {
+    let fields = match &s.fields {
+        syn::Fields::Named(_) => parse_fields(&s.fields),
+        _ => Vec::new(),
+    };
+
+    AnchorStructDef {
+        name: s.ident.to_string(),
+        fields,
+    }
+}
+
+fn parse_enum_def(e: &syn::ItemEnum) -> AnchorEnumDef {
+    let variants = e
+        .variants
+        .iter()
+        .map(|variant| EnumVariantDef {
+            name: variant.ident.to_string(),
+            fields: parse_fields(&variant.fields),
+            is_tuple: matches!(variant.fields, syn::Fields::Unnamed(_)),
+        })
+        .collect();
+
+    AnchorEnumDef {
+        name: e.ident.to_string(),
+        variants,
+    }
+}
+
+/// Named fields keep their names; tuple fields are named by position
+fn parse_fields(fields: &syn::Fields) -> Vec<InstructionArg> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| InstructionArg {
+            name: field
+                .ident
+                .as_ref()
+                .map(|ident| ident.to_string())
+                .unwrap_or_else(|| i.to_string()),
+            ty: type_to_string(&field.ty),
+        })
+        .collect()
 }
 
 fn parse_state_struct(s: &ItemStruct) -> Result<AnchorStateStruct> {
@@ -484,16 +882,16 @@ fn parse_state_struct(s: &ItemStruct) -> Result<AnchorStateStruct> {
                 .unwrap_or_default();
             let field_ty = type_to_string(&field.ty);
 
-            // Extract #[max_len(N)] if present
-            let max_len = extract_max_len(&field.attrs);
+            // Extract #[max_len(N)] / #[max_len(N, M)] if present
+            let max_lens = extract_max_lens(&field.attrs);
+            let max_len = max_lens.first().copied();
 
             // Check if this is a Vec<T>
             let (is_vec, vec_info) = if let Some(element_type) = is_vec_type(&field.ty) {
-                let vec_max_len = extract_max_len_for_vec(&field.attrs);
                 let vec_field = VecField {
                     name: field_name.clone(),
                     element_type,
-                    max_len: vec_max_len,
+                    max_len,
                     resolved_max_len: 0, // Will be resolved later
                     is_mutable: true,
                 };
@@ -506,8 +904,10 @@ fn parse_state_struct(s: &ItemStruct) -> Result<AnchorStateStruct> {
                 name: field_name,
                 ty: field_ty,
                 max_len,
+                inner_max_lens: max_lens.iter().skip(1).copied().collect(),
                 is_vec,
                 vec_info,
+                borsh_skip: is_borsh_skip(&field.attrs),
             });
         }
     }
@@ -516,26 +916,46 @@ fn parse_state_struct(s: &ItemStruct) -> Result<AnchorStateStruct> {
         name,
         fields,
         has_init_space,
-        is_zero_copy: false,  // TODO: Detect #[account(zero_copy)]
+        is_zero_copy: s
+            .attrs
+            .iter()
+            .any(|a| a.path().is_ident("account") && attr_to_string(a).contains("zero_copy")),
         is_packed: false,     // TODO: Detect #[repr(C, packed)]
         is_unsafe: false,     // TODO: Detect unsafe flag
     })
 }
 
+/// Anchor error codes are `offset + discriminant`: the offset defaults to 6000
+/// (`#[error_code(offset = N)]` overrides it) and discriminants follow Rust's rules,
+/// so `Foo = 10` restarts numbering
 fn parse_error_enum(e: &syn::ItemEnum) -> Result<Vec<AnchorError>> {
     let mut errors = Vec::new();
-    let mut code = 6000u32;
 
+    let offset = e
+        .attrs
+        .iter()
+        .find(|a| a.path().is_ident("error_code"))
+        .map(|a| extract_value(&attr_to_string(a), "offset"))
+        .and_then(|v| v.trim_end_matches(')').trim().replace('_', "").parse::<u32>().ok())
+        .unwrap_or(6000);
+
+    let mut discriminant = 0u32;
     for variant in &e.variants {
+        if let Some((_, expr)) = &variant.discriminant {
+            let value = expr.to_token_stream().to_string().replace('_', "");
+            discriminant = value.parse().with_context(|| {
+                format!("Unsupported discriminant for error {}: {}", variant.ident, value)
+            })?;
+        }
         let name = variant.ident.to_string();
         let msg = extract_msg_attr(&variant.attrs);
 
         errors.push(AnchorError {
             name,
-            code: Some(code),
+            code: Some(offset + discriminant),
             msg,
         });
-        code += 1;
+        discriminant += 1;
     }
 
     Ok(errors)
@@ -543,6 +963,15 @@ fn parse_error_enum(e: &syn::ItemEnum) -> Result<Vec<AnchorError>> {
 
 // Helper functions
 
+/// `#[borsh(skip)]`, or the older `#[borsh_skip]` / `#[skip]`
+fn is_borsh_skip(attrs: &[Attribute]) -> bool {
+    attrs.iter().any(|a| {
+        a.path().is_ident("borsh_skip")
+            || a.path().is_ident("skip")
+            || a.path().is_ident("borsh") && attr_to_string(a).replace(' ', "").contains("(skip)")
+    })
+}
+
 fn has_attribute(attrs: &[Attribute], name: &str) -> bool {
     attrs.iter().any(|a| a.path().is_ident(name))
 }
@@ -603,56 +1032,6 @@ fn extract_value(s: &str, key: &str) -> String {
     String::new()
 }
 
-fn extract_value_optional(s: &str, key: &str) -> Option<String> {
-    let val = extract_value(s, key);
-    if val.is_empty() {
-        None
-    } else {
-        Some(val)
-    }
-}
-
-fn extract_seeds(s: &str) -> Vec<String> {
-    if let Some(start) = s.find("seeds") {
-        if let Some(bracket_start) = s[start..].find('[') {
-            let rest = &s[start + bracket_start..];
-            if let Some(bracket_end) = rest.find(']') {
-                let inner = &rest[1..bracket_end];
-                return inner
-                    .split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect();
-            }
-        }
-    }
-    Vec::new()
-}
-
-fn extract_constraint(s: &str) -> (String, Option<String>) {
-    let expr = extract_value(s, "constraint");
-    let error = if expr.contains('@') {
-        expr.split('@').nth(1).map(|s| s.trim().to_string())
-    } else {
-        None
-    };
-    let clean_expr = expr.split('@').next().unwrap_or(&expr).trim().to_string();
-    (clean_expr, error)
-}
-
-fn extract_has_one(s: &str) -> (String, Option<String>) {
-    let val = extract_value(s, "has_one");
-    if val.contains('@') {
-        let parts: Vec<&str> = val.split('@').collect();
-        (
-            parts[0].trim().to_string(),
-            Some(parts[1].trim().to_string()),
-        )
-    } else {
-        (val, None)
-    }
-}
-
 fn extract_msg_attr(attrs: &[Attribute]) -> String {
     for attr in attrs {
         if attr.path().is_ident("msg") {
@@ -667,20 +1046,334 @@ fn extract_msg_attr(attrs: &[Attribute]) -> String {
     String::new()
 }
 
-fn extract_max_len(attrs: &[Attribute]) -> Option<usize> {
-    for attr in attrs {
-        if attr.path().is_ident("max_len") {
-            let tokens = attr_to_string(attr);
-            // Parse "# [max_len (200)]" -> extract 200
-            if let Some(start) = tokens.find('(') {
-                if let Some(end) = tokens.find(')') {
-                    let num_str = tokens[start + 1..end].trim();
-                    if let Ok(num) = num_str.parse::<usize>() {
-                        return Some(num);
-                    }
+/// Collect `///` doc comments, which syn exposes as `#[doc = "..."]` attributes
+fn extract_doc_comments(attrs: &[Attribute]) -> Vec<String> {
+    attrs
+        .iter()
+        .filter(|a| a.path().is_ident("doc"))
+        .filter_map(|a| match &a.meta {
+            syn::Meta::NameValue(nv) => match &nv.value {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(s),
+                    ..
+                }) => Some(s.value().trim().to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_params_struct_captured() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod swapper {
+                use super::*;
+                pub fn swap(ctx: Context<Swap>, params: SwapParams, route: Route) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Swap<'info> {
+                pub user: Signer<'info>,
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+            pub struct SwapParams {
+                pub amount_in: u64,
+                pub min_out: u64,
+            }
+
+            pub struct Route {
+                pub hops: u8,
+            }
+
+            #[event]
+            pub struct Swapped {
+                pub amount: u64,
+            }
+
+            struct Unrelated {
+                pub flag: bool,
+            }
+        "#;
+        let program = parse_anchor_source(source).unwrap();
+
+        let names: Vec<&str> = program.types.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["SwapParams", "Route"]);
+        let events: Vec<&str> = program.events.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(events, ["Swapped"]);
+
+        let params = &program.types[0];
+        let fields: Vec<(&str, &str)> = params
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.ty.as_str()))
+            .collect();
+        assert_eq!(fields, [("amount_in", "u64"), ("min_out", "u64")]);
+    }
+
+    #[test]
+    fn test_event_structs_parsed() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[event]
+            pub struct Deposited {
+                pub owner: Pubkey,
+                pub amounts: [u64; 2],
+            }
+
+            #[program]
+            pub mod vault {
+                use super::*;
+                pub fn deposit(ctx: Context<Deposit>) -> Result<()> {
+                    Ok(())
                 }
             }
+
+            #[derive(Accounts)]
+            pub struct Deposit<'info> {
+                pub owner: Signer<'info>,
+            }
+
+            #[event]
+            #[derive(AnchorSerialize, AnchorDeserialize)]
+            pub struct Withdrawn {
+                pub amount: u64,
+            }
+        "#;
+        let program = parse_anchor_source(source).unwrap();
+
+        let events: Vec<(&str, Vec<(&str, &str)>)> = program
+            .events
+            .iter()
+            .map(|e| (e.name.as_str(), e.fields.iter().map(|f| (f.name.as_str(), f.ty.as_str())).collect()))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("Deposited", vec![("owner", "Pubkey"), ("amounts", "[u64 ; 2]")]),
+                ("Withdrawn", vec![("amount", "u64")]),
+            ]
+        );
+        // Events aren't plain types, even with a Borsh derive
+        assert!(program.types.is_empty());
+    }
+
+    #[test]
+    fn test_plain_types_resolved_by_name() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            pub struct Hop {
+                pub pool: Pubkey,
+                pub side: Side,
+            }
+
+            pub enum Side {
+                Bid,
+                Ask,
+            }
+
+            pub struct Route {
+                pub hops: [Hop; 4],
+            }
+
+            #[program]
+            pub mod swapper {
+                use super::*;
+                pub fn swap(ctx: Context<Swap>, route: Route, extra: Vec<Leg>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Swap<'info> {
+                pub user: Signer<'info>,
+            }
+
+            pub struct Leg {
+                pub amount: u64,
+            }
+
+            struct Unrelated {
+                pub flag: bool,
+            }
+        "#;
+        let program = parse_anchor_source(source).unwrap();
+
+        let names: Vec<&str> = program.types.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["Hop", "Route", "Leg"]);
+        let enums: Vec<&str> = program.enums.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(enums, ["Side"]);
+    }
+
+    #[test]
+    fn test_spl_types_match_whole_type_names() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+            use anchor_spl::token::{Mint, TokenAccount};
+
+            #[program]
+            pub mod mints {
+                use super::*;
+                pub fn configure(ctx: Context<Configure>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Configure<'info> {
+                pub config: Account<'info, MintConfig>,
+                pub data: Account<'info, TokenAccountData>,
+                pub settings: Account<'info, ProgramSettings>,
+                pub mint: Account<'info, Mint>,
+                pub spl_mint: Account<'info, anchor_spl::token::Mint>,
+                pub vault: Box<Account<'info, TokenAccount>>,
+            }
+        "#;
+        let program = parse_anchor_source(source).unwrap();
+        let accounts = &program.account_structs[0].accounts;
+
+        let inner = |i: usize| match &accounts[i].ty {
+            AccountType::Account { inner } => inner.clone(),
+            other => panic!("{} parsed as {:?}", accounts[i].name, other),
+        };
+        assert_eq!(inner(0), "MintConfig");
+        assert_eq!(inner(1), "TokenAccountData");
+        assert_eq!(inner(2), "ProgramSettings");
+        assert!(matches!(accounts[3].ty, AccountType::Mint));
+        assert!(matches!(accounts[4].ty, AccountType::Mint));
+        assert!(matches!(accounts[5].ty, AccountType::TokenAccount));
+    }
+
+    #[test]
+    fn test_max_len_bounds_parsed() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod profiles {
+                use super::*;
+                pub fn touch(ctx: Context<Touch>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Touch<'info> {
+                pub profile: Account<'info, Profile>,
+            }
+
+            #[account]
+            pub struct Profile {
+                #[max_len(10)]
+                pub name: String,
+                #[max_len(4, 32)]
+                pub tags: Vec<String>,
+                #[max_len(3)]
+                pub scores: Vec<u64>,
+                pub bio: String,
+            }
+        "#;
+        let program = parse_anchor_source(source).unwrap();
+        let fields = &program.state_structs[0].fields;
+        let bounds: Vec<_> = fields.iter().map(|f| (f.max_len, f.inner_max_lens.clone())).collect();
+        assert_eq!(bounds, [(Some(10), vec![]), (Some(4), vec![32]), (Some(3), vec![]), (None, vec![])]);
+        assert_eq!(fields[1].vec_info.as_ref().unwrap().get_max_len(), 4);
+
+        let analysis = crate::analyzer::analyze(&program).unwrap();
+        let sizes: Vec<_> = analysis.account_sizes[0].fields.iter().map(|(_, size)| *size).collect();
+        // 4 + 10, 4 + 4 * (4 + 32), 4 + 3 * 8, unbounded: length prefix only
+        assert_eq!(sizes, [14, 148, 28, 4]);
+        assert_eq!(analysis.account_sizes[0].size, 8 + 14 + 148 + 28 + 4);
+    }
+
+    #[test]
+    fn test_constraint_clause_parsed_structurally() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod pools {
+                use super::*;
+                pub fn update(ctx: Context<Update>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            pub struct Update<'info> {
+                #[account(mut, constraint = pool.kind == "constraint, owner = x" @ PoolError::Bad, has_one = authority)]
+                pub pool: Account<'info, Pool>,
+                pub authority: Signer<'info>,
+            }
+        "#;
+        let program = parse_anchor_source(source).unwrap();
+        let constraints = &program.account_structs[0].accounts[0].constraints;
+
+        assert_eq!(constraints.len(), 3);
+        assert!(matches!(constraints[0], AccountConstraint::Mut));
+        match &constraints[1] {
+            AccountConstraint::Constraint { expr, error } => {
+                assert!(expr.ends_with("\"constraint, owner = x\""), "{}", expr);
+                assert_eq!(error.as_deref(), Some("PoolError::Bad"));
+            }
+            other => panic!("expected constraint, got {:?}", other),
         }
+        assert!(matches!(
+            &constraints[2],
+            AccountConstraint::HasOne { field, error: None } if field == "authority"
+        ));
+    }
+
+    #[test]
+    fn test_instruction_attr_args_captured() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[program]
+            pub mod vaults {
+                use super::*;
+                pub fn open(ctx: Context<Open>, id: u64, owner: Pubkey, tag: [u8; 32]) -> Result<()> {
+                    Ok(())
+                }
+                pub fn close(ctx: Context<Close>) -> Result<()> {
+                    Ok(())
+                }
+            }
+
+            #[derive(Accounts)]
+            #[instruction(id: u64, owner: Pubkey, tag: [u8; 32])]
+            pub struct Open<'info> {
+                #[account(seeds = [b"vault", owner.as_ref(), &id.to_le_bytes()], bump)]
+                pub vault: Account<'info, Vault>,
+            }
+
+            #[derive(Accounts)]
+            pub struct Close<'info> {
+                pub vault: Account<'info, Vault>,
+            }
+        "#;
+        let program = parse_anchor_source(source).unwrap();
+
+        let open = &program.account_structs[0];
+        let args: Vec<(&str, &str)> = open
+            .instruction_args
+            .iter()
+            .map(|a| (a.name.as_str(), a.ty.as_str()))
+            .collect();
+        assert_eq!(args, [("id", "u64"), ("owner", "Pubkey"), ("tag", "[u8;32]")]);
+
+        assert!(program.account_structs[1].instruction_args.is_empty());
     }
-    None
 }
@@ -3,19 +3,54 @@
 //! This module provides transformations for Anchor's AccountLoader pattern
 //! to Pinocchio's zero-copy unsafe load methods.
 
-use crate::ir::AnchorStateStruct;
+use crate::ir::{AnchorStateStruct, AnchorStructDef};
+use regex::Regex;
+
+/// Accounts larger than this are loaded in place instead of deserialized
+const ZERO_COPY_THRESHOLD: usize = 10 * 1024;
 
 /// Check if a state struct should use zero-copy
 /// Returns true if explicitly marked or if size > 10KB
-pub fn should_use_zero_copy(state: &AnchorStateStruct) -> bool {
-    // TODO: Implement zero-copy detection
-    state.is_zero_copy
+pub fn should_use_zero_copy(state: &AnchorStateStruct, types: &[AnchorStructDef]) -> bool {
+    state.is_zero_copy || estimate_state_size(state, types) > ZERO_COPY_THRESHOLD
+}
+
+/// Estimate size of a state struct in bytes, discriminator included.
+/// Structs from `types` are summed field by field; Vecs and Strings count at their
+/// `#[max_len]` capacity, or just the length prefix when unbounded.
+pub fn estimate_state_size(state: &AnchorStateStruct, types: &[AnchorStructDef]) -> usize {
+    let fields: usize = state
+        .fields
+        .iter()
+        .map(|field| match (&field.vec_info, field.max_len) {
+            (Some(vec_info), _) => {
+                4 + vec_info.max_len.unwrap_or(0) * estimate_type_size(&vec_info.element_type, types)
+            }
+            (None, Some(max_len)) if field.ty == "String" => 4 + max_len,
+            _ => estimate_type_size(&field.ty, types),
+        })
+        .sum();
+    8 + fields
 }
 
-/// Estimate size of a state struct in bytes
-pub fn estimate_state_size(_state: &AnchorStateStruct) -> usize {
-    // TODO: Implement size estimation
-    0
+fn estimate_type_size(ty: &str, types: &[AnchorStructDef]) -> usize {
+    let ty = ty.replace(' ', "");
+
+    // [T; N]
+    if let Some((elem, len)) = ty
+        .strip_prefix('[')
+        .and_then(|t| t.strip_suffix(']'))
+        .and_then(|t| t.rsplit_once(';'))
+    {
+        if let Ok(len) = len.parse::<usize>() {
+            return len * estimate_type_size(elem, types);
+        }
+    }
+
+    match types.iter().find(|t| t.name == ty) {
+        Some(def) => def.fields.iter().map(|f| estimate_type_size(&f.ty, types)).sum(),
+        None => crate::analyzer::estimate_field_size(&ty),
+    }
 }
 
 /// Generate safety documentation for zero-copy methods
@@ -36,20 +71,87 @@ pub fn generate_safety_doc(is_packed: bool) -> String {
 }
 
 /// Transform AccountLoader.load() calls to unsafe PoolState::load()
+/// `loader_accounts` are (account name, state type) pairs
 pub fn transform_account_loader_usage(
     body: &str,
-    _loader_accounts: &[(String, String)],
+    loader_accounts: &[(String, String)],
 ) -> String {
-    // TODO: Implement AccountLoader transformation
+    let mut result = body.to_string();
+
     // Pattern: pool_state.load()? → unsafe { PoolState::load(pool_state)? }
     // Pattern: pool_state.load_mut()? → unsafe { PoolState::load_mut(pool_state)? }
-    body.to_string()
+    for (account, state_type) in loader_accounts {
+        let re = Regex::new(&format!(
+            r"\b{}\s*\.\s*(load|load_mut)\s*\(\s*\)\s*\?",
+            regex::escape(account)
+        ))
+        .unwrap();
+        result = re
+            .replace_all(&result, format!("unsafe {{ {}::${{1}}({})? }}", state_type, account))
+            .to_string();
+    }
+
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_large_accounts_use_zero_copy() {
+        let source = r#"
+            use anchor_lang::prelude::*;
+
+            #[account]
+            pub struct Book {
+                pub authority: Pubkey,
+                pub entries: [Entry; 512],
+                #[max_len(16)]
+                pub label: String,
+            }
+
+            #[account]
+            pub struct Small {
+                pub count: u64,
+                pub pending: Option<u32>,
+            }
+
+            #[account(zero_copy)]
+            pub struct Marked {
+                pub count: u64,
+            }
+
+            #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+            pub struct Entry {
+                pub owner: Pubkey,
+                pub amount: u64,
+            }
+        "#;
+        let program = crate::parser::parse_anchor_source(source).unwrap();
+        let state = |name: &str| program.state_structs.iter().find(|s| s.name == name).unwrap();
+
+        // 8 + 32 + 512 * (32 + 8) + 4 + 16
+        assert_eq!(estimate_state_size(state("Book"), &program.types), 20540);
+        assert_eq!(estimate_state_size(state("Small"), &program.types), 8 + 8 + 5);
+
+        assert!(state("Book").is_zero_copy);
+        assert!(!state("Small").is_zero_copy);
+        assert!(state("Marked").is_zero_copy);
+        assert!(should_use_zero_copy(state("Book"), &program.types));
+    }
+
+    #[test]
+    fn test_account_loader_usage() {
+        let loaders = [("pool_state".to_string(), "PoolState".to_string())];
+        let body = "let pool = pool_state . load () ? ;\nlet mut pool_mut = pool_state.load_mut()?;\nlet other = my_pool_state.load()?;";
+        let result = transform_account_loader_usage(body, &loaders);
+
+        assert!(result.contains("let pool = unsafe { PoolState::load(pool_state)? } ;"));
+        assert!(result.contains("let mut pool_mut = unsafe { PoolState::load_mut(pool_state)? };"));
+        assert!(result.contains("my_pool_state.load()?"));
+    }
+
     #[test]
     fn test_safety_doc_generation() {
         let doc = generate_safety_doc(true);
@@ -0,0 +1,434 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+const COUNTER_PROGRAM: &str = r#"
+use anchor_lang::prelude::*;
+
+declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
+
+#[program]
+pub mod counter {
+    use super::*;
+
+    pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
+        ctx.accounts.counter.count = 0;
+        Ok(())
+    }
+
+    pub fn increment(ctx: Context<Increment>) -> Result<()> {
+        ctx.accounts.counter.count += 1;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(init, payer = authority, space = 8 + 8)]
+    pub counter: Account<'info, Counter>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Increment<'info> {
+    #[account(mut)]
+    pub counter: Account<'info, Counter>,
+}
+
+#[account]
+pub struct Counter {
+    pub count: u64,
+}
+"#;
+
+fn uncpi_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_BIN_EXE_uncpi"))
+}
+
+/// Write the counter program into `dir` and return the input path
+fn write_counter_program(dir: &Path) -> PathBuf {
+    let input = dir.join("lib.rs");
+    std::fs::write(&input, COUNTER_PROGRAM).unwrap();
+    input
+}
+
+fn run_uncpi(input: &Path, output_dir: &Path, extra_args: &[&str]) -> Output {
+    Command::new(uncpi_path())
+        .arg(input)
+        .arg("-o")
+        .arg(output_dir)
+        .args(extra_args)
+        .output()
+        .expect("Failed to run uncpi")
+}
+
+#[test]
+fn test_timings_reports_each_phase() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+
+    let output = run_uncpi(&input, output_dir.path(), &["--timings", "--idl"]);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Timings:"));
+    for phase in [
+        "parse",
+        "analyze",
+        "transform",
+        "accounts",
+        "validations",
+        "body",
+        "state",
+        "emit",
+        "idl",
+    ] {
+        assert!(
+            stdout
+                .lines()
+                .any(|line| line.trim_start().starts_with(phase) && line.ends_with("ms")),
+            "Missing timing line for {}:\n{}",
+            phase,
+            stdout
+        );
+    }
+}
+
+#[test]
+fn test_no_timings_without_flag() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+
+    let output = run_uncpi(&input, output_dir.path(), &[]);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("Timings:"));
+}
+
+#[test]
+fn test_emit_fuzz_scaffold() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+
+    let output = run_uncpi(&input, output_dir.path(), &["--emit-fuzz"]);
+    assert!(output.status.success());
+
+    let fuzz_dir = output_dir.path().join("fuzz");
+    let cargo_toml = std::fs::read_to_string(fuzz_dir.join("Cargo.toml")).unwrap();
+    assert!(cargo_toml.contains("cargo-fuzz = true"));
+    assert!(cargo_toml.contains("[dependencies.counter]\npath = \"..\""));
+
+    let target = std::fs::read_to_string(fuzz_dir.join("fuzz_targets/dispatch.rs")).unwrap();
+    assert!(target.contains("fuzz_target!(|instruction_data: &[u8]|"));
+    assert!(target.contains("counter::process_instruction(&program_id, &[], instruction_data)"));
+    assert!(target.contains("let program_id: Pubkey = counter::ID;"));
+    syn::parse_file(&target).unwrap();
+}
+
+#[test]
+fn test_no_fuzz_scaffold_without_flag() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+
+    let output = run_uncpi(&input, output_dir.path(), &[]);
+    assert!(output.status.success());
+    assert!(!output_dir.path().join("fuzz").exists());
+}
+
+#[test]
+fn test_emit_tests_covers_each_instruction() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+
+    let output = run_uncpi(&input, output_dir.path(), &["--emit-tests"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let tests = std::fs::read_to_string(output_dir.path().join("tests/instructions.rs")).unwrap();
+    syn::parse_file(&tests).unwrap();
+    assert!(tests.contains("const PROGRAM_ID: Pubkey = counter::ID;"));
+    assert!(tests.contains("counter::process_instruction(&PROGRAM_ID, &[], instruction_data)"));
+    assert!(tests.contains("fn short_instruction_data_is_rejected() {\n    assert_eq!(call(&[]), Err(ProgramError::InvalidInstructionData));"));
+    assert!(tests.contains("assert_eq!(call(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]), Err(ProgramError::InvalidInstructionData));"));
+    // sha256("global:initialize")[0..8] routes to a handler needing counter, authority, system_program
+    assert!(tests.contains(
+        "fn initialize_requires_its_accounts() {\n    // Routed to initialize, which takes 3 account(s)\n    assert_eq!(call(&[0xaf, 0xaf, 0x6d, 0x1f, 0x0d, 0x98, 0x9b, 0xed]), Err(ProgramError::NotEnoughAccountKeys));"
+    ), "{}", tests);
+    assert!(tests.contains("fn increment_requires_its_accounts() {"));
+
+    // One-byte selectors get the first unused byte as the unknown one
+    let output_dir = TempDir::new().unwrap();
+    let output = run_uncpi(&input, output_dir.path(), &["--emit-tests", "--anchor-compat=false"]);
+    assert!(output.status.success());
+    let tests = std::fs::read_to_string(output_dir.path().join("tests/instructions.rs")).unwrap();
+    assert!(tests.contains("assert_eq!(call(&[0x02]), Err(ProgramError::InvalidInstructionData));"));
+    assert!(tests.contains("assert_eq!(call(&[0x01]), Err(ProgramError::NotEnoughAccountKeys));"));
+
+    let output_dir = TempDir::new().unwrap();
+    let output = run_uncpi(&input, output_dir.path(), &[]);
+    assert!(output.status.success());
+    assert!(!output_dir.path().join("tests").exists());
+}
+
+#[test]
+fn test_dry_run_prints_files_without_writing() {
+    let input_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+    let output_dir = input_dir.path().join("out");
+
+    let output = run_uncpi(&input, &output_dir, &["--dry-run", "--idl", "--emit-tests", "--verbose"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!output_dir.exists());
+
+    // One listing in path order, with the IDL among the crate's files and no progress lines
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("==> Cargo.toml <==\n"), "{}", stdout);
+    let mut last = 0;
+    for path in ["Cargo.toml", "idl.json", "security.json", "src/instructions/initialize.rs", "src/lib.rs", "tests/instructions.rs"] {
+        let header = stdout.find(&format!("==> {} <==\n", path));
+        assert!(header.is_some_and(|at| at >= last), "missing or out of order {}:\n{}", path, stdout);
+        last = header.unwrap();
+    }
+    assert!(String::from_utf8_lossy(&output.stderr).contains("[4/4] Emitting Pinocchio code..."));
+    assert!(stdout.contains("pub fn process_instruction("));
+    assert!(!stdout.contains("Success!"));
+}
+
+#[test]
+fn test_emit_ir_writes_each_stage() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+
+    let output = run_uncpi(&input, output_dir.path(), &[]);
+    assert!(output.status.success());
+    assert!(!output_dir.path().join("pinocchio_ir.json").exists());
+
+    let output = run_uncpi(&input, output_dir.path(), &["--emit-ir"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let read_json = |name: &str| {
+        let json = std::fs::read_to_string(output_dir.path().join(name)).unwrap();
+        serde_json::from_str::<serde_json::Value>(&json).unwrap()
+    };
+    let anchor_ir = read_json("anchor_ir.json");
+    assert_eq!(anchor_ir["instructions"][1]["name"], "increment");
+    let analysis = read_json("analysis.json");
+    assert_eq!(analysis["account_sizes"][0]["struct_name"], "Counter");
+    let pinocchio_ir = read_json("pinocchio_ir.json");
+    assert_eq!(pinocchio_ir["state_structs"][0]["fields"][0]["name"], "count");
+}
+
+#[test]
+fn test_no_security_json_skips_metadata_file() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+
+    let output = run_uncpi(&input, output_dir.path(), &[]);
+    assert!(output.status.success());
+    assert!(output_dir.path().join("security.json").exists());
+
+    let output_dir = TempDir::new().unwrap();
+    let output = run_uncpi(&input, output_dir.path(), &["--no-security-json"]);
+    assert!(output.status.success());
+    assert!(!output_dir.path().join("security.json").exists());
+    assert!(output_dir.path().join("src/lib.rs").exists());
+}
+
+#[test]
+fn test_pinocchio_log_mode_keeps_formatted_logs() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = input_dir.path().join("lib.rs");
+    std::fs::write(
+        &input,
+        COUNTER_PROGRAM.replace(
+            "ctx.accounts.counter.count += 1;",
+            "ctx.accounts.counter.count += 1;\n        msg!(\"count is {}\", ctx.accounts.counter.count);",
+        ),
+    )
+    .unwrap();
+
+    let output = run_uncpi(&input, output_dir.path(), &["--logger=pinocchio-log"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let cargo_toml = std::fs::read_to_string(output_dir.path().join("Cargo.toml")).unwrap();
+    assert!(cargo_toml.contains("pinocchio-log = \"0.4\""));
+    let handler =
+        std::fs::read_to_string(output_dir.path().join("src/instructions/increment.rs")).unwrap();
+    assert!(handler.contains("pinocchio_log::log!"), "{}", handler);
+    assert!(!handler.contains("// msg!"));
+
+    let output_dir = TempDir::new().unwrap();
+    let output = run_uncpi(&input, output_dir.path(), &[]);
+    assert!(output.status.success());
+    let cargo_toml = std::fs::read_to_string(output_dir.path().join("Cargo.toml")).unwrap();
+    assert!(!cargo_toml.contains("pinocchio-log"));
+}
+
+#[test]
+fn test_rename_instruction_changes_module_and_discriminator() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+
+    let output = run_uncpi(
+        &input,
+        output_dir.path(),
+        &["--rename-instruction", "increment=bump", "--idl"],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let instructions = output_dir.path().join("src/instructions");
+    assert!(instructions.join("bump.rs").exists());
+    assert!(!instructions.join("increment.rs").exists());
+    let lib = std::fs::read_to_string(output_dir.path().join("src/lib.rs")).unwrap();
+    // sha256("global:bump")[0..8]
+    assert!(lib.contains("const BUMP_DISC: [u8; 8] = [0x66, 0xa3, 0x5b, 0xdd, 0x92, 0x58, 0xb8, 0x8e];"), "{}", lib);
+    assert!(lib.contains("BUMP_DISC => instructions::bump(program_id, accounts, data),"));
+    assert!(!lib.contains("INCREMENT_DISC"));
+    let idl = std::fs::read_to_string(output_dir.path().join("idl.json")).unwrap();
+    let idl: serde_json::Value = serde_json::from_str(&idl).unwrap();
+    assert_eq!(idl["instructions"][1]["name"], "bump");
+
+    // Renaming onto an existing instruction would collide
+    let output = run_uncpi(
+        &input,
+        output_dir.path(),
+        &["--rename-instruction", "increment=initialize"],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("'initialize' is used more than once"));
+
+    let output = run_uncpi(&input, output_dir.path(), &["--rename-instruction", "decrement=bump"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_non_anchor_mode_dispatches_on_one_byte() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+
+    let output = run_uncpi(&input, output_dir.path(), &["--anchor-compat=false", "--idl"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let lib = std::fs::read_to_string(output_dir.path().join("src/lib.rs")).unwrap();
+    syn::parse_file(&lib).unwrap();
+    assert!(lib.contains("const INITIALIZE_DISC: u8 = 0x00;\nconst INCREMENT_DISC: u8 = 0x01;"), "{}", lib);
+    assert!(lib.contains(".split_first()\n        .ok_or(ProgramError::InvalidInstructionData)?;"));
+    assert!(lib.contains("match *disc {\n        INITIALIZE_DISC => instructions::initialize(program_id, accounts, data),"));
+    assert!(!lib.contains("split_at(8)"));
+    let idl = std::fs::read_to_string(output_dir.path().join("idl.json")).unwrap();
+    let idl: serde_json::Value = serde_json::from_str(&idl).unwrap();
+    assert_eq!(idl["instructions"][1]["discriminator"], serde_json::json!([1]));
+
+    // Anchor-compatible 8-byte dispatch stays the default
+    let output_dir = TempDir::new().unwrap();
+    let output = run_uncpi(&input, output_dir.path(), &["--anchor-compat"]);
+    assert!(output.status.success());
+    let lib = std::fs::read_to_string(output_dir.path().join("src/lib.rs")).unwrap();
+    assert!(lib.contains("let (disc, data) = instruction_data.split_at(8);"));
+}
+
+#[test]
+fn test_disc_len_shortens_every_discriminator() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+
+    // The u64 count would sit misaligned after 4 bytes, so it cannot be cast in place
+    let output = run_uncpi(&input, output_dir.path(), &["--disc-len=4"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("field 'count' needs 8-byte alignment"));
+
+    let output = run_uncpi(
+        &input,
+        output_dir.path(),
+        &["--disc-len=4", "--field-accessors", "--idl", "--idl-format", "codama"],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // sha256("global:increment")[0..4]
+    let lib = std::fs::read_to_string(output_dir.path().join("src/lib.rs")).unwrap();
+    syn::parse_file(&lib).unwrap();
+    assert!(lib.contains("const INCREMENT_DISC: [u8; 4] = [0x0b, 0x12, 0x68, 0x09];"), "{}", lib);
+    assert!(lib.contains("if instruction_data.len() < 4 {"));
+    assert!(lib.contains("let (disc, data) = instruction_data.split_at(4);\n    let disc: [u8; 4] = disc.try_into().unwrap();"));
+
+    // sha256("account:Counter")[0..4], with the state right after it
+    let state = std::fs::read_to_string(output_dir.path().join("src/state.rs")).unwrap();
+    assert!(state.contains("pub const DISCRIMINATOR: [u8; 4] = [0xff, 0xb0, 0x04, 0xf5];"), "{}", state);
+    assert!(state.contains("if data.len() < 4 + Self::SIZE {"));
+    assert!(state.contains("u64::from_le_bytes(self.data[4..12].try_into().unwrap())"), "{}", state);
+    let initialize = std::fs::read_to_string(output_dir.path().join("src/instructions/initialize.rs")).unwrap();
+    assert!(initialize.contains("counter.try_borrow_mut_data()?[..4].copy_from_slice(&Counter::DISCRIMINATOR);"));
+
+    let idl = std::fs::read_to_string(output_dir.path().join("idl.json")).unwrap();
+    let idl: serde_json::Value = serde_json::from_str(&idl).unwrap();
+    let program = &idl["program"];
+    assert_eq!(program["instructions"][1]["arguments"][0]["defaultValue"]["data"], "0b126809");
+    assert_eq!(program["accounts"][0]["data"]["fields"][0]["type"]["size"], 4);
+    assert_eq!(program["accounts"][0]["data"]["fields"][0]["defaultValue"]["data"], "ffb004f5");
+
+    let output = run_uncpi(&input, output_dir.path(), &["--disc-len=2"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("expected 8, 4 or 1"));
+}
+
+#[test]
+fn test_idl_name_overrides_module_name() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+    let read_idl_name = |output_dir: &Path| {
+        let idl = std::fs::read_to_string(output_dir.join("idl.json")).unwrap();
+        let idl: serde_json::Value = serde_json::from_str(&idl).unwrap();
+        idl["name"].as_str().unwrap().to_string()
+    };
+
+    let output = run_uncpi(&input, output_dir.path(), &["--idl"]);
+    assert!(output.status.success());
+    assert_eq!(read_idl_name(output_dir.path()), "counter");
+
+    let output = run_uncpi(
+        &input,
+        output_dir.path(),
+        &["--idl", "--idl-name", "counter-program"],
+    );
+    assert!(output.status.success());
+    assert_eq!(read_idl_name(output_dir.path()), "counter_program");
+
+    // A file inside a crate is named after the nearest Cargo.toml with a [package]
+    let crate_dir = input_dir.path().join("programs").join("counter");
+    std::fs::create_dir_all(crate_dir.join("src")).unwrap();
+    std::fs::write(input_dir.path().join("Cargo.toml"), "[workspace]\nmembers = [\"programs/*\"]\n").unwrap();
+    std::fs::write(crate_dir.join("Cargo.toml"), "[package]\nname = \"counter-program\"\n").unwrap();
+    let input = crate_dir.join("src").join("lib.rs");
+    std::fs::write(&input, COUNTER_PROGRAM).unwrap();
+    let output = run_uncpi(&input, output_dir.path(), &["--idl"]);
+    assert!(output.status.success());
+    assert_eq!(read_idl_name(output_dir.path()), "counter_program");
+}
+
+#[test]
+fn test_jobs_sets_thread_pool_size() {
+    let input_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let input = write_counter_program(input_dir.path());
+
+    let output = run_uncpi(&input, output_dir.path(), &["--jobs", "1", "--verbose"]);
+    assert!(output.status.success());
+
+    // Progress goes to stderr, keeping stdout for results
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Threads: 1\n"), "{}", stderr);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Threads:"));
+}
@@ -0,0 +1,35 @@
+use anchor_lang::prelude::*;
+
+declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
+
+#[program]
+pub mod events {
+    use super::*;
+
+    pub fn ping(ctx: Context<Ping>, nonce: u64) -> Result<()> {
+        ctx.accounts.counter.pings += 1;
+        emit!(Pinged {
+            sender: ctx.accounts.sender.key(),
+            nonce,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Ping<'info> {
+    #[account(mut)]
+    pub counter: Account<'info, Counter>,
+    pub sender: Signer<'info>,
+}
+
+#[account]
+pub struct Counter {
+    pub pings: u64,
+}
+
+#[event]
+pub struct Pinged {
+    pub sender: Pubkey,
+    pub nonce: u64,
+}
@@ -0,0 +1,27 @@
+use anchor_lang::prelude::*;
+
+declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
+
+#[program]
+pub mod optional_accounts {
+    use super::*;
+
+    pub fn trade(ctx: Context<Trade>, amount: u64) -> Result<()> {
+        ctx.accounts.market.volume += amount;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Trade<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    pub trader: Signer<'info>,
+    #[account(mut, constraint = referrer.key() != trader.key())]
+    pub referrer: Option<SystemAccount<'info>>,
+}
+
+#[account]
+pub struct Market {
+    pub volume: u64,
+}
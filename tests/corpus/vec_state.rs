@@ -0,0 +1,36 @@
+use anchor_lang::prelude::*;
+
+declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
+
+#[program]
+pub mod vec_state {
+    use super::*;
+
+    pub fn add_member(ctx: Context<AddMember>, member: Pubkey) -> Result<()> {
+        let group = &mut ctx.accounts.group;
+        require!(group.members.len() < 10, GroupError::Full);
+        group.members.push(member);
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct AddMember<'info> {
+    #[account(mut, has_one = admin)]
+    pub group: Account<'info, Group>,
+    pub admin: Signer<'info>,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Group {
+    pub admin: Pubkey,
+    #[max_len(10)]
+    pub members: Vec<Pubkey>,
+}
+
+#[error_code]
+pub enum GroupError {
+    #[msg("Group is full")]
+    Full,
+}
@@ -0,0 +1,127 @@
+//! Transpile every program in tests/corpus/ and check the output is valid Rust with
+//! nothing Anchor-only left in it
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+fn corpus_programs() -> Vec<PathBuf> {
+    let corpus_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("corpus");
+    let mut programs: Vec<PathBuf> = std::fs::read_dir(&corpus_dir)
+        .expect("Should read corpus dir")
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "rs"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+fn rust_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap().filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            files.extend(rust_files(&path));
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            files.push(path);
+        }
+    }
+    files
+}
+
+fn transpile(program: &Path, output_dir: &Path) {
+    let output = Command::new(env!("CARGO_BIN_EXE_uncpi"))
+        .arg(program)
+        .arg("-o")
+        .arg(output_dir)
+        .output()
+        .expect("Failed to run uncpi");
+
+    assert!(
+        output.status.success(),
+        "Transpiling {:?} failed:\n{}",
+        program,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Anchor items with no Pinocchio counterpart; output still naming them won't compile
+const ANCHOR_ONLY: &[&str] = &["CpiContext", "token::", "ctx.accounts", "ctx.bumps", "to_account_info"];
+
+/// Whether `code` has `name` starting at an identifier boundary, so `token::` doesn't
+/// match `pinocchio_token::`
+fn names(code: &str, name: &str) -> bool {
+    code.match_indices(name).any(|(i, _)| {
+        !code[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+#[test]
+fn test_corpus_output_parses() {
+    let programs = corpus_programs();
+    assert!(programs.len() >= 3, "Corpus should have at least 3 programs");
+
+    let mut failures = Vec::new();
+    for program in &programs {
+        let output_dir = TempDir::new().unwrap();
+        transpile(program, output_dir.path());
+
+        for file in rust_files(output_dir.path()) {
+            let source = std::fs::read_to_string(&file).unwrap();
+            if let Err(e) = syn::parse_file(&source) {
+                failures.push(format!(
+                    "{}: {}: {}",
+                    program.file_name().unwrap().to_string_lossy(),
+                    file.strip_prefix(output_dir.path()).unwrap().display(),
+                    e
+                ));
+            }
+            let compact: String = source.split_whitespace().collect();
+            for anchor_only in ANCHOR_ONLY.iter().filter(|name| names(&compact, name)) {
+                failures.push(format!(
+                    "{}: {}: still uses `{}`",
+                    program.file_name().unwrap().to_string_lossy(),
+                    file.strip_prefix(output_dir.path()).unwrap().display(),
+                    anchor_only
+                ));
+            }
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "Generated code does not parse or is not fully transformed:\n{}",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn test_corpus_output_builds() {
+    // Requires solana-platform-tools; skip when unavailable
+    let sbf_available = Command::new("cargo")
+        .args(["build-sbf", "--version"])
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !sbf_available {
+        eprintln!("Skipping SBF compile test - cargo build-sbf not available");
+        return;
+    }
+
+    for program in corpus_programs() {
+        let output_dir = TempDir::new().unwrap();
+        transpile(&program, output_dir.path());
+
+        let status = Command::new("cargo")
+            .arg("build-sbf")
+            .current_dir(output_dir.path())
+            .status()
+            .expect("Failed to run cargo build-sbf");
+        assert!(status.success(), "{:?} output should compile", program);
+    }
+}